@@ -1,17 +1,133 @@
-use clap::{App, Arg};
+use crate::capture::CaptureConfig;
+use crate::ip_addrs::Prefix;
+use clap::{App, Arg, SubCommand};
 use log::LevelFilter;
+use std::ffi::OsString;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
+
+/// Smallest MTU that every IPv4 link needs to support (RFC 791)
+const MIN_MTU4: usize = 68;
+
+/// Smallest MTU that every IPv6 link needs to support (RFC 8200 section 5)
+const MIN_MTU6: usize = 1280;
+
+/// Largest MTU of a TUN device, which allows jumbo packets
+const MAX_MTU: usize = 65535;
+
+/// How packets are read from and written to TUN devices
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoBackend {
+    /// One tokio read or write per packet
+    Tokio,
+    /// Batched reads and writes through io_uring
+    #[cfg(feature = "io-uring")]
+    IoUring,
+}
+
+/// Which kind of probes a simulated traceroute sends
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProbeProtocol {
+    /// ICMP echo requests like `traceroute -I`
+    Icmp,
+    /// UDP datagrams to unlikely ports like plain `traceroute`
+    Udp,
+    /// TCP SYN segments like `traceroute -T`
+    Tcp,
+}
+
+/// What the program should do
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// Answer probes that arrive on TUN devices
+    Run,
+    /// Answer the probes of the capture at `input` and write the responses to `output`
+    Replay { input: PathBuf, output: PathBuf },
+    /// Print what a traceroute to `target` would show without sending any packets
+    Simulate {
+        target: IpAddr,
+        source: Option<IpAddr>,
+        protocol: ProbeProtocol,
+        queries: usize,
+        max_hops: u8,
+    },
+    /// Check end-to-end in private user and network namespaces that probes are answered
+    Selftest,
+}
+
+/// A virtual address whose probes are forwarded to a real backend once they got through all
+/// virtual hops
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Forward {
+    pub virtual_address: IpAddr,
+    pub backend: IpAddr,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    /// Parse `VIRTUAL=BACKEND` where both addresses are of the same family
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (virtual_address, backend) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not of the form VIRTUAL=BACKEND", s))?;
+        let virtual_address = IpAddr::from_str(virtual_address).map_err(|e| e.to_string())?;
+        let backend = IpAddr::from_str(backend).map_err(|e| e.to_string())?;
+        if virtual_address.is_ipv4() != backend.is_ipv4() {
+            return Err(format!(
+                "{} and {} are not of the same address family",
+                virtual_address, backend
+            ));
+        }
+        Ok(Self {
+            virtual_address,
+            backend,
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Arguments {
+    pub command: Command,
     pub log_level: LevelFilter,
     pub tun_device_name: String,
     pub n_hosts: usize,
     pub networks: Vec<IpAddr>,
+    pub icmp4_error_size: usize,
+    pub icmp6_error_size: usize,
+    pub reassembly_timeout: Duration,
+    pub reassembly_memory: usize,
+    pub mtu: Option<i32>,
+    pub queues: usize,
+    pub single_device: bool,
+    pub tap: bool,
+    pub nfqueue: Option<u16>,
+    pub forwards: Vec<Forward>,
+    pub io_backend: IoBackend,
+    pub netns: Option<String>,
+    pub attach: bool,
+    pub tun_fds: Vec<i32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub seccomp: bool,
+    pub manage_routes: bool,
+    pub routes: Vec<Prefix>,
+    pub route_table: u32,
+    pub capture: Option<CaptureConfig>,
 }
 
 pub fn parse_arguments() -> Arguments {
+    parse_arguments_from(std::env::args_os())
+}
+
+/// Parse program arguments from `args` whose first item is the program name
+pub fn parse_arguments_from<I, T>(args: I) -> Arguments
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
     let matches = App::new("VipTraceRouter")
         .version(env!("CARGO_PKG_VERSION"))
         .about(
@@ -50,9 +166,267 @@ pub fn parse_arguments() -> Arguments {
                 .required(true)
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("icmp4_error_size")
+                .long("icmp4-error-size")
+                .help("Maximum size in bytes of generated ICMPv4 error messages including the IP header (RFC 1812 suggests 576)")
+                .default_value("576")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("icmp6_error_size")
+                .long("icmp6-error-size")
+                .help("Maximum size in bytes of generated ICMPv6 error messages including the IP header (RFC 4443 requires at most 1280)")
+                .default_value("1280")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reassembly_timeout")
+                .long("reassembly-timeout")
+                .help("Number of seconds after which incomplete fragmented packets are given up")
+                .default_value("30")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reassembly_memory")
+                .long("reassembly-memory")
+                .help("Maximum number of bytes that all incomplete fragmented packets may use together")
+                .default_value("4194304")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mtu")
+                .long("mtu")
+                .help("MTU of the created TUN devices between 68, or 1280 if any network is IPv6, and 65535 to support jumbo packets (defaults to the system's default)")
+                .validator(in_range(MIN_MTU4, MAX_MTU))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queues")
+                .long("queues")
+                .help("Number of queues per TUN device which are each served by their own worker task")
+                .default_value("1")
+                .validator(at_least(1))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("io_backend")
+                .long("io-backend")
+                .help("How packets are read from and written to the TUN devices")
+                .possible_values(&[
+                    "tokio",
+                    #[cfg(feature = "io-uring")]
+                    "io-uring",
+                ])
+                .default_value("tokio")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("single_device")
+                .long("single-device")
+                .help("Serve all networks on a single dual-stack TUN device with one address per network instead of one device per network")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("tap")
+                .long("tap")
+                .help("Use TAP instead of TUN devices so that they can be bridged into an Ethernet segment, on which ARP and neighbor solicitations for the virtual hosts are answered")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("nfqueue")
+                .long("nfqueue")
+                .help("Number of a netfilter queue (NFQUEUE) to read packets to real addresses from instead of using TUN devices. Packets with a TTL of at most nhosts are answered by the virtual hops of the first network of their address family, all others are accepted")
+                .takes_value(true)
+                .conflicts_with_all(&["tap", "attach", "tun_fd", "single_device"]),
+        )
+        .arg(
+            Arg::with_name("forwards")
+                .long("forward")
+                .help("Forward the probes that get through all virtual hops to VIRTUAL to the real host BACKEND and translate its replies back, given as VIRTUAL=BACKEND and possibly multiple times. The forwarded packets are routed by the kernel, so IP forwarding needs to be enabled")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["tap", "nfqueue"]),
+        )
+        .arg(
+            Arg::with_name("netns")
+                .long("netns")
+                .help("Name of an existing network namespace (see `ip netns`) in which the TUN devices are created while the program itself stays in its own namespace")
+                .takes_value(true)
+                .validator(|netns| {
+                    crate::tun_management::netns_path(&netns)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("attach")
+                .long("attach")
+                .help("Attach to persistent TUN devices that already exist instead of creating them, which needs no capabilities if they are owned by the user running the program")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("tun_fd")
+                .long("tun-fd")
+                .help("Open file descriptor of a TUN device that was passed in by the parent process, may be given multiple times (file descriptors passed by systemd via LISTEN_FDS are used as well)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .help("Switch to this user (name or id) and drop all capabilities once the TUN devices are set up. Can not be combined with --manage-routes because removing the routes on shutdown needs CAP_NET_ADMIN")
+                .takes_value(true)
+                .conflicts_with_all(&["manage_routes", "routes"]),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .help("Group (name or id) to switch to together with --user (defaults to the user's id)")
+                .requires("user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seccomp")
+                .long("seccomp")
+                .help("Only allow the system calls that packet handling needs once the TUN devices are set up")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("manage_routes")
+                .long("manage-routes")
+                .help("Install routes for the virtual networks on their TUN devices at startup and remove them on shutdown")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("routes")
+                .long("route")
+                .help("Additional destination prefix that is routed to and served by the first TUN device of its address family, may be given multiple times (implies --manage-routes)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("route_table")
+                .long("route-table")
+                .help("Id of the routing table that routes are installed into, e.g. for policy routing (254 is the main table)")
+                .default_value("254")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .help("Write all received packets and sent responses to this pcapng file, whose directory needs to be writable by --user for rotation")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capture_max_size")
+                .long("capture-max-size")
+                .help("Size in bytes after which the capture file is rotated")
+                .default_value("104857600")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capture_files")
+                .long("capture-files")
+                .help("Number of rotated capture files that are kept")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capture_filter")
+                .long("capture-filter")
+                .help("Only capture packets from or to addresses in this prefix, e.g. 192.0.2.0/24")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Answers the probes of a pcap or pcapng capture and writes the responses to a pcap capture")
+                .arg(
+                    Arg::with_name("input")
+                        .help("Capture with the probes")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Capture that the responses are written to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .about("Prints what a traceroute to one of the virtual hosts would show without needing a TUN device")
+                .arg(
+                    Arg::with_name("target")
+                        .help("Address that is tracerouted")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .help("Source address of the probes (defaults to an address from the documentation range)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("protocol")
+                        .long("protocol")
+                        .help("Kind of probes that are sent")
+                        .possible_values(&["icmp", "udp", "tcp"])
+                        .default_value("udp")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("queries")
+                        .short("q")
+                        .long("queries")
+                        .help("Number of probes per hop")
+                        .default_value("3")
+                        .takes_value(true)
+                        .validator(at_least(1)),
+                )
+                .arg(
+                    Arg::with_name("max_hops")
+                        .short("m")
+                        .long("max-hops")
+                        .help("Largest TTL that is probed")
+                        .default_value("30")
+                        .takes_value(true)
+                        .validator(in_range(1, u8::MAX as usize)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .about("Brings up the TUN devices in private user and network namespaces and checks that real probes are answered as expected"),
+        )
+        .get_matches_from(args);
 
-    Arguments {
+    let arguments = Arguments {
+        command: match matches.subcommand() {
+            ("replay", Some(replay_matches)) => Command::Replay {
+                input: PathBuf::from(replay_matches.value_of("input").unwrap()),
+                output: PathBuf::from(replay_matches.value_of("output").unwrap()),
+            },
+            ("simulate", Some(simulate_matches)) => Command::Simulate {
+                target: IpAddr::from_str(simulate_matches.value_of("target").unwrap())
+                    .expect("could not parse target as IP address"),
+                source: simulate_matches.value_of("source").map(|source| {
+                    IpAddr::from_str(source).expect("could not parse source as IP address")
+                }),
+                protocol: match simulate_matches.value_of("protocol").unwrap() {
+                    "icmp" => ProbeProtocol::Icmp,
+                    "tcp" => ProbeProtocol::Tcp,
+                    _ => ProbeProtocol::Udp,
+                },
+                queries: usize::from_str(simulate_matches.value_of("queries").unwrap())
+                    .expect("could not parse queries as number"),
+                max_hops: u8::from_str(simulate_matches.value_of("max_hops").unwrap())
+                    .expect("could not parse max-hops as number"),
+            },
+            ("selftest", _) => Command::Selftest,
+            _ => Command::Run,
+        },
         log_level: match matches.occurrences_of("verbosity") {
             0 => LevelFilter::Info,  // default log level
             1 => LevelFilter::Debug, // verbosity increased once
@@ -66,5 +440,100 @@ pub fn parse_arguments() -> Arguments {
             .unwrap()
             .map(|address| IpAddr::from_str(address).expect("Could not parse IP address"))
             .collect(),
+        icmp4_error_size: usize::from_str(matches.value_of("icmp4_error_size").unwrap())
+            .expect("could not parse icmp4-error-size as number"),
+        icmp6_error_size: usize::from_str(matches.value_of("icmp6_error_size").unwrap())
+            .expect("could not parse icmp6-error-size as number"),
+        reassembly_timeout: Duration::from_secs(
+            u64::from_str(matches.value_of("reassembly_timeout").unwrap())
+                .expect("could not parse reassembly-timeout as number"),
+        ),
+        reassembly_memory: usize::from_str(matches.value_of("reassembly_memory").unwrap())
+            .expect("could not parse reassembly-memory as number"),
+        mtu: matches
+            .value_of("mtu")
+            .map(|mtu| i32::from_str(mtu).expect("could not parse mtu as number")),
+        queues: usize::from_str(matches.value_of("queues").unwrap())
+            .expect("could not parse queues as number"),
+        io_backend: match matches.value_of("io_backend").unwrap() {
+            #[cfg(feature = "io-uring")]
+            "io-uring" => IoBackend::IoUring,
+            _ => IoBackend::Tokio,
+        },
+        single_device: matches.is_present("single_device"),
+        tap: matches.is_present("tap"),
+        nfqueue: matches
+            .value_of("nfqueue")
+            .map(|queue| u16::from_str(queue).expect("could not parse nfqueue as queue number")),
+        forwards: matches
+            .values_of("forwards")
+            .map(|forwards| {
+                forwards
+                    .map(|forward| Forward::from_str(forward).expect("could not parse forward"))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        netns: matches.value_of("netns").map(str::to_string),
+        attach: matches.is_present("attach"),
+        tun_fds: matches
+            .values_of("tun_fd")
+            .map(|fds| {
+                fds.map(|fd| i32::from_str(fd).expect("could not parse tun-fd as number"))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        user: matches.value_of("user").map(str::to_string),
+        group: matches.value_of("group").map(str::to_string),
+        seccomp: matches.is_present("seccomp"),
+        manage_routes: matches.is_present("manage_routes") || matches.is_present("routes"),
+        routes: matches
+            .values_of("routes")
+            .map(|routes| {
+                routes
+                    .map(|route| Prefix::from_str(route).expect("could not parse route as prefix"))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        route_table: u32::from_str(matches.value_of("route_table").unwrap())
+            .expect("could not parse route-table as number"),
+        capture: matches.value_of("capture").map(|path| CaptureConfig {
+            path: PathBuf::from(path),
+            max_size: u64::from_str(matches.value_of("capture_max_size").unwrap())
+                .expect("could not parse capture-max-size as number"),
+            max_files: usize::from_str(matches.value_of("capture_files").unwrap())
+                .expect("could not parse capture-files as number"),
+            filter: matches.value_of("capture_filter").map(|filter| {
+                Prefix::from_str(filter).expect("could not parse capture-filter as prefix")
+            }),
+        }),
+    };
+
+    if let Some(mtu) = arguments.mtu {
+        if (mtu as usize) < MIN_MTU6 && arguments.networks.iter().any(IpAddr::is_ipv6) {
+            clap::Error::with_description(
+                &format!("--mtu needs to be at least {} for IPv6 networks", MIN_MTU6),
+                clap::ErrorKind::ValueValidation,
+            )
+            .exit();
+        }
+    }
+    arguments
+}
+
+/// Validator for numeric arguments that need to be at least `minimum`
+fn at_least(minimum: usize) -> impl Fn(String) -> Result<(), String> {
+    move |value| match usize::from_str(&value) {
+        Ok(number) if number >= minimum => Ok(()),
+        Ok(_) => Err(format!("needs to be at least {}", minimum)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Validator for numeric arguments that need to be between `minimum` and `maximum`
+fn in_range(minimum: usize, maximum: usize) -> impl Fn(String) -> Result<(), String> {
+    move |value| match usize::from_str(&value) {
+        Ok(number) if (minimum..=maximum).contains(&number) => Ok(()),
+        Ok(_) => Err(format!("needs to be between {} and {}", minimum, maximum)),
+        Err(e) => Err(e.to_string()),
     }
 }
@@ -0,0 +1,272 @@
+//! Live capture of the packets that are received and sent
+//!
+//! All packets that pass the capture filter are written to a pcapng file together with the
+//! direction in which they passed.
+//! Once the file reaches its maximum size it is rotated like a log file so that only a limited
+//! number of older captures is kept around.
+//!
+//! The packet handlers only pass the packets on to a dedicated writer thread so that they never
+//! wait for the file system. Packets are dropped instead if the writer can not keep up, and
+//! the buffers of written packets are reused for further ones.
+//! Rotation happens with the privileges the program has at that point, so after `--user` the
+//! directory of the capture needs to be writable by that user.
+
+use crate::ip_addrs::Prefix;
+use crate::pcap::{Direction, PcapngWriter};
+use crate::stats::STATS;
+use log::{error, info};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long captured packets may stay buffered before they are written to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of packets that may wait for the writer thread before further ones are dropped
+const QUEUE_LEN: usize = 4096;
+
+/// The capture that packets are recorded to if capturing has been started
+static CAPTURE: OnceLock<Capture> = OnceLock::new();
+
+/// Configuration of a live capture
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CaptureConfig {
+    pub path: PathBuf,
+    /// Size in bytes after which the capture file is rotated
+    pub max_size: u64,
+    /// Number of rotated capture files that are kept
+    pub max_files: usize,
+    /// Only packets from or to addresses in this prefix are captured
+    pub filter: Option<Prefix>,
+}
+
+#[derive(Debug)]
+struct Capture {
+    config: CaptureConfig,
+    writer: SyncSender<Message>,
+    /// Number of packets that wait for the writer thread, so that packets which do not fit into
+    /// the queue are dropped before they are copied
+    queued: AtomicUsize,
+    /// Buffers of packets that have been written, which are reused for further packets
+    free_buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+/// What the writer thread is asked to do
+#[derive(Debug)]
+enum Message {
+    Packet {
+        timestamp: Duration,
+        direction: Direction,
+        data: Vec<u8>,
+    },
+    /// Write all buffered packets to the file and report the result
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+#[derive(Debug)]
+struct CaptureFile {
+    writer: PcapngWriter<BufWriter<File>>,
+    last_flush: Instant,
+}
+
+/// Start capturing all packets that are recorded from now on
+pub fn start(config: CaptureConfig) -> io::Result<()> {
+    let file = CaptureFile {
+        writer: create_file(&config.path)?,
+        last_flush: Instant::now(),
+    };
+    let (writer, messages) = mpsc::sync_channel(QUEUE_LEN);
+    CAPTURE
+        .set(Capture {
+            config: config.clone(),
+            writer,
+            queued: AtomicUsize::new(0),
+            free_buffers: Mutex::new(Vec::new()),
+        })
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "capture is already running"))?;
+    let capture = CAPTURE.get().expect("Capture was just started");
+    info!("Capturing packets [path={}]", config.path.display());
+    std::thread::Builder::new()
+        .name("capture".to_string())
+        .spawn(move || write_packets(capture, file, &messages))?;
+    Ok(())
+}
+
+/// Record that `packet` passed in `direction` if a capture is running and the packet passes its
+/// filter
+pub fn record(direction: Direction, packet: &[u8]) {
+    let capture = match CAPTURE.get() {
+        None => return,
+        Some(capture) => capture,
+    };
+    if let Some(filter) = &capture.config.filter {
+        // the filter applies to the remote side which is the source of received packets and
+        // the destination of sent ones
+        match remote_address(direction, packet) {
+            Some(address) if filter.contains(&address) => {}
+            _ => return,
+        }
+    }
+
+    if capture.queued.load(Ordering::Relaxed) >= QUEUE_LEN {
+        STATS.capture.dropped.increment();
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut data = capture
+        .free_buffers
+        .lock()
+        .expect("Capture buffers are poisoned")
+        .pop()
+        .unwrap_or_default();
+    data.clear();
+    data.extend_from_slice(packet);
+    let message = Message::Packet {
+        timestamp,
+        direction,
+        data,
+    };
+    capture.queued.fetch_add(1, Ordering::Relaxed);
+    match capture.writer.try_send(message) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            capture.queued.fetch_sub(1, Ordering::Relaxed);
+            STATS.capture.dropped.increment();
+        }
+        // the writer has stopped after an error which it already logged
+        Err(TrySendError::Disconnected(_)) => {
+            capture.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Write the packets that are still buffered to the capture file, e.g. before the program exits
+pub fn flush() -> io::Result<()> {
+    let capture = match CAPTURE.get() {
+        None => return Ok(()),
+        Some(capture) => capture,
+    };
+    let stopped = || io::Error::other("capture writer has stopped");
+    let (result_tx, result_rx) = mpsc::channel();
+    capture
+        .writer
+        .send(Message::Flush(result_tx))
+        .map_err(|_| stopped())?;
+    result_rx.recv().map_err(|_| stopped())?
+}
+
+/// Write the packets from `messages` to `file` until the capture stops or writing fails
+fn write_packets(capture: &Capture, mut file: CaptureFile, messages: &Receiver<Message>) {
+    let config = &capture.config;
+    loop {
+        // packets are flushed after a while even if no further ones arrive
+        let result = match messages.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Message::Packet {
+                timestamp,
+                direction,
+                data,
+            }) => {
+                capture.queued.fetch_sub(1, Ordering::Relaxed);
+                let result = file.write_packet(config, timestamp, direction, &data);
+                let mut free_buffers = capture
+                    .free_buffers
+                    .lock()
+                    .expect("Capture buffers are poisoned");
+                if free_buffers.len() < QUEUE_LEN {
+                    free_buffers.push(data);
+                }
+                result
+            }
+            Ok(Message::Flush(result_tx)) => {
+                let _ = result_tx.send(file.flush());
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => file.flush(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if let Err(e) = result {
+            error!("Could not capture packet, stopping capture [error={}]", e);
+            return;
+        }
+    }
+}
+
+impl CaptureFile {
+    fn write_packet(
+        &mut self,
+        config: &CaptureConfig,
+        timestamp: Duration,
+        direction: Direction,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        if self.writer.bytes_written() >= config.max_size {
+            self.writer.flush()?;
+            rotate(&config.path, config.max_files)?;
+            self.writer = create_file(&config.path)?;
+        }
+
+        self.writer.write_packet(timestamp, direction, packet)?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+}
+
+fn create_file(path: &Path) -> io::Result<PcapngWriter<BufWriter<File>>> {
+    PcapngWriter::new(BufWriter::new(File::create(path)?))
+}
+
+/// Move the capture at `path` to `<path>.1`, `<path>.1` to `<path>.2` and so on while only
+/// keeping `max_files` of them
+fn rotate(path: &Path, max_files: usize) -> io::Result<()> {
+    let rotated = |i: usize| {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(format!(".{}", i));
+        PathBuf::from(rotated)
+    };
+
+    if max_files == 0 {
+        return fs::remove_file(path);
+    }
+    for i in (1..max_files).rev() {
+        match fs::rename(rotated(i), rotated(i + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+/// Get the address of the other side of the IP `packet`
+fn remote_address(direction: Direction, packet: &[u8]) -> Option<IpAddr> {
+    match (packet.first()? >> 4, direction) {
+        (4, Direction::Inbound) => Some(ipv4_at(packet, 12)?.into()),
+        (4, Direction::Outbound) => Some(ipv4_at(packet, 16)?.into()),
+        (6, Direction::Inbound) => Some(ipv6_at(packet, 8)?.into()),
+        (6, Direction::Outbound) => Some(ipv6_at(packet, 24)?.into()),
+        _ => None,
+    }
+}
+
+fn ipv4_at(packet: &[u8], offset: usize) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = packet.get(offset..offset + 4)?.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+fn ipv6_at(packet: &[u8], offset: usize) -> Option<Ipv6Addr> {
+    let octets: [u8; 16] = packet.get(offset..offset + 16)?.try_into().ok()?;
+    Some(Ipv6Addr::from(octets))
+}
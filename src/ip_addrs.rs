@@ -1,5 +1,7 @@
 use log::trace;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 /// reverse of `Ipv4Addr::from(<u32>)`
 fn ipv4_to_u32(addr: &Ipv4Addr) -> u32 {
@@ -89,6 +91,103 @@ pub fn get_nth_address_in_network6(n: usize, prefix_length: usize, network: &Ipv
     Ipv6Addr::from(device_address)
 }
 
+/// Get the number of `address` inside its network which is the reverse of
+/// `get_nth_address_in_network4()`
+pub fn get_host_number_in_network4(netmask_size: u32, address: &Ipv4Addr) -> u32 {
+    let netmask = ipv4_to_u32(&calc_netmask_from_size4(netmask_size));
+    ipv4_to_u32(address) & !netmask
+}
+
+/// Get the number of `address` inside its network which is the reverse of
+/// `get_nth_address_in_network6()`
+pub fn get_host_number_in_network6(prefix_length: usize, address: &Ipv6Addr) -> u128 {
+    let netmask = ipv6_to_u128(&calc_netmask_from_size6(prefix_length));
+    ipv6_to_u128(address) & !netmask
+}
+
+/// Whether both addresses are part of the same network
+pub fn is_same_network6(prefix_length: usize, a: &Ipv6Addr, b: &Ipv6Addr) -> bool {
+    let netmask = ipv6_to_u128(&calc_netmask_from_size6(prefix_length));
+    ipv6_to_u128(a) & netmask == ipv6_to_u128(b) & netmask
+}
+
+/// An IP network that is given by its address and prefix length like `192.0.2.0/24`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Prefix {
+    pub address: IpAddr,
+    pub length: u8,
+}
+
+impl Prefix {
+    /// The virtual network that starts at `network` and is large enough for `n_hosts` hosts
+    pub fn virtual_network(network: IpAddr, n_hosts: usize) -> Self {
+        let length = match network {
+            IpAddr::V4(_) => calc_netmask_size_with_n_hosts4(n_hosts) as u8,
+            IpAddr::V6(_) => calc_netmask_size_with_n_hosts6(n_hosts) as u8,
+        };
+        Self {
+            address: network,
+            length,
+        }
+    }
+
+    /// Whether `address` is part of this network
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let netmask = ipv4_to_u32(&calc_netmask_from_size4(self.length as u32));
+                ipv4_to_u32(&network) & netmask == ipv4_to_u32(address) & netmask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                is_same_network6(self.length as usize, &network, address)
+            }
+            _ => false,
+        }
+    }
+
+    /// The first address of this network, i.e. `address` without its host bits
+    pub fn network(&self) -> IpAddr {
+        match self.address {
+            IpAddr::V4(address) => {
+                let netmask = ipv4_to_u32(&calc_netmask_from_size4(self.length as u32));
+                Ipv4Addr::from(ipv4_to_u32(&address) & netmask).into()
+            }
+            IpAddr::V6(address) => {
+                let netmask = ipv6_to_u128(&calc_netmask_from_size6(self.length as usize));
+                Ipv6Addr::from(ipv6_to_u128(&address) & netmask).into()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    /// Parse a prefix in CIDR notation, a single address is a prefix of its full length
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, length) = match s.split_once('/') {
+            None => (s, None),
+            Some((address, length)) => (address, Some(length)),
+        };
+        let address = IpAddr::from_str(address).map_err(|e| e.to_string())?;
+        let max_length = if address.is_ipv4() { 32 } else { 128 };
+        let length = match length {
+            None => max_length,
+            Some(length) => u8::from_str(length).map_err(|e| e.to_string())?,
+        };
+        if length > max_length {
+            return Err(format!("prefix length {} is too long", length));
+        }
+        Ok(Self { address, length })
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn test_calc_netmask_from_size4() {
@@ -138,3 +237,36 @@ fn test_get_nth_address_in_network6() {
         Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)
     )
 }
+
+#[cfg(test)]
+#[test]
+fn test_get_host_number_in_network6() {
+    assert_eq!(
+        get_host_number_in_network6(64, &Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x1f)),
+        0x1f
+    );
+    assert_eq!(
+        get_host_number_in_network6(124, &Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x1f)),
+        0xf
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_prefix() {
+    let prefix = Prefix::from_str("192.0.2.0/24").unwrap();
+    assert!(prefix.contains(&IpAddr::from_str("192.0.2.200").unwrap()));
+    assert!(!prefix.contains(&IpAddr::from_str("192.0.3.1").unwrap()));
+    assert!(!prefix.contains(&IpAddr::from_str("2001:db8::1").unwrap()));
+
+    let prefix = Prefix::from_str("2001:db8::/32").unwrap();
+    assert!(prefix.contains(&IpAddr::from_str("2001:db8:ffff::1").unwrap()));
+    assert!(!prefix.contains(&IpAddr::from_str("2001:db9::1").unwrap()));
+
+    assert_eq!(Prefix::from_str("10.0.0.1").unwrap().length, 32);
+    assert_eq!(
+        Prefix::from_str("192.0.2.77/24").unwrap().network(),
+        IpAddr::from_str("192.0.2.0").unwrap()
+    );
+    assert!(Prefix::from_str("10.0.0.0/33").is_err());
+}
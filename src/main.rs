@@ -1,46 +1,354 @@
 #![feature(ip)]
 #![feature(async_closure)]
+#![cfg_attr(test, feature(test))]
 
-use crate::argparse::Arguments;
-use log::{debug, info, trace, warn, LevelFilter};
+#[cfg(test)]
+extern crate test;
+
+use crate::argparse::{Arguments, Command, IoBackend};
+use crate::ip_addrs::Prefix;
+use crate::netlink::{RouteError, RouteManager};
+use crate::packet_io::PacketIo;
+use crate::pcap::Direction;
+use crate::stats::STATS;
+use log::{debug, error, info, trace, warn, LevelFilter};
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
-use tokio_tun::Tun;
 
 mod argparse;
+mod capture;
 mod ip_addrs;
+mod netlink;
+mod nfqueue;
+#[cfg(feature = "systemd")]
+mod notify;
+mod packet_io;
 mod packets;
+mod pcap;
+mod replay;
+mod sandbox;
+mod selftest;
+mod simulate;
+mod stats;
 mod tun_management;
+#[cfg(feature = "io-uring")]
+mod uring;
+
+/// How often the packet handlers are given a chance to handle things like timeouts
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args = argparse::parse_arguments();
     setup_logging(args.log_level);
     debug!("Parsed program arguments [args={:?}]", args);
 
-    let tun_devices =
-        tun_management::create_tun_devices(&args.tun_device_name, args.n_hosts, &args.networks)
-            .await;
-    debug!("Created all tun devices");
+    // the threads of the runtime need to inherit this
+    if args.user.is_some() && matches!(args.command, Command::Run) {
+        sandbox::prepare_privilege_drop().expect("Could not prepare dropping privileges");
+    }
+    // this changes the environment, which is only safe before the runtime starts its threads
+    let listen_fds = match args.command {
+        Command::Run => tun_management::listen_fds(),
+        _ => Vec::new(),
+    };
+    tokio::runtime::Runtime::new()
+        .expect("Could not start runtime")
+        .block_on(run_command(&args, listen_fds));
+}
 
-    let handles: Vec<JoinHandle<_>> = tun_devices
-        .into_iter()
-        .map(|tun| {
-            let args = args.clone();
-            tokio::spawn(async move { loop_for_tun_device(&args, tun).await })
-        })
-        .collect();
+/// Run the subcommand that `args` selects
+///
+/// `listen_fds` are the file descriptors that systemd passed to the `Run` command.
+async fn run_command(args: &Arguments, listen_fds: Vec<RawFd>) {
+    match &args.command {
+        Command::Run => run(args, listen_fds).await,
+        Command::Replay { input, output } => {
+            replay::replay(args, input, output).expect("Could not replay capture")
+        }
+        Command::Simulate {
+            target,
+            source,
+            protocol,
+            queries,
+            max_hops,
+        } => simulate::simulate(
+            args,
+            *target,
+            *source,
+            *protocol,
+            *queries,
+            *max_hops,
+            &mut std::io::stdout().lock(),
+        )
+        .expect("Could not simulate traceroute"),
+        Command::Selftest => {
+            let passed = selftest::selftest(args).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+    }
+}
+
+/// Create the TUN devices, or take over the inherited ones, and answer the probes that arrive on
+/// them
+async fn run(args: &Arguments, listen_fds: Vec<RawFd>) {
+    if let Some(capture) = &args.capture {
+        capture::start(capture.clone()).expect("Could not start capture");
+    }
+    #[cfg(feature = "systemd")]
+    let notifier = notify::Notifier::from_env().expect("Could not open notification socket");
+    // names are resolved before setup so that typos are noticed early
+    let credentials = args.user.as_ref().map(|user| {
+        let uid = sandbox::lookup_user(user).expect("Could not resolve user");
+        let gid = match &args.group {
+            None => uid,
+            Some(group) => sandbox::lookup_group(group).expect("Could not resolve group"),
+        };
+        (uid, gid)
+    });
+
+    // the workers stop once `true` is sent
+    let (shutdown, stopped) = watch::channel(false);
+    let inherited_fds: Vec<_> = args.tun_fds.iter().copied().chain(listen_fds).collect();
+    let (device_names, handles) = if let Some(number) = args.nfqueue {
+        let queue = nfqueue::Queue::bind(number).expect("Could not bind to netfilter queue");
+        let senders = nfqueue::Senders::new().expect("Could not open raw sockets");
+        let args = args.clone();
+        let handle =
+            tokio::spawn(
+                async move { nfqueue::loop_for_queue(&args, queue, senders, stopped).await },
+            );
+        (Vec::new(), vec![handle])
+    } else if !inherited_fds.is_empty() {
+        let tun_devices = tun_management::inherited_tun_devices(&inherited_fds, args.tap)
+            .expect("Could not use inherited TUN file descriptors");
+        (
+            device_names(&tun_devices),
+            spawn_workers(args, tun_devices, stopped),
+        )
+    } else if args.attach {
+        let tun_devices = tun_management::attach_tun_devices(
+            &args.tun_device_name,
+            device_networks(args).len(),
+            args.queues,
+            args.tap,
+        )
+        .expect("Could not attach to persistent TUN devices");
+        (
+            device_names(&tun_devices),
+            spawn_workers(args, tun_devices, stopped),
+        )
+    } else {
+        let tun_devices = match &args.netns {
+            None => tun_management::create_tun_devices(args).await,
+            Some(netns) => tun_management::create_tun_devices_in_netns(netns, args)
+                .await
+                .expect("Could not create TUN devices in network namespace"),
+        };
+        debug!("Created all tun devices");
+        (
+            device_names(&tun_devices),
+            spawn_workers(args, tun_devices, stopped),
+        )
+    };
+
+    let mut routes = if args.manage_routes {
+        match setup_routes(args, &device_names) {
+            Ok(routes) => Some(routes),
+            Err(e) => {
+                error!("Could not set up routes [error={}]", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    if let Some((uid, gid)) = credentials {
+        sandbox::drop_privileges(uid, gid).expect("Could not drop privileges");
+    }
+    if args.seccomp {
+        sandbox::apply_seccomp_filter().expect("Could not apply seccomp filter");
+    }
     info!("Now Listening for incoming packets");
 
     #[cfg(feature = "systemd")]
-    let _ = systemd::daemon::notify(true, (&[(systemd::daemon::STATE_READY, "1")]).iter());
+    if let Some(notifier) = &notifier {
+        notifier.notify(notify::READY);
+    }
+
+    let workers = async {
+        for handle in handles {
+            let _ = tokio::join!(handle);
+        }
+    };
+    tokio::pin!(workers);
+    let interrupted = tokio::select! {
+        _ = &mut workers => false,
+        signal = termination_signal() => {
+            info!("Received {}, shutting down", signal);
+            true
+        }
+    };
+
+    #[cfg(feature = "systemd")]
+    if let Some(notifier) = &notifier {
+        notifier.notify(notify::STOPPING);
+    }
+    // routes go first because stopping the workers may remove their devices
+    if let Some(routes) = &mut routes {
+        for e in routes.remove_all() {
+            error!("Could not remove route [error={}]", e);
+        }
+    }
+    if interrupted {
+        // the workers still send the responses to the packets they have already received and
+        // then close their queues, which removes the devices that were created by the program
+        let _ = shutdown.send(true);
+        tokio::select! {
+            _ = workers => {}
+            signal = termination_signal() => {
+                warn!("Received {} while shutting down, exiting without waiting for the workers", signal);
+                std::process::exit(1);
+            }
+        }
+    }
+    debug!("All workers stopped");
+
+    if let Err(e) = capture::flush() {
+        warn!("Could not flush capture [error={}]", e);
+    }
+    info!("Stopped [{}]", STATS);
+}
 
-    for handle in handles {
-        let _ = tokio::join!(handle);
+/// Wait until the program is asked to stop by SIGINT or SIGTERM
+///
+/// A second signal while the workers are stopping ends the program right away.
+async fn termination_signal() -> &'static str {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Could not listen for termination signal");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "interrupt",
+        _ = terminate.recv() => "termination signal",
     }
 }
 
+/// Names of the TUN devices in the order of the configured networks
+fn device_names<T: AsRawFd>(tun_devices: &[Vec<T>]) -> Vec<String> {
+    tun_devices
+        .iter()
+        .map(|queues| {
+            tun_management::device_name(queues[0].as_raw_fd())
+                .expect("Could not read name of TUN device")
+        })
+        .collect()
+}
+
+/// The prefixes that each TUN device serves in the order of the devices.
+///
+/// These are the virtual networks of the device and the `--route` prefixes which are put on the
+/// first device of their address family.
+fn device_networks(args: &Arguments) -> Vec<Vec<Prefix>> {
+    let device_of = |i: usize| if args.single_device { 0 } else { i };
+    let mut devices = vec![Vec::new(); device_of(args.networks.len() - 1) + 1];
+    for (i, network) in args.networks.iter().enumerate() {
+        devices[device_of(i)].push(Prefix::virtual_network(*network, args.n_hosts));
+    }
+    for prefix in &args.routes {
+        let first_of_family = args
+            .networks
+            .iter()
+            .position(|network| network.is_ipv4() == prefix.address.is_ipv4());
+        if let Some(i) = first_of_family {
+            devices[device_of(i)].push(*prefix);
+        }
+    }
+    devices
+}
+
+/// Route the prefixes of every device to it. Routes installed before a failure are removed
+/// again.
+fn setup_routes(args: &Arguments, device_names: &[String]) -> Result<RouteManager, RouteError> {
+    // the devices live in the namespace they were created in
+    let mut routes = match &args.netns {
+        None => RouteManager::new(args.route_table)?,
+        Some(netns) => tun_management::in_netns(netns, || RouteManager::new(args.route_table))??,
+    };
+
+    let networks = device_networks(args);
+    if let Some(prefix) = args
+        .routes
+        .iter()
+        .find(|prefix| !networks.iter().flatten().any(|network| network == *prefix))
+    {
+        return Err(RouteError::NoDevice { prefix: *prefix });
+    }
+
+    let mut install = || {
+        for (prefixes, name) in networks.iter().zip(device_names) {
+            for prefix in prefixes {
+                routes.add_route(*prefix, name)?;
+            }
+        }
+        Ok(())
+    };
+    if let Err(e) = install() {
+        for e in routes.remove_all() {
+            error!("Could not remove route [error={}]", e);
+        }
+        return Err(e);
+    }
+    Ok(routes)
+}
+
+/// Start one worker per queue of every TUN device that answers the probes arriving on it until
+/// `true` is sent to `stopped`
+fn spawn_workers<T>(
+    args: &Arguments,
+    tun_devices: Vec<Vec<T>>,
+    stopped: watch::Receiver<bool>,
+) -> Vec<JoinHandle<()>>
+where
+    T: PacketIo + AsRawFd + Send + 'static,
+{
+    let mut handles: Vec<JoinHandle<()>> = Vec::new();
+    // devices that were not created from the arguments, e.g. additional inherited ones, serve
+    // everything that is routed to them
+    let mut networks = device_networks(args).into_iter();
+    for queues in tun_devices {
+        // all queues of a device share its state so that e.g. fragments of one packet that
+        // arrive on different queues are still put together
+        let mtu = tun_management::device_mtu(queues[0].as_raw_fd())
+            .expect("Could not read MTU of TUN device");
+        let state =
+            packets::State::new(args, mtu).with_networks(networks.next().unwrap_or_default());
+        let state = Arc::new(if args.tap {
+            state.with_ethernet()
+        } else {
+            state
+        });
+        for (i, tun) in queues.into_iter().enumerate() {
+            let args = args.clone();
+            let state = state.clone();
+            let stopped = stopped.clone();
+            handles.push(match args.io_backend {
+                IoBackend::Tokio => tokio::spawn(async move {
+                    loop_for_device(&args, &state, tun, i == 0, stopped).await
+                }),
+                #[cfg(feature = "io-uring")]
+                IoBackend::IoUring => tokio::task::spawn_blocking(move || {
+                    uring::loop_for_tun_device(&args, &state, tun, i == 0, &stopped)
+                }),
+            });
+        }
+    }
+    handles
+}
+
 fn setup_logging(log_level: LevelFilter) {
     TermLogger::init(
         log_level,
@@ -51,23 +359,143 @@ fn setup_logging(log_level: LevelFilter) {
     .expect("Could not setup logging");
 }
 
-async fn loop_for_tun_device(program_args: &Arguments, mut tun: Tun) {
+/// Receive packets from one queue of a device, handle them and send back the responses.
+///
+/// Timeouts are only handled by the worker of the device's first queue (`handles_timeouts`).
+/// The loop ends when no more packets can be received or `stopped` changes.
+async fn loop_for_device(
+    program_args: &Arguments,
+    state: &packets::State,
+    mut io: impl PacketIo,
+    handles_timeouts: bool,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut timeout_check = tokio::time::interval(TIMEOUT_CHECK_INTERVAL);
+
+    // one byte more than the MTU so that packets which are too large can be noticed
+    let mut buf = vec![0u8; state.max_frame_len() + 1];
+    let mut responses = packets::Responses::new();
     loop {
-        let mut buf = [0u8; 1024];
-        let n = tun
-            .read(&mut buf)
-            .await
-            .expect("Could not read from TUN device");
-        match packets::handle(program_args, &buf[..n]) {
-            None => {}
-            Some(response) => match tun.write(&response).await {
+        responses.clear();
+        tokio::select! {
+            n = io.recv(&mut buf) => match n {
+                Err(e) => {
+                    error!("Could not receive packet, stopping [error={}]", e);
+                    return;
+                }
+                Ok(n) => handle_read(program_args, state, &buf[..n], &mut responses),
+            },
+            _ = timeout_check.tick(), if handles_timeouts => {
+                packets::handle_timeouts(program_args, state, &mut responses);
+            }
+            _ = stopped.changed() => return,
+        }
+
+        for response in responses.iter() {
+            match io.send(response).await {
                 Err(e) => {
                     warn!("Could not write response [error={}]", e);
                 }
                 Ok(n_bytes) => {
                     trace!("Wrote response [n_bytes={}]", n_bytes);
+                    if let Some(packet) = packets::ip_packet(state, response) {
+                        capture::record(Direction::Outbound, packet);
+                    }
                 }
-            },
+            }
         }
     }
 }
+
+/// Handle the bytes of one read from a TUN device and add the responses to `responses`.
+///
+/// A read that filled the whole buffer of one byte more than the MTU is dropped because its
+/// packet was larger than the MTU.
+fn handle_read(
+    program_args: &Arguments,
+    state: &packets::State,
+    packet: &[u8],
+    responses: &mut packets::Responses,
+) {
+    if let Some(packet) = packets::ip_packet(state, packet) {
+        capture::record(Direction::Inbound, packet);
+    }
+    if packet.len() > state.max_frame_len() {
+        warn!(
+            "Dropping packet that is larger than the MTU [len={}, mtu={}]",
+            packet.len(),
+            state.mtu()
+        );
+        STATS.tun_reads.oversized.increment();
+    } else {
+        packets::handle(program_args, state, packet, responses);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_loop_for_device() {
+    use pnet_packet::icmp::{IcmpPacket, IcmpTypes};
+    use pnet_packet::ip::IpNextHeaderProtocols;
+    use pnet_packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+    use pnet_packet::Packet;
+    use std::net::Ipv4Addr;
+    use tokio::sync::mpsc;
+
+    let args =
+        argparse::parse_arguments_from(["vip_tracerouter", "--net", "10.0.0.0", "--nhosts", "10"]);
+    let state = packets::State::new(&args, 1500);
+    let (_shutdown, stopped) = watch::channel(false);
+    let (probes, incoming) = mpsc::channel(8);
+    let (outgoing, mut responses) = mpsc::channel(8);
+
+    // ICMP echo request to the 5th virtual host
+    let probe = |ttl: u8| {
+        let mut probe = vec![0; 20 + 8];
+        let mut packet = MutableIpv4Packet::new(&mut probe).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(28);
+        packet.set_ttl(ttl);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        packet.set_source(Ipv4Addr::new(192, 0, 2, 1));
+        packet.set_destination(Ipv4Addr::new(10, 0, 0, 5));
+        probe[20] = IcmpTypes::EchoRequest.0;
+        probe
+    };
+    probes.send(probe(3)).await.unwrap();
+    probes.send(probe(64)).await.unwrap();
+    drop(probes);
+
+    // the loop stops once all probes have been received
+    loop_for_device(
+        &args,
+        &state,
+        packet_io::ChannelIo::new(incoming, outgoing),
+        true,
+        stopped,
+    )
+    .await;
+
+    let time_exceeded = responses.recv().await.unwrap();
+    let time_exceeded = Ipv4Packet::new(&time_exceeded).unwrap();
+    assert_eq!(time_exceeded.get_source(), Ipv4Addr::new(10, 0, 0, 3));
+    assert_eq!(
+        IcmpPacket::new(time_exceeded.payload())
+            .unwrap()
+            .get_icmp_type(),
+        IcmpTypes::TimeExceeded
+    );
+
+    let echo_reply = responses.recv().await.unwrap();
+    let echo_reply = Ipv4Packet::new(&echo_reply).unwrap();
+    assert_eq!(echo_reply.get_source(), Ipv4Addr::new(10, 0, 0, 5));
+    assert_eq!(echo_reply.get_destination(), Ipv4Addr::new(192, 0, 2, 1));
+    assert_eq!(
+        IcmpPacket::new(echo_reply.payload())
+            .unwrap()
+            .get_icmp_type(),
+        IcmpTypes::EchoReply
+    );
+    assert!(responses.recv().await.is_none());
+}
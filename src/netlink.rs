@@ -0,0 +1,457 @@
+//! Management of the routes towards the TUN devices and their addresses via rtnetlink
+//!
+//! Only the on-link prefix of each device's own address is routed by the kernel automatically.
+//! The route manager additionally installs routes for the virtual prefixes and any extra
+//! destination prefixes, optionally into a separate routing table for policy routing, and
+//! removes exactly the routes that it installed again on shutdown.
+//! A single TUN device that serves several networks also gets its additional addresses from here
+//! since the device can only be created with one.
+//! See rtnetlink(7) for the message format.
+
+use crate::ip_addrs::Prefix;
+use crate::packet_io;
+use crate::tun_management;
+use log::{debug, info};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+
+const NLMSG_ERROR: u16 = 2;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_F_NODAD: u8 = 0x02;
+
+/// Routes installed by "static" configuration as opposed to the kernel or routing daemons
+const RTPROT_STATIC: u8 = 4;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+
+const NLMSG_HEADER_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const IFADDRMSG_LEN: usize = 8;
+
+/// Why a route or address could not be installed or removed
+#[derive(Debug)]
+pub enum RouteError {
+    /// The netlink socket could not be opened
+    Socket(io::Error),
+    /// There is no interface with the given name
+    UnknownInterface { interface: String, error: io::Error },
+    /// None of the TUN devices has the address family of the prefix
+    NoDevice { prefix: Prefix },
+    /// The kernel rejected adding or removing a route
+    Rejected {
+        operation: &'static str,
+        prefix: Prefix,
+        interface: String,
+        error: io::Error,
+    },
+    /// The kernel rejected adding an address to an interface
+    AddressRejected {
+        address: Prefix,
+        interface: String,
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::Socket(error) => write!(f, "netlink socket failed: {}", error),
+            RouteError::UnknownInterface { interface, error } => {
+                write!(f, "unknown interface {}: {}", interface, error)
+            }
+            RouteError::NoDevice { prefix } => {
+                write!(f, "no TUN device for the address family of {}", prefix)
+            }
+            RouteError::Rejected {
+                operation,
+                prefix,
+                interface,
+                error,
+            } => write!(
+                f,
+                "could not {} route to {} via {}: {}",
+                operation, prefix, interface, error
+            ),
+            RouteError::AddressRejected {
+                address,
+                interface,
+                error,
+            } => write!(
+                f,
+                "could not add address {} to {}: {}",
+                address, interface, error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RouteError::Socket(error)
+            | RouteError::UnknownInterface { error, .. }
+            | RouteError::Rejected { error, .. }
+            | RouteError::AddressRejected { error, .. } => Some(error),
+            RouteError::NoDevice { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for RouteError {
+    fn from(error: io::Error) -> Self {
+        RouteError::Socket(error)
+    }
+}
+
+/// A rtnetlink socket that sends one request at a time and waits for its acknowledgement
+#[derive(Debug)]
+struct Netlink {
+    socket: OwnedFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        Ok(Self {
+            socket: packet_io::raw_socket(libc::AF_NETLINK, libc::NETLINK_ROUTE)?,
+            sequence: 0,
+        })
+    }
+
+    /// Get the index of the interface `name` in the network namespace of the socket, which is
+    /// not necessarily the one of the calling thread
+    fn interface_index(&self, name: &str) -> Result<u32, RouteError> {
+        tun_management::interface_index(self.socket.as_raw_fd(), name).map_err(|error| {
+            RouteError::UnknownInterface {
+                interface: name.to_string(),
+                error,
+            }
+        })
+    }
+
+    /// Send a complete message and wait for the kernel's acknowledgement.
+    ///
+    /// The kernel's errors are returned as the corresponding `io::Error`.
+    fn request(&mut self, mut message: Vec<u8>) -> io::Result<()> {
+        self.sequence = self.sequence.wrapping_add(1);
+        message[8..12].copy_from_slice(&self.sequence.to_ne_bytes());
+        // SAFETY: the message buffer is valid for its length
+        let sent = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut response = [0u8; 4096];
+        loop {
+            // SAFETY: the response buffer is valid for its length
+            let len = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    response.as_mut_ptr() as *mut libc::c_void,
+                    response.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let response = &response[..len as usize];
+            if response.len() < NLMSG_HEADER_LEN + 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink response",
+                ));
+            }
+
+            let response_type = u16::from_ne_bytes([response[4], response[5]]);
+            let sequence = u32::from_ne_bytes(response[8..12].try_into().unwrap());
+            if sequence != self.sequence {
+                // a late answer to an earlier request
+                continue;
+            }
+            if response_type != NLMSG_ERROR {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected netlink response of type {}", response_type),
+                ));
+            }
+            // an error of 0 is an acknowledgement, everything else a negative errno
+            let errno = i32::from_ne_bytes(
+                response[NLMSG_HEADER_LEN..NLMSG_HEADER_LEN + 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            return match errno {
+                0 => Ok(()),
+                errno => Err(io::Error::from_raw_os_error(-errno)),
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    prefix: Prefix,
+    interface: String,
+    index: u32,
+}
+
+/// Installs routes towards interfaces and remembers them so that they can be removed again
+#[derive(Debug)]
+pub struct RouteManager {
+    netlink: Netlink,
+    table: u32,
+    installed: Vec<Route>,
+}
+
+impl RouteManager {
+    /// Open a route manager that installs routes into `table` of the current network namespace
+    pub fn new(table: u32) -> Result<Self, RouteError> {
+        Ok(Self {
+            netlink: Netlink::open()?,
+            table,
+            installed: Vec::new(),
+        })
+    }
+
+    /// Route `prefix` to the interface `interface`.
+    ///
+    /// Routes that exist already, e.g. the on-link prefix of the interface's own address, are
+    /// left alone and not removed later either.
+    pub fn add_route(&mut self, prefix: Prefix, interface: &str) -> Result<(), RouteError> {
+        let route = Route {
+            prefix: Prefix {
+                address: prefix.network(),
+                length: prefix.length,
+            },
+            interface: interface.to_string(),
+            index: self.netlink.interface_index(interface)?,
+        };
+
+        match self.request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &route, "add") {
+            Err(RouteError::Rejected { error, .. })
+                if error.raw_os_error() == Some(libc::EEXIST) =>
+            {
+                debug!(
+                    "Route exists already [prefix={}, interface={}]",
+                    route.prefix, route.interface
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+            Ok(()) => {
+                info!(
+                    "Installed route [prefix={}, interface={}, table={}]",
+                    route.prefix, route.interface, self.table
+                );
+                self.installed.push(route);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove all routes that were installed by this manager and return the errors of the ones
+    /// that could not be removed
+    pub fn remove_all(&mut self) -> Vec<RouteError> {
+        let mut errors = Vec::new();
+        for route in std::mem::take(&mut self.installed) {
+            match self.request(RTM_DELROUTE, 0, &route, "remove") {
+                Ok(()) => info!(
+                    "Removed route [prefix={}, interface={}]",
+                    route.prefix, route.interface
+                ),
+                // the kernel removes the routes of a device together with the device
+                Err(RouteError::Rejected { error, .. })
+                    if matches!(error.raw_os_error(), Some(libc::ESRCH | libc::ENODEV)) =>
+                {
+                    debug!(
+                        "Route was already removed [prefix={}, interface={}]",
+                        route.prefix, route.interface
+                    )
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        errors
+    }
+
+    fn request(
+        &mut self,
+        message_type: u16,
+        flags: u16,
+        route: &Route,
+        operation: &'static str,
+    ) -> Result<(), RouteError> {
+        let message = build_route_message(
+            message_type,
+            NLM_F_REQUEST | NLM_F_ACK | flags,
+            self.table,
+            route,
+        );
+        self.netlink
+            .request(message)
+            .map_err(|error| RouteError::Rejected {
+                operation,
+                prefix: route.prefix,
+                interface: route.interface.clone(),
+                error,
+            })
+    }
+}
+
+/// Add `address` with its prefix length to the interface `interface` of the current network
+/// namespace.
+///
+/// Duplicate address detection is skipped because nothing else can be using the addresses of
+/// the virtual networks.
+pub fn add_address(address: Prefix, interface: &str) -> Result<(), RouteError> {
+    let mut netlink = Netlink::open()?;
+    let index = netlink.interface_index(interface)?;
+    let message = build_address_message(
+        RTM_NEWADDR,
+        NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE,
+        address,
+        index,
+    );
+    netlink
+        .request(message)
+        .map_err(|error| RouteError::AddressRejected {
+            address,
+            interface: interface.to_string(),
+            error,
+        })?;
+    info!(
+        "Added address to interface [address={}, interface={}]",
+        address, interface
+    );
+    Ok(())
+}
+
+/// The address family and raw bytes of `address`
+fn family_and_octets(address: IpAddr) -> (u8, Vec<u8>) {
+    match address {
+        IpAddr::V4(address) => (libc::AF_INET as u8, address.octets().to_vec()),
+        IpAddr::V6(address) => (libc::AF_INET6 as u8, address.octets().to_vec()),
+    }
+}
+
+/// Start a message with a netlink header whose length and sequence number are filled in later
+fn start_message(message_type: u16, flags: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(64);
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message
+}
+
+/// Fill in the length of a complete message
+fn finish_message(mut message: Vec<u8>) -> Vec<u8> {
+    let len = message.len() as u32;
+    message[..4].copy_from_slice(&len.to_ne_bytes());
+    message
+}
+
+/// Build a complete netlink message that adds or removes `route`
+fn build_route_message(message_type: u16, flags: u16, table: u32, route: &Route) -> Vec<u8> {
+    let (family, destination) = family_and_octets(route.prefix.address);
+    let mut message = start_message(message_type, flags);
+
+    // rtmsg, tables above 255 only fit into the RTA_TABLE attribute
+    message.extend_from_slice(&[
+        family,
+        route.prefix.length,
+        0,
+        0,
+        if table < 256 { table as u8 } else { 0 },
+        RTPROT_STATIC,
+        RT_SCOPE_LINK,
+        RTN_UNICAST,
+    ]);
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    debug_assert_eq!(message.len(), NLMSG_HEADER_LEN + RTMSG_LEN);
+
+    push_attribute(&mut message, RTA_DST, &destination);
+    push_attribute(&mut message, RTA_OIF, &route.index.to_ne_bytes());
+    push_attribute(&mut message, RTA_TABLE, &table.to_ne_bytes());
+    finish_message(message)
+}
+
+/// Build a complete netlink message that adds `address` to the interface with index `index`
+fn build_address_message(message_type: u16, flags: u16, address: Prefix, index: u32) -> Vec<u8> {
+    let (family, octets) = family_and_octets(address.address);
+    let mut message = start_message(message_type, flags);
+
+    // ifaddrmsg
+    message.extend_from_slice(&[family, address.length, IFA_F_NODAD, RT_SCOPE_UNIVERSE]);
+    message.extend_from_slice(&index.to_ne_bytes());
+    debug_assert_eq!(message.len(), NLMSG_HEADER_LEN + IFADDRMSG_LEN);
+
+    // for IPv4, IFA_LOCAL is the address of the interface and IFA_ADDRESS the one of its peer,
+    // which are the same without a point-to-point peer
+    push_attribute(&mut message, IFA_LOCAL, &octets);
+    push_attribute(&mut message, IFA_ADDRESS, &octets);
+    finish_message(message)
+}
+
+/// Append a route attribute padded to 4 bytes
+fn push_attribute(message: &mut Vec<u8>, attribute_type: u16, value: &[u8]) {
+    let len = 4 + value.len();
+    message.extend_from_slice(&(len as u16).to_ne_bytes());
+    message.extend_from_slice(&attribute_type.to_ne_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len() + (4 - len % 4) % 4, 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_build_route_message() {
+    use std::str::FromStr;
+
+    let route = Route {
+        prefix: Prefix::from_str("192.0.2.0/24").unwrap(),
+        interface: "tun0".to_string(),
+        index: 7,
+    };
+    let message = build_route_message(RTM_NEWROUTE, NLM_F_REQUEST, 1000, &route);
+    // header, rtmsg and three attributes of 8 bytes each
+    assert_eq!(message.len(), NLMSG_HEADER_LEN + RTMSG_LEN + 3 * 8);
+    assert_eq!(
+        u32::from_ne_bytes(message[..4].try_into().unwrap()) as usize,
+        message.len()
+    );
+    // the table does not fit into rtmsg
+    assert_eq!(message[NLMSG_HEADER_LEN + 4], 0);
+    let destination = &message[NLMSG_HEADER_LEN + RTMSG_LEN..][..8];
+    assert_eq!(
+        u16::from_ne_bytes([destination[2], destination[3]]),
+        RTA_DST
+    );
+    assert_eq!(destination[4..], [192, 0, 2, 0]);
+}
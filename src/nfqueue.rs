@@ -0,0 +1,419 @@
+//! Netfilter queue backend that puts virtual hops in front of real addresses
+//!
+//! Instead of being read from a TUN device, packets are queued to this program by a netfilter
+//! rule like `iptables -A INPUT -d 203.0.113.7 -m ttl --ttl-lt 11 -j NFQUEUE --queue-num 0`.
+//! Packets whose TTL runs out before they passed all virtual hops are dropped and answered with
+//! a time exceeded message from the virtual hop, which is sent through a raw socket.
+//! All other packets are accepted so that the services on the real address keep working.
+//! The virtual hops are the hosts of the first `--net` network of the packet's address family.
+//! See `linux/netfilter/nfnetlink_queue.h` for the message format.
+
+use crate::argparse::Arguments;
+use crate::capture;
+use crate::ip_addrs;
+use crate::packet_io::{self, RawSender};
+use crate::packets;
+use crate::pcap::Direction;
+use log::{debug, error, info, trace, warn};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::watch;
+
+const NETLINK_NETFILTER: libc::c_int = 12;
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLMSG_HEADER_LEN: usize = 16;
+/// Length of `struct nfgenmsg` which follows the netlink header
+const NFGENMSG_LEN: usize = 4;
+
+/// Netfilter subsystem of the queue messages which is the high byte of their type
+const NFNL_SUBSYS_QUEUE: u16 = 3;
+const NFQNL_MSG_PACKET: u16 = NFNL_SUBSYS_QUEUE << 8;
+const NFQNL_MSG_VERDICT: u16 = NFNL_SUBSYS_QUEUE << 8 | 1;
+const NFQNL_MSG_CONFIG: u16 = NFNL_SUBSYS_QUEUE << 8 | 2;
+
+const NFQA_CFG_CMD: u16 = 1;
+const NFQA_CFG_PARAMS: u16 = 2;
+const NFQA_CFG_MASK: u16 = 4;
+const NFQA_CFG_FLAGS: u16 = 5;
+const NFQNL_CFG_CMD_BIND: u8 = 1;
+const NFQNL_COPY_PACKET: u8 = 2;
+/// Accept packets instead of dropping them while the queue is full
+const NFQA_CFG_F_FAIL_OPEN: u32 = 1;
+
+const NFQA_PACKET_HDR: u16 = 1;
+const NFQA_VERDICT_HDR: u16 = 2;
+const NFQA_PAYLOAD: u16 = 10;
+/// Flags in the type of an attribute that are not part of the type itself
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+
+/// How many bytes of every queued packet are copied to this program
+const COPY_RANGE: u32 = u16::MAX as u32;
+
+/// A bound netfilter queue that packets are read from and verdicts are sent to
+#[derive(Debug)]
+pub struct Queue {
+    fd: AsyncFd<OwnedFd>,
+    number: u16,
+}
+
+impl Queue {
+    /// Bind to the queue with the given number, which needs `CAP_NET_ADMIN`
+    pub fn bind(number: u16) -> io::Result<Self> {
+        let fd = packet_io::raw_socket(libc::AF_NETLINK, NETLINK_NETFILTER)?;
+        let mut command = Message::new(NFQNL_MSG_CONFIG, NLM_F_REQUEST | NLM_F_ACK, number);
+        command.push_attribute(NFQA_CFG_CMD, &[NFQNL_CFG_CMD_BIND, 0, 0, 0]);
+        request(&fd, command)?;
+        let mut params = Message::new(NFQNL_MSG_CONFIG, NLM_F_REQUEST | NLM_F_ACK, number);
+        let mut copy = COPY_RANGE.to_be_bytes().to_vec();
+        copy.push(NFQNL_COPY_PACKET);
+        params.push_attribute(NFQA_CFG_PARAMS, &copy);
+        params.push_attribute(NFQA_CFG_MASK, &NFQA_CFG_F_FAIL_OPEN.to_be_bytes());
+        params.push_attribute(NFQA_CFG_FLAGS, &NFQA_CFG_F_FAIL_OPEN.to_be_bytes());
+        request(&fd, params)?;
+
+        packet_io::set_nonblocking(fd.as_raw_fd(), true)?;
+        info!("Bound to netfilter queue [queue={}]", number);
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            number,
+        })
+    }
+
+    /// Receive the next batch of netlink messages into `buffer` and return its length
+    async fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            // SAFETY: the buffer is valid for writes of its length
+            match guard.try_io(|fd| unsafe {
+                let n = libc::recv(
+                    fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                );
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Tell the kernel what to do with the queued packet `id`
+    fn verdict(&self, id: u32, verdict: u32) -> io::Result<()> {
+        let mut message = Message::new(NFQNL_MSG_VERDICT, NLM_F_REQUEST, self.number);
+        let mut header = verdict.to_be_bytes().to_vec();
+        header.extend_from_slice(&id.to_be_bytes());
+        message.push_attribute(NFQA_VERDICT_HDR, &header);
+        send(self.fd.get_ref(), &message.finish())
+    }
+}
+
+/// The raw sockets that time exceeded messages are sent from
+#[derive(Debug)]
+pub struct Senders {
+    ipv4: RawSender,
+    ipv6: RawSender,
+}
+
+impl Senders {
+    /// Open the raw sockets, which needs `CAP_NET_RAW`
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            ipv4: RawSender::new(Ipv4Addr::UNSPECIFIED.into())?,
+            ipv6: RawSender::new(Ipv6Addr::UNSPECIFIED.into())?,
+        })
+    }
+
+    fn send_to(&self, packet: &[u8], destination: IpAddr) -> io::Result<()> {
+        match destination {
+            IpAddr::V4(_) => self.ipv4.send_to(packet, destination),
+            IpAddr::V6(_) => self.ipv6.send_to(packet, destination),
+        }
+    }
+}
+
+/// Answer the packets of `queue` until it can not be read anymore or `stopped` changes
+pub async fn loop_for_queue(
+    program_args: &Arguments,
+    queue: Queue,
+    senders: Senders,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut buffer = vec![0u8; COPY_RANGE as usize + 4096];
+    let mut response = Vec::new();
+    loop {
+        let received = tokio::select! {
+            received = queue.recv(&mut buffer) => received,
+            _ = stopped.changed() => return,
+        };
+        let len = match received {
+            // the kernel drops queued packets that do not fit into the socket's buffer
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                warn!("Netfilter queue overflowed, packets were lost");
+                continue;
+            }
+            Err(e) => {
+                error!(
+                    "Could not receive from netfilter queue, stopping [error={}]",
+                    e
+                );
+                return;
+            }
+            Ok(len) => len,
+        };
+
+        for (id, packet) in queued_packets(&buffer[..len]) {
+            capture::record(Direction::Inbound, packet);
+            let verdict = match virtual_hop(program_args, packet) {
+                None => NF_ACCEPT,
+                Some((hop, source)) => {
+                    response.clear();
+                    if packets::build_time_exceeded(program_args, packet, hop, &mut response)
+                        .is_some()
+                    {
+                        debug!(
+                            "Answering queued packet from virtual hop [hop={}, source={}]",
+                            hop, source
+                        );
+                        match senders.send_to(&response, source) {
+                            Ok(()) => capture::record(Direction::Outbound, &response),
+                            Err(e) => warn!("Could not send response [error={}]", e),
+                        }
+                    }
+                    NF_DROP
+                }
+            };
+            if let Err(e) = queue.verdict(id, verdict) {
+                warn!("Could not send verdict [id={}, error={}]", id, e);
+            }
+        }
+    }
+}
+
+/// The virtual hop at which `packet` runs out of TTL together with the packet's source, or
+/// `None` if it makes it through all virtual hops to the real address
+fn virtual_hop(program_args: &Arguments, packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    let (ttl, source) = match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let source: [u8; 4] = packet[12..16].try_into().unwrap();
+            (packet[8], IpAddr::from(source))
+        }
+        6 if packet.len() >= 40 => {
+            let source: [u8; 16] = packet[8..24].try_into().unwrap();
+            (packet[7], IpAddr::from(source))
+        }
+        _ => return None,
+    };
+    if ttl as usize > program_args.n_hosts {
+        return None;
+    }
+    // a packet that is sent with a TTL of 0 still does not get past the first hop
+    let ttl = ttl.max(1);
+    let network = program_args
+        .networks
+        .iter()
+        .find(|network| network.is_ipv4() == source.is_ipv4())?;
+    let hop = match network {
+        IpAddr::V4(network) => IpAddr::V4(ip_addrs::get_nth_address_in_network4(
+            ttl as u32,
+            ip_addrs::calc_netmask_size_with_n_hosts4(program_args.n_hosts),
+            network,
+        )),
+        IpAddr::V6(network) => IpAddr::V6(ip_addrs::get_nth_address_in_network6(
+            ttl as usize,
+            ip_addrs::calc_netmask_size_with_n_hosts6(program_args.n_hosts),
+            network,
+        )),
+    };
+    trace!("Queued packet runs out of TTL [ttl={}, hop={}]", ttl, hop);
+    Some((hop, source))
+}
+
+/// The id and payload of every queued packet in a batch of netlink messages
+fn queued_packets(mut messages: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut result = Vec::new();
+    while messages.len() >= NLMSG_HEADER_LEN {
+        let len = u32::from_ne_bytes(messages[..4].try_into().unwrap()) as usize;
+        if len < NLMSG_HEADER_LEN || len > messages.len() {
+            break;
+        }
+        let message_type = u16::from_ne_bytes([messages[4], messages[5]]);
+        if message_type == NFQNL_MSG_PACKET && len >= NLMSG_HEADER_LEN + NFGENMSG_LEN {
+            let mut id = None;
+            let mut payload = None;
+            for (attribute_type, value) in
+                attributes(&messages[NLMSG_HEADER_LEN + NFGENMSG_LEN..len])
+            {
+                match attribute_type {
+                    NFQA_PACKET_HDR if value.len() >= 4 => {
+                        id = Some(u32::from_be_bytes(value[..4].try_into().unwrap()))
+                    }
+                    NFQA_PAYLOAD => payload = Some(value),
+                    _ => {}
+                }
+            }
+            match (id, payload) {
+                (Some(id), Some(payload)) => result.push((id, payload)),
+                _ => debug!("Ignoring incomplete queued packet message"),
+            }
+        }
+        messages = &messages[align(len).min(messages.len())..];
+    }
+    result
+}
+
+/// The types and values of the netlink attributes in `data`
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        if len < 4 || len > data.len() {
+            return None;
+        }
+        let attribute_type = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        let value = &data[4..len];
+        data = &data[align(len).min(data.len())..];
+        Some((attribute_type, value))
+    })
+}
+
+/// Round `len` up to the 4 byte alignment of netlink messages and attributes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A netfilter queue message under construction
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(message_type: u16, flags: u16, queue: u16) -> Self {
+        let mut message = Vec::with_capacity(64);
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(&flags.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg with the queue number as resource id
+        message.extend_from_slice(&[libc::AF_UNSPEC as u8, 0]);
+        message.extend_from_slice(&queue.to_be_bytes());
+        Self(message)
+    }
+
+    fn push_attribute(&mut self, attribute_type: u16, value: &[u8]) {
+        let len = 4 + value.len();
+        self.0.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.0.extend_from_slice(&attribute_type.to_ne_bytes());
+        self.0.extend_from_slice(value);
+        self.0.resize(align(self.0.len()), 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&len.to_ne_bytes());
+        self.0
+    }
+}
+
+fn send(fd: &OwnedFd, message: &[u8]) -> io::Result<()> {
+    // SAFETY: the message buffer is valid for its length
+    let sent = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send a configuration message on the still blocking socket and wait for its acknowledgement
+fn request(fd: &OwnedFd, message: Message) -> io::Result<()> {
+    send(fd, &message.finish())?;
+    let mut response = [0u8; 1024];
+    // SAFETY: the response buffer is valid for its length
+    let len = unsafe {
+        libc::recv(
+            fd.as_raw_fd(),
+            response.as_mut_ptr() as *mut libc::c_void,
+            response.len(),
+            0,
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let response = &response[..len as usize];
+    if response.len() < NLMSG_HEADER_LEN + 4
+        || u16::from_ne_bytes([response[4], response[5]]) != NLMSG_ERROR
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected netlink response",
+        ));
+    }
+    // an error of 0 is an acknowledgement, everything else a negative errno
+    match i32::from_ne_bytes(
+        response[NLMSG_HEADER_LEN..NLMSG_HEADER_LEN + 4]
+            .try_into()
+            .unwrap(),
+    ) {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(-errno)),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_queued_packets() {
+    let probe = [
+        0x45, 0, 0, 20, 0, 0, 0, 0, 3, 1, 0, 0, 192, 0, 2, 1, 203, 0, 113, 7,
+    ];
+    let mut message = Message::new(NFQNL_MSG_PACKET, 0, 0);
+    let mut header = 42u32.to_be_bytes().to_vec();
+    header.extend_from_slice(&[0x08, 0x00, 1]);
+    message.push_attribute(NFQA_PACKET_HDR, &header);
+    message.push_attribute(NFQA_PAYLOAD, &probe);
+    let mut messages = message.finish();
+    // a second message that is no queued packet
+    messages.extend_from_slice(&Message::new(NLMSG_ERROR, 0, 0).finish());
+
+    let queued = queued_packets(&messages);
+    assert_eq!(queued, vec![(42, &probe[..])]);
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--nhosts",
+        "5",
+        "--nfqueue",
+        "0",
+    ]);
+    // the probe with a TTL of 3 runs out at the third virtual hop
+    assert_eq!(
+        virtual_hop(&args, &probe),
+        Some((IpAddr::from([10, 0, 0, 3]), IpAddr::from([192, 0, 2, 1])))
+    );
+    let mut reached = probe;
+    reached[8] = 6;
+    assert_eq!(virtual_hop(&args, &reached), None);
+}
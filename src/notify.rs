@@ -0,0 +1,53 @@
+//! Notifications about the state of the program to the service manager (`sd_notify(3)`)
+//!
+//! The socket is opened before the program sandboxes itself so that the service manager can
+//! still be told when the program stops once no new sockets may be opened.
+
+use log::warn;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// The program has finished its setup
+pub const READY: &str = "READY=1";
+/// The program is shutting down
+pub const STOPPING: &str = "STOPPING=1";
+
+/// Sends notifications to the socket that the service manager passed in `NOTIFY_SOCKET`
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+}
+
+impl Notifier {
+    /// Open the notification socket, or return `None` if the service manager does not want to
+    /// be notified
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            None => return Ok(None),
+            Some(path) => path,
+        };
+        // names starting with @ are in the abstract namespace
+        let address = match path.as_bytes() {
+            [b'@', name @ ..] => SocketAddr::from_abstract_name(name)?,
+            _ => SocketAddr::from_pathname(&path)?,
+        };
+        Ok(Some(Self {
+            socket: UnixDatagram::unbound()?,
+            address,
+        }))
+    }
+
+    /// Tell the service manager about `state`, which is only logged if it fails because the
+    /// program works the same without it
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            warn!(
+                "Could not notify service manager [state={}, error={}]",
+                state, e
+            );
+        }
+    }
+}
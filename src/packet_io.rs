@@ -0,0 +1,244 @@
+//! Sources and sinks of the packets that the device loop handles
+//!
+//! The device loop only needs to receive packets and send responses back so it works the same
+//! on a real TUN device and on the in-memory channels that the tests use.
+
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(test)]
+use tokio::sync::mpsc;
+use tokio_tun::Tun;
+
+/// Something that IP packets can be received from and sent to
+pub trait PacketIo {
+    /// Receive the next packet into `buffer` and return its length
+    fn recv<'a>(
+        &'a mut self,
+        buffer: &'a mut [u8],
+    ) -> impl Future<Output = io::Result<usize>> + Send + 'a;
+
+    /// Send `packet` and return the number of bytes that were sent
+    fn send<'a>(
+        &'a mut self,
+        packet: &'a [u8],
+    ) -> impl Future<Output = io::Result<usize>> + Send + 'a;
+}
+
+impl PacketIo for Tun {
+    async fn recv<'a>(&'a mut self, buffer: &'a mut [u8]) -> io::Result<usize> {
+        self.read(buffer).await
+    }
+
+    async fn send<'a>(&'a mut self, packet: &'a [u8]) -> io::Result<usize> {
+        self.write(packet).await
+    }
+}
+
+/// An already open file descriptor of a TUN device queue
+///
+/// Unlike `Tun` this needs no privileges because the device was attached to by someone else or
+/// is owned by the user that runs the program.
+#[derive(Debug)]
+pub struct TunFd {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl TunFd {
+    /// Take ownership of `fd` and switch it to non-blocking mode
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        set_nonblocking(fd.as_raw_fd(), true)?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+}
+
+impl AsRawFd for TunFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl PacketIo for TunFd {
+    async fn recv<'a>(&'a mut self, buffer: &'a mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            // SAFETY: the buffer is valid for writes of its length
+            match guard.try_io(|fd| unsafe {
+                let n = libc::read(
+                    fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                );
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn send<'a>(&'a mut self, packet: &'a [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            // SAFETY: the packet is valid for reads of its length
+            match guard.try_io(|fd| unsafe {
+                let n = libc::write(
+                    fd.as_raw_fd(),
+                    packet.as_ptr() as *const libc::c_void,
+                    packet.len(),
+                );
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// A raw socket that sends complete IP packets which the kernel routes like its own
+#[derive(Debug)]
+pub struct RawSender {
+    fd: OwnedFd,
+}
+
+impl RawSender {
+    /// Open a sender for packets of the address family of `family`
+    pub fn new(family: IpAddr) -> io::Result<Self> {
+        let family = match family {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        };
+        // IPPROTO_RAW sockets expect the IP header to be included in the sent packets
+        Ok(Self {
+            fd: raw_socket(family, libc::IPPROTO_RAW)?,
+        })
+    }
+
+    /// Send `packet` whose IP header is addressed to `destination`
+    pub fn send_to(&self, packet: &[u8], destination: IpAddr) -> io::Result<()> {
+        // SAFETY: all-zero bytes are valid for the plain C address structs
+        let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let address_len = match destination {
+            IpAddr::V4(destination) => {
+                let address = &mut address as *mut _ as *mut libc::sockaddr_in;
+                // SAFETY: sockaddr_storage is large enough for every address type
+                unsafe {
+                    (*address).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*address).sin_addr.s_addr = u32::from_ne_bytes(destination.octets());
+                }
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            IpAddr::V6(destination) => {
+                let address = &mut address as *mut _ as *mut libc::sockaddr_in6;
+                // SAFETY: sockaddr_storage is large enough for every address type
+                unsafe {
+                    (*address).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*address).sin6_addr.s6_addr = destination.octets();
+                }
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        // SAFETY: the packet and address buffers are valid for the given lengths
+        let sent = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &address as *const _ as *const libc::sockaddr,
+                address_len as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Open a raw socket of `family` for `protocol`
+pub fn raw_socket(family: libc::c_int, protocol: libc::c_int) -> io::Result<OwnedFd> {
+    // SAFETY: socket has no memory safety requirements
+    let fd = unsafe { libc::socket(family, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just opened and is not owned by anything else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Set or clear `O_NONBLOCK` on `fd`
+pub fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+    // SAFETY: fcntl only reads and writes the flags of an open file descriptor
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if libc::fcntl(fd, libc::F_SETFL, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Packet I/O over in-memory channels which needs no privileges at all
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ChannelIo {
+    incoming: mpsc::Receiver<Vec<u8>>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl ChannelIo {
+    /// Create packet I/O that receives the packets sent into `incoming` and sends its packets
+    /// to `outgoing`
+    pub fn new(incoming: mpsc::Receiver<Vec<u8>>, outgoing: mpsc::Sender<Vec<u8>>) -> Self {
+        Self { incoming, outgoing }
+    }
+}
+
+#[cfg(test)]
+impl PacketIo for ChannelIo {
+    async fn recv<'a>(&'a mut self, buffer: &'a mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "channel closed"))?;
+        // behave like a TUN device which truncates packets that do not fit the buffer
+        let len = packet.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn send<'a>(&'a mut self, packet: &'a [u8]) -> io::Result<usize> {
+        self.outgoing
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "channel closed"))?;
+        Ok(packet.len())
+    }
+}
@@ -0,0 +1,288 @@
+//! Checks whether an ICMP error message may be sent in response to a packet at all
+//!
+//! RFC 1122 section 3.2.2 and RFC 4443 section 2.4 forbid sending ICMP errors in response to
+//! other ICMP errors, to packets addressed to a broadcast or multicast destination,
+//! to non-initial fragments and to packets whose source does not identify a single host.
+
+use super::ipv6_extensions::{ExtensionHeaders, PARAMETER_PROBLEM_UNRECOGNIZED_OPTION};
+use crate::argparse::Arguments;
+use crate::ip_addrs;
+use crate::stats::{Counter, STATS};
+use log::debug;
+use pnet_packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet_packet::icmpv6::Icmpv6Packet;
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::Packet;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// The reason why no ICMP error message is sent in response to a packet
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SuppressionReason {
+    IcmpError,
+    BroadcastDestination,
+    MulticastDestination,
+    NonInitialFragment,
+    InvalidSource,
+}
+
+impl SuppressionReason {
+    fn counter(self) -> &'static Counter {
+        let counters = &STATS.suppressed_icmp_errors;
+        match self {
+            SuppressionReason::IcmpError => &counters.icmp_error,
+            SuppressionReason::BroadcastDestination => &counters.broadcast_destination,
+            SuppressionReason::MulticastDestination => &counters.multicast_destination,
+            SuppressionReason::NonInitialFragment => &counters.non_initial_fragment,
+            SuppressionReason::InvalidSource => &counters.invalid_source,
+        }
+    }
+}
+
+/// Decide whether an ICMP error message may be sent in response to `packet`.
+///
+/// If not, the reason is logged and counted.
+pub fn may_send_icmp_error4(program_args: &Arguments, packet: &Ipv4Packet) -> bool {
+    record(check_ipv4(program_args, packet))
+}
+
+/// Decide whether an ICMPv6 error message may be sent in response to `packet`.
+///
+/// If not, the reason is logged and counted.
+pub fn may_send_icmp_error6(packet: &Ipv6Packet, extension_headers: &ExtensionHeaders) -> bool {
+    record(check_ipv6(packet, extension_headers, false))
+}
+
+/// Decide whether an ICMPv6 parameter problem message with the given `code` may be sent in
+/// response to `packet`.
+///
+/// Unlike other errors, parameter problems about unrecognized options may also be sent for
+/// packets with a multicast destination (RFC 4443 section 2.4 (e.3)).
+pub fn may_send_parameter_problem6(
+    packet: &Ipv6Packet,
+    extension_headers: &ExtensionHeaders,
+    code: u8,
+) -> bool {
+    record(check_ipv6(
+        packet,
+        extension_headers,
+        code == PARAMETER_PROBLEM_UNRECOGNIZED_OPTION,
+    ))
+}
+
+fn record(result: Result<(), SuppressionReason>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(reason) => {
+            debug!("Suppressing ICMP error message [reason={:?}]", reason);
+            reason.counter().increment();
+            false
+        }
+    }
+}
+
+fn check_ipv4(program_args: &Arguments, packet: &Ipv4Packet) -> Result<(), SuppressionReason> {
+    let source = packet.get_source();
+    if source.is_unspecified()
+        || source.is_loopback()
+        || source.is_multicast()
+        || source.is_broadcast()
+    {
+        return Err(SuppressionReason::InvalidSource);
+    }
+
+    let destination = packet.get_destination();
+    if destination.is_multicast() {
+        return Err(SuppressionReason::MulticastDestination);
+    }
+    if destination.is_broadcast() || is_network_broadcast4(program_args, &destination) {
+        return Err(SuppressionReason::BroadcastDestination);
+    }
+
+    if packet.get_fragment_offset() != 0 {
+        return Err(SuppressionReason::NonInitialFragment);
+    }
+
+    if packet.get_next_level_protocol() == IpNextHeaderProtocols::Icmp {
+        if let Some(icmp_packet) = IcmpPacket::new(packet.payload()) {
+            let icmp_type = icmp_packet.get_icmp_type();
+            if icmp_type == IcmpTypes::DestinationUnreachable
+                || icmp_type == IcmpTypes::SourceQuench
+                || icmp_type == IcmpTypes::RedirectMessage
+                || icmp_type == IcmpTypes::TimeExceeded
+                || icmp_type == IcmpTypes::ParameterProblem
+            {
+                return Err(SuppressionReason::IcmpError);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_ipv6(
+    packet: &Ipv6Packet,
+    extension_headers: &ExtensionHeaders,
+    multicast_destination_allowed: bool,
+) -> Result<(), SuppressionReason> {
+    let source = packet.get_source();
+    if source.is_unspecified() || source.is_loopback() || source.is_multicast() {
+        return Err(SuppressionReason::InvalidSource);
+    }
+
+    if packet.get_destination().is_multicast() && !multicast_destination_allowed {
+        return Err(SuppressionReason::MulticastDestination);
+    }
+
+    if extension_headers.is_non_initial_fragment() {
+        return Err(SuppressionReason::NonInitialFragment);
+    }
+
+    if extension_headers.upper_layer_protocol == IpNextHeaderProtocols::Icmpv6 {
+        if let Some(icmp_packet) = Icmpv6Packet::new(extension_headers.upper_layer_payload(packet))
+        {
+            // ICMPv6 error messages all have a type value below 128 (RFC 4443 section 2.1)
+            if icmp_packet.get_icmpv6_type().0 < 128 {
+                return Err(SuppressionReason::IcmpError);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `address` is the broadcast address of one of the virtual networks
+///
+/// Real addresses that the NFQUEUE backend answers for are never treated as broadcasts.
+fn is_network_broadcast4(program_args: &Arguments, address: &Ipv4Addr) -> bool {
+    let netmask_size = ip_addrs::calc_netmask_size_with_n_hosts4(program_args.n_hosts);
+    let broadcast = ip_addrs::get_nth_address_in_network4(u32::MAX, netmask_size, address);
+    *address == broadcast
+        && program_args.networks.iter().any(|network| match network {
+            IpAddr::V4(network) => {
+                ip_addrs::get_nth_address_in_network4(u32::MAX, netmask_size, network) == broadcast
+            }
+            IpAddr::V6(_) => false,
+        })
+}
+
+#[cfg(test)]
+#[test]
+fn test_check_ipv6() {
+    use super::ipv6_extensions::parse_extension_headers;
+    use pnet_packet::icmpv6::{Icmpv6Code, Icmpv6Type, MutableIcmpv6Packet};
+    use pnet_packet::ipv6::MutableIpv6Packet;
+    use pnet_packet::MutablePacket;
+    use std::net::Ipv6Addr;
+
+    let build = |source: Ipv6Addr, destination: Ipv6Addr, icmpv6_type: u8| {
+        let mut buffer = vec![0; 48];
+        let mut packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+        packet.set_version(6);
+        packet.set_payload_length(8);
+        packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        packet.set_hop_limit(64);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        let mut icmp_packet = MutableIcmpv6Packet::new(packet.payload_mut()).unwrap();
+        icmp_packet.set_icmpv6_type(Icmpv6Type(icmpv6_type));
+        icmp_packet.set_icmpv6_code(Icmpv6Code(0));
+        buffer
+    };
+    let client = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    let virtual_host = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 5);
+
+    let check = |buffer: &[u8]| {
+        let packet = Ipv6Packet::new(buffer).unwrap();
+        let extension_headers = parse_extension_headers(&packet);
+        check_ipv6(&packet, &extension_headers, false)
+    };
+
+    let echo_request = build(client, virtual_host, 128);
+    assert_eq!(check(&echo_request), Ok(()));
+
+    let time_exceeded = build(client, virtual_host, 3);
+    assert_eq!(check(&time_exceeded), Err(SuppressionReason::IcmpError));
+
+    let from_loopback = build(Ipv6Addr::LOCALHOST, virtual_host, 128);
+    assert_eq!(check(&from_loopback), Err(SuppressionReason::InvalidSource));
+
+    let to_multicast = build(client, "ff02::1".parse().unwrap(), 128);
+    assert_eq!(
+        check(&to_multicast),
+        Err(SuppressionReason::MulticastDestination)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_check_ipv4() {
+    use pnet_packet::icmp::{IcmpCode, IcmpType, MutableIcmpPacket};
+    use pnet_packet::ipv4::MutableIpv4Packet;
+    use pnet_packet::MutablePacket;
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--nhosts",
+        "4",
+    ]);
+    let build = |source: Ipv4Addr, destination: Ipv4Addr, icmp_type: u8, fragment_offset: u16| {
+        let mut buffer = vec![0; 28];
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(28);
+        packet.set_fragment_offset(fragment_offset);
+        packet.set_ttl(64);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        let mut icmp_packet = MutableIcmpPacket::new(packet.payload_mut()).unwrap();
+        icmp_packet.set_icmp_type(IcmpType(icmp_type));
+        icmp_packet.set_icmp_code(IcmpCode(0));
+        buffer
+    };
+    let client = Ipv4Addr::new(198, 51, 100, 1);
+    let virtual_host = Ipv4Addr::new(10, 0, 0, 4);
+
+    let check = |buffer: &[u8]| check_ipv4(&args, &Ipv4Packet::new(buffer).unwrap());
+
+    assert_eq!(check(&build(client, virtual_host, 8, 0)), Ok(()));
+    // real addresses that end like a broadcast address are fine
+    assert_eq!(
+        check(&build(client, Ipv4Addr::new(203, 0, 113, 7), 8, 0)),
+        Ok(())
+    );
+
+    let time_exceeded = build(client, virtual_host, 11, 0);
+    assert_eq!(check(&time_exceeded), Err(SuppressionReason::IcmpError));
+
+    for broadcast in [Ipv4Addr::BROADCAST, Ipv4Addr::new(10, 0, 0, 7)] {
+        assert_eq!(
+            check(&build(client, broadcast, 8, 0)),
+            Err(SuppressionReason::BroadcastDestination)
+        );
+    }
+
+    let to_multicast = build(client, Ipv4Addr::new(224, 0, 0, 1), 8, 0);
+    assert_eq!(
+        check(&to_multicast),
+        Err(SuppressionReason::MulticastDestination)
+    );
+
+    let non_initial_fragment = build(client, virtual_host, 8, 1);
+    assert_eq!(
+        check(&non_initial_fragment),
+        Err(SuppressionReason::NonInitialFragment)
+    );
+
+    for source in [Ipv4Addr::UNSPECIFIED, Ipv4Addr::LOCALHOST] {
+        assert_eq!(
+            check(&build(source, virtual_host, 8, 0)),
+            Err(SuppressionReason::InvalidSource)
+        );
+    }
+}
@@ -0,0 +1,623 @@
+//! Ethernet framing for TAP devices
+//!
+//! On a TAP device the virtual hosts need to look like real hosts on an Ethernet segment.
+//! ARP requests and IPv6 Neighbor Solicitations for their addresses are answered with a MAC
+//! address that is derived from the host's address so that it stays the same across restarts.
+//! IP packets are unwrapped and handed to the IP handlers whose responses are then framed again.
+//! The MAC addresses of other hosts are only learned from their ARP and NDP messages.
+
+use super::{Responses, State};
+use crate::argparse::Arguments;
+use crate::ip_addrs;
+use log::{debug, trace};
+use pnet_packet::icmpv6::ndp::{NeighborAdvertFlags, NeighborSolicitPacket};
+use pnet_packet::icmpv6::{checksum, Icmpv6Packet, Icmpv6Types};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+/// Length of an Ethernet header without VLAN tags
+pub const HEADER_LEN: usize = 14;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const ARP_LEN: usize = 28;
+const ARP_HARDWARE_ETHERNET: u16 = 1;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// Length of a Neighbor Advertisement with a target link-layer address option
+const NEIGHBOR_ADVERT_LEN: usize = 32;
+/// Length of a Neighbor Solicitation or Advertisement without options
+const NDP_MESSAGE_LEN: usize = 24;
+/// NDP option that carries the link-layer address of the sender (RFC 4861 section 4.6.1)
+const NDP_OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+/// NDP option that carries the link-layer address of the target (RFC 4861 section 4.6.1)
+const NDP_OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+
+/// How many neighbors are remembered before all of them are forgotten again
+const MAX_NEIGHBORS: usize = 4096;
+
+pub type MacAddr = [u8; 6];
+
+/// The MAC addresses that neighbors announced in ARP and NDP messages, by their IP address
+#[derive(Debug, Default)]
+pub struct Neighbors(Mutex<HashMap<IpAddr, MacAddr>>);
+
+impl Neighbors {
+    fn learn(&self, address: IpAddr, mac: MacAddr) {
+        if address.is_unspecified() || address.is_multicast() || mac[0] & 1 != 0 {
+            return;
+        }
+        let mut neighbors = self.0.lock().expect("Neighbors are poisoned");
+        if neighbors.len() >= MAX_NEIGHBORS && !neighbors.contains_key(&address) {
+            neighbors.clear();
+        }
+        neighbors.insert(address, mac);
+    }
+
+    fn get(&self, address: &IpAddr) -> Option<MacAddr> {
+        self.0
+            .lock()
+            .expect("Neighbors are poisoned")
+            .get(address)
+            .copied()
+    }
+}
+
+/// A locally administered unicast MAC address that is derived from `address` so that the
+/// virtual host keeps it across restarts
+pub fn host_mac(address: IpAddr) -> MacAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            [0x02, 0x00, a, b, c, d]
+        }
+        IpAddr::V6(address) => {
+            // FNV-1a so that hosts which only differ in their network still get different ones
+            let hash = address.octets().iter().fold(0x811c_9dc5u32, |hash, &byte| {
+                (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+            });
+            let [a, b, c, d] = hash.to_be_bytes();
+            [0x02, 0x06, a, b, c, d]
+        }
+    }
+}
+
+/// Handle one Ethernet frame and add the frames that should be written back to `responses`
+pub fn handle_frame(
+    program_args: &Arguments,
+    state: &State,
+    neighbors: &Neighbors,
+    frame: &[u8],
+    responses: &mut Responses,
+) {
+    if frame.len() < HEADER_LEN {
+        debug!("Dropping truncated Ethernet frame [len={}]", frame.len());
+        return;
+    }
+    let destination_mac: MacAddr = frame[..6].try_into().unwrap();
+    let source_mac: MacAddr = frame[6..12].try_into().unwrap();
+    let payload = &frame[HEADER_LEN..];
+
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => handle_arp(
+            program_args,
+            state,
+            neighbors,
+            source_mac,
+            payload,
+            responses,
+        ),
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => {
+            let packet = match ip_packet(payload) {
+                None => {
+                    debug!(
+                        "Dropping truncated IP packet in Ethernet frame [len={}]",
+                        payload.len()
+                    );
+                    return;
+                }
+                Some(packet) => packet,
+            };
+            let (source, destination) = addresses(packet);
+            // the segment may be shared with real hosts whose packets are none of our business
+            if destination_mac[0] & 1 == 0 && destination_mac != host_mac(destination) {
+                trace!(
+                    "Ignoring frame for another host [destination={}]",
+                    destination
+                );
+                return;
+            }
+            learn_from_ndp(neighbors, packet);
+
+            if let Some(target) = neighbor_solicitation_target(packet) {
+                if is_virtual_host(program_args, state, target) {
+                    responses.push_with(usize::MAX, |out| {
+                        build_neighbor_advert(out, packet, source_mac, target)
+                    });
+                }
+                return;
+            }
+
+            let first = responses.ends.len();
+            responses.push_with(state.mtu, |out| {
+                super::handle_packet(program_args, state, packet, out)
+            });
+            frame_responses(neighbors, responses, first, Some((source, source_mac)));
+        }
+        ethertype => trace!("Ignoring Ethernet frame [ethertype={:#06x}]", ethertype),
+    }
+}
+
+/// Put an Ethernet header in front of the IP packets in `responses` from the `first`th on.
+///
+/// Responses to the sender of the frame that is being handled, given as its address and the
+/// frame's source MAC address, are sent back to that MAC address, which for senders outside of
+/// the segment is the one of their router.
+/// All others are addressed to the neighbor that announced their destination and dropped if
+/// there is none.
+pub fn frame_responses(
+    neighbors: &Neighbors,
+    responses: &mut Responses,
+    first: usize,
+    sender: Option<(IpAddr, MacAddr)>,
+) {
+    responses.prepend_headers(first, |packet, out| {
+        let (source, destination) = addresses(packet);
+        let destination_mac = match sender
+            .filter(|(sender, _)| *sender == destination)
+            .map(|(_, mac)| mac)
+            .or_else(|| neighbors.get(&destination))
+        {
+            None => {
+                debug!(
+                    "Dropping response to unknown neighbor [destination={}]",
+                    destination
+                );
+                return None;
+            }
+            Some(mac) => mac,
+        };
+        let ethertype = match destination {
+            IpAddr::V4(_) => ETHERTYPE_IPV4,
+            IpAddr::V6(_) => ETHERTYPE_IPV6,
+        };
+        push_header(out, destination_mac, host_mac(source), ethertype);
+        Some(())
+    });
+}
+
+/// The IP packet that `frame` carries if it carries one
+pub fn ip_packet_of_frame(frame: &[u8]) -> Option<&[u8]> {
+    match u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]) {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => ip_packet(&frame[HEADER_LEN..]),
+        _ => None,
+    }
+}
+
+/// The IP packet in the payload of an Ethernet frame without the padding up to the minimum
+/// frame size
+fn ip_packet(payload: &[u8]) -> Option<&[u8]> {
+    let len = match payload.first()? >> 4 {
+        4 if payload.len() >= 20 => u16::from_be_bytes([payload[2], payload[3]]) as usize,
+        6 if payload.len() >= 40 => 40 + u16::from_be_bytes([payload[4], payload[5]]) as usize,
+        _ => return None,
+    };
+    // an IPv4 packet can not be shorter than its header
+    payload.get(..len).filter(|packet| packet.len() >= 20)
+}
+
+/// Source and destination address of an IP packet whose header is known to be complete
+fn addresses(packet: &[u8]) -> (IpAddr, IpAddr) {
+    if packet[0] >> 4 == 4 {
+        let source: [u8; 4] = packet[12..16].try_into().unwrap();
+        let destination: [u8; 4] = packet[16..20].try_into().unwrap();
+        (
+            Ipv4Addr::from(source).into(),
+            Ipv4Addr::from(destination).into(),
+        )
+    } else {
+        let source: [u8; 16] = packet[8..24].try_into().unwrap();
+        let destination: [u8; 16] = packet[24..40].try_into().unwrap();
+        (
+            Ipv6Addr::from(source).into(),
+            Ipv6Addr::from(destination).into(),
+        )
+    }
+}
+
+/// Whether the neighbors of the segment should be told the MAC address of `address`.
+///
+/// That is every host of the device's networks except for the first one, which is the address
+/// of the device itself and thereby the kernel's to answer for.
+fn is_virtual_host(program_args: &Arguments, state: &State, address: IpAddr) -> bool {
+    let first_host = match address {
+        IpAddr::V4(address) => IpAddr::V4(ip_addrs::get_nth_address_in_network4(
+            1,
+            ip_addrs::calc_netmask_size_with_n_hosts4(program_args.n_hosts),
+            &address,
+        )),
+        IpAddr::V6(address) => IpAddr::V6(ip_addrs::get_nth_address_in_network6(
+            1,
+            ip_addrs::calc_netmask_size_with_n_hosts6(program_args.n_hosts),
+            &address,
+        )),
+    };
+    address != first_host
+        && state
+            .networks
+            .iter()
+            .any(|network| network.contains(&address))
+}
+
+fn handle_arp(
+    program_args: &Arguments,
+    state: &State,
+    neighbors: &Neighbors,
+    source_mac: MacAddr,
+    arp: &[u8],
+    responses: &mut Responses,
+) {
+    // only requests and replies of IPv4 addresses on Ethernet are understood
+    if arp.len() < ARP_LEN
+        || u16::from_be_bytes([arp[0], arp[1]]) != ARP_HARDWARE_ETHERNET
+        || u16::from_be_bytes([arp[2], arp[3]]) != ETHERTYPE_IPV4
+        || arp[4] != 6
+        || arp[5] != 4
+    {
+        trace!("Ignoring ARP packet that is not about an IPv4 address");
+        return;
+    }
+    let operation = u16::from_be_bytes([arp[6], arp[7]]);
+    if operation != ARP_REQUEST && operation != ARP_REPLY {
+        trace!("Ignoring ARP packet [operation={}]", operation);
+        return;
+    }
+    let sender_mac: MacAddr = arp[8..14].try_into().unwrap();
+    let sender: [u8; 4] = arp[14..18].try_into().unwrap();
+    let target: [u8; 4] = arp[24..28].try_into().unwrap();
+    let sender = Ipv4Addr::from(sender);
+    let target = Ipv4Addr::from(target);
+    neighbors.learn(sender.into(), sender_mac);
+
+    // replies only tell us about their sender and announcements of the sender's own address need no answer
+    if operation == ARP_REPLY
+        || sender == target
+        || !is_virtual_host(program_args, state, target.into())
+    {
+        return;
+    }
+    debug!(
+        "Answering ARP request [target={}, sender={}]",
+        target, sender
+    );
+    let target_mac = host_mac(target.into());
+    responses.push_with(usize::MAX, |out| {
+        push_header(out, source_mac, target_mac, ETHERTYPE_ARP);
+        out.extend_from_slice(&ARP_HARDWARE_ETHERNET.to_be_bytes());
+        out.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        out.extend_from_slice(&[6, 4]);
+        out.extend_from_slice(&ARP_REPLY.to_be_bytes());
+        out.extend_from_slice(&target_mac);
+        out.extend_from_slice(&target.octets());
+        out.extend_from_slice(&sender_mac);
+        out.extend_from_slice(&sender.octets());
+        Some(())
+    });
+}
+
+/// Learn the link-layer address that a Neighbor Solicitation or Advertisement in `packet`
+/// announces in its options (RFC 4861 sections 4.3 and 4.4)
+fn learn_from_ndp(neighbors: &Neighbors, packet: &[u8]) {
+    let ipv6 = match Ipv6Packet::new(packet) {
+        Some(ipv6)
+            if ipv6.get_version() == 6
+                && ipv6.get_next_header() == IpNextHeaderProtocols::Icmpv6
+                && ipv6.get_hop_limit() == 255 =>
+        {
+            ipv6
+        }
+        _ => return,
+    };
+    let icmp = &packet[40..];
+    if icmp.len() < NDP_MESSAGE_LEN || icmp[1] != 0 {
+        return;
+    }
+    // solicitations announce their sender and advertisements their target
+    let (address, option_type) = if icmp[0] == Icmpv6Types::NeighborSolicit.0 {
+        (ipv6.get_source(), NDP_OPTION_SOURCE_LINK_LAYER_ADDRESS)
+    } else if icmp[0] == Icmpv6Types::NeighborAdvert.0 {
+        let target: [u8; 16] = icmp[8..24].try_into().unwrap();
+        (Ipv6Addr::from(target), NDP_OPTION_TARGET_LINK_LAYER_ADDRESS)
+    } else {
+        return;
+    };
+
+    let mut options = &icmp[NDP_MESSAGE_LEN..];
+    while options.len() >= 8 {
+        // the length is given in units of 8 bytes
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            return;
+        }
+        if options[0] == option_type && len == 8 {
+            neighbors.learn(address.into(), options[2..8].try_into().unwrap());
+        }
+        options = &options[len..];
+    }
+}
+
+/// The target address if `packet` is a valid Neighbor Solicitation (RFC 4861 section 7.1.1)
+fn neighbor_solicitation_target(packet: &[u8]) -> Option<IpAddr> {
+    let ipv6 = Ipv6Packet::new(packet).filter(|ipv6| ipv6.get_version() == 6)?;
+    if ipv6.get_next_header() != IpNextHeaderProtocols::Icmpv6 || ipv6.get_hop_limit() != 255 {
+        return None;
+    }
+    let icmp = &packet[40..];
+    let solicitation = NeighborSolicitPacket::new(icmp)?;
+    if solicitation.get_icmpv6_type() != Icmpv6Types::NeighborSolicit
+        || solicitation.get_icmpv6_code().0 != 0
+    {
+        return None;
+    }
+    Some(IpAddr::V6(solicitation.get_target_addr()))
+}
+
+/// Write a complete frame with the Neighbor Advertisement that answers `solicitation`
+fn build_neighbor_advert(
+    out: &mut Vec<u8>,
+    solicitation: &[u8],
+    source_mac: MacAddr,
+    target: IpAddr,
+) -> Option<()> {
+    let target = match target {
+        IpAddr::V6(target) => target,
+        IpAddr::V4(_) => return None,
+    };
+    let solicitation = Ipv6Packet::new(solicitation)?;
+    // duplicate address detection is answered to all nodes since its sender has no address yet
+    let (destination, destination_mac, flags) = if solicitation.get_source().is_unspecified() {
+        (
+            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1),
+            [0x33, 0x33, 0, 0, 0, 1],
+            NeighborAdvertFlags::Override,
+        )
+    } else {
+        (
+            solicitation.get_source(),
+            source_mac,
+            NeighborAdvertFlags::Solicited | NeighborAdvertFlags::Override,
+        )
+    };
+    debug!(
+        "Answering neighbor solicitation [target={}, sender={}]",
+        target,
+        solicitation.get_source()
+    );
+
+    let target_mac = host_mac(target.into());
+    push_header(out, destination_mac, target_mac, ETHERTYPE_IPV6);
+    let start = out.len();
+    out.resize(start + 40, 0);
+    let mut ipv6 = MutableIpv6Packet::new(&mut out[start..])?;
+    ipv6.set_version(6);
+    ipv6.set_payload_length(NEIGHBOR_ADVERT_LEN as u16);
+    ipv6.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ipv6.set_hop_limit(255);
+    ipv6.set_source(target);
+    ipv6.set_destination(destination);
+
+    let icmp_start = out.len();
+    out.extend_from_slice(&[Icmpv6Types::NeighborAdvert.0, 0, 0, 0, flags, 0, 0, 0]);
+    out.extend_from_slice(&target.octets());
+    out.extend_from_slice(&[NDP_OPTION_TARGET_LINK_LAYER_ADDRESS, 1]);
+    out.extend_from_slice(&target_mac);
+    let checksum = checksum(
+        &Icmpv6Packet::new(&out[icmp_start..])?,
+        &target,
+        &destination,
+    );
+    out[icmp_start + 2..icmp_start + 4].copy_from_slice(&checksum.to_be_bytes());
+    Some(())
+}
+
+fn push_header(out: &mut Vec<u8>, destination: MacAddr, source: MacAddr, ethertype: u16) {
+    out.extend_from_slice(&destination);
+    out.extend_from_slice(&source);
+    out.extend_from_slice(&ethertype.to_be_bytes());
+}
+
+#[cfg(test)]
+#[test]
+fn test_handle_arp_request() {
+    use crate::ip_addrs::Prefix;
+    use std::str::FromStr;
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--nhosts",
+        "10",
+        "--tap",
+    ]);
+    let state =
+        State::new(&args, 1500).with_networks(vec![Prefix::from_str("10.0.0.0/28").unwrap()]);
+    let neighbors = Neighbors::default();
+    let sender_mac = [0x52, 0x54, 0, 0, 0, 1];
+    let request = |target: [u8; 4]| {
+        let mut frame = Vec::new();
+        push_header(&mut frame, [0xff; 6], sender_mac, ETHERTYPE_ARP);
+        frame.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+        frame.extend_from_slice(&sender_mac);
+        frame.extend_from_slice(&[10, 0, 0, 1]);
+        frame.extend_from_slice(&[0; 6]);
+        frame.extend_from_slice(&target);
+        frame
+    };
+
+    let mut responses = Responses::new();
+    handle_frame(
+        &args,
+        &state,
+        &neighbors,
+        &request([10, 0, 0, 5]),
+        &mut responses,
+    );
+    // the device's own address and addresses outside of the network are not answered
+    handle_frame(
+        &args,
+        &state,
+        &neighbors,
+        &request([10, 0, 0, 1]),
+        &mut responses,
+    );
+    handle_frame(
+        &args,
+        &state,
+        &neighbors,
+        &request([10, 0, 1, 5]),
+        &mut responses,
+    );
+    let replies: Vec<_> = responses.iter().collect();
+    assert_eq!(replies.len(), 1);
+    let reply = replies[0];
+    assert_eq!(reply[..6], sender_mac);
+    assert_eq!(reply[6..12], [0x02, 0x00, 10, 0, 0, 5]);
+    assert_eq!(
+        reply[HEADER_LEN + 6..HEADER_LEN + 8],
+        ARP_REPLY.to_be_bytes()
+    );
+    assert_eq!(reply[HEADER_LEN + 14..HEADER_LEN + 18], [10, 0, 0, 5]);
+    assert_eq!(
+        neighbors.get(&IpAddr::from([10, 0, 0, 1])),
+        Some(sender_mac)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_handle_neighbor_solicitation() {
+    use crate::ip_addrs::Prefix;
+    use std::str::FromStr;
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "2001:db8::",
+        "--nhosts",
+        "10",
+        "--tap",
+    ]);
+    let state =
+        State::new(&args, 1500).with_networks(vec![Prefix::from_str("2001:db8::/64").unwrap()]);
+    let neighbors = Neighbors::default();
+    let sender_mac = [0x52, 0x54, 0, 0, 0, 1];
+    let sender = Ipv6Addr::from_str("2001:db8::1").unwrap();
+    let target = Ipv6Addr::from_str("2001:db8::5").unwrap();
+    let solicitation = |source: Ipv6Addr| {
+        let mut frame = Vec::new();
+        push_header(
+            &mut frame,
+            [0x33, 0x33, 0xff, 0, 0, 5],
+            sender_mac,
+            ETHERTYPE_IPV6,
+        );
+        let start = frame.len();
+        frame.resize(start + 40, 0);
+        let mut ipv6 = MutableIpv6Packet::new(&mut frame[start..]).unwrap();
+        ipv6.set_version(6);
+        ipv6.set_payload_length(32);
+        ipv6.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        ipv6.set_hop_limit(255);
+        ipv6.set_source(source);
+        ipv6.set_destination(Ipv6Addr::from_str("ff02::1:ff00:5").unwrap());
+        frame.extend_from_slice(&[Icmpv6Types::NeighborSolicit.0, 0, 0, 0, 0, 0, 0, 0]);
+        frame.extend_from_slice(&target.octets());
+        frame.extend_from_slice(&[NDP_OPTION_SOURCE_LINK_LAYER_ADDRESS, 1]);
+        frame.extend_from_slice(&sender_mac);
+        frame
+    };
+
+    let mut responses = Responses::new();
+    handle_frame(
+        &args,
+        &state,
+        &neighbors,
+        &solicitation(sender),
+        &mut responses,
+    );
+    // duplicate address detection is answered to all nodes
+    handle_frame(
+        &args,
+        &state,
+        &neighbors,
+        &solicitation(Ipv6Addr::UNSPECIFIED),
+        &mut responses,
+    );
+    let adverts: Vec<_> = responses.iter().collect();
+    assert_eq!(adverts.len(), 2);
+    let expected = [
+        (
+            sender_mac,
+            sender,
+            NeighborAdvertFlags::Solicited | NeighborAdvertFlags::Override,
+        ),
+        (
+            [0x33, 0x33, 0, 0, 0, 1],
+            Ipv6Addr::from_str("ff02::1").unwrap(),
+            NeighborAdvertFlags::Override,
+        ),
+    ];
+    for (advert, (destination_mac, destination, flags)) in adverts.into_iter().zip(expected) {
+        assert_eq!(advert[..6], destination_mac);
+        assert_eq!(advert[6..12], host_mac(target.into()));
+        let ipv6 = Ipv6Packet::new(&advert[HEADER_LEN..]).unwrap();
+        assert_eq!(ipv6.get_source(), target);
+        assert_eq!(ipv6.get_destination(), destination);
+        assert_eq!(ipv6.get_hop_limit(), 255);
+        let icmp = &advert[HEADER_LEN + 40..];
+        assert_eq!(icmp.len(), NEIGHBOR_ADVERT_LEN);
+        let icmp_packet = Icmpv6Packet::new(icmp).unwrap();
+        assert_eq!(icmp_packet.get_icmpv6_type(), Icmpv6Types::NeighborAdvert);
+        assert_eq!(
+            icmp_packet.get_checksum(),
+            checksum(&icmp_packet, &target, &destination)
+        );
+        assert_eq!(icmp[4], flags);
+        assert_eq!(icmp[8..24], target.octets());
+        assert_eq!(icmp[24..26], [NDP_OPTION_TARGET_LINK_LAYER_ADDRESS, 1]);
+        assert_eq!(icmp[26..32], host_mac(target.into()));
+    }
+    assert_eq!(neighbors.get(&sender.into()), Some(sender_mac));
+
+    // other packets are answered to the MAC address they came from without it being learned
+    let router_mac = [0x52, 0x54, 0, 0, 0, 2];
+    let probe_source = Ipv6Addr::from_str("2001:db8:1::1").unwrap();
+    let mut probe = Vec::new();
+    push_header(
+        &mut probe,
+        host_mac(target.into()),
+        router_mac,
+        ETHERTYPE_IPV6,
+    );
+    let start = probe.len();
+    probe.resize(start + 40 + 8, 0);
+    let mut ipv6 = MutableIpv6Packet::new(&mut probe[start..]).unwrap();
+    ipv6.set_version(6);
+    ipv6.set_payload_length(8);
+    ipv6.set_next_header(IpNextHeaderProtocols::Udp);
+    ipv6.set_hop_limit(64);
+    ipv6.set_source(probe_source);
+    ipv6.set_destination(target);
+    responses.clear();
+    handle_frame(&args, &state, &neighbors, &probe, &mut responses);
+    let response = responses.iter().next().unwrap();
+    assert_eq!(response[..6], router_mac);
+    assert_eq!(neighbors.get(&probe_source.into()), None);
+}
@@ -0,0 +1,834 @@
+//! Network address translation between the clients of a virtual address and a real backend
+//!
+//! Packets that get through all virtual hops to a virtual address with a backend are sent on
+//! to the backend from the virtual address. Their source port, or identifier for ICMP echo
+//! messages, is only changed if another client of the same virtual address already uses it for
+//! the same port of the backend.
+//! The backend's replies, including ICMP errors about the forwarded packets, are translated
+//! back so that the client only ever sees the virtual address.
+
+use super::{ipv6_extensions, State};
+use crate::argparse::Arguments;
+use crate::stats::STATS;
+use log::debug;
+use pnet_packet::icmp::{self, IcmpTypes, MutableIcmpPacket};
+use pnet_packet::icmpv6::{self, Icmpv6Types, MutableIcmpv6Packet};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::tcp::{self, MutableTcpPacket};
+use pnet_packet::udp::{self, MutableUdpPacket};
+use pnet_packet::Packet;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// TTL of forwarded packets so that they reach the backend however far behind the virtual
+/// address it is
+const FORWARDED_TTL: u8 = 64;
+
+/// How many connections are translated at once before the least recently used one is forgotten
+const MAX_TRANSLATIONS: usize = 4096;
+
+/// Length of an ICMP error message's header in front of the quoted packet
+const ICMP_ERROR_HEADER_LEN: usize = 8;
+
+/// One end of a translated connection
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Endpoint {
+    protocol: IpNextHeaderProtocol,
+    address: IpAddr,
+    /// The port, or the identifier of ICMP echo messages
+    port: u16,
+}
+
+/// A translated connection as the backend sees it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Connection {
+    /// The end at the virtual address, whose port may differ from the client's
+    virtual_end: Endpoint,
+    /// The port of the backend, or 0 for ICMP echo messages
+    backend_port: u16,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Translation {
+    client: Endpoint,
+    /// Key of the connection in `Table::by_last_use`
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    /// The port that the backend sees for each client's connection to a virtual address and a
+    /// port of its backend
+    ports: HashMap<(Endpoint, IpAddr, u16), u16>,
+    /// The client behind each connection that the backend sees
+    clients: HashMap<Connection, Translation>,
+    /// All connections in the order in which they were last used
+    by_last_use: BTreeMap<u64, Connection>,
+    /// Number of times that connections were used so far
+    uses: u64,
+}
+
+impl Table {
+    fn insert(&mut self, connection: Connection, client: Endpoint) {
+        self.uses += 1;
+        let virtual_end = connection.virtual_end;
+        self.ports.insert(
+            (client, virtual_end.address, connection.backend_port),
+            virtual_end.port,
+        );
+        self.clients.insert(
+            connection,
+            Translation {
+                client,
+                last_used: self.uses,
+            },
+        );
+        self.by_last_use.insert(self.uses, connection);
+    }
+
+    /// The client of `connection`, which is marked as being used just now
+    fn use_connection(&mut self, connection: Connection) -> Option<Endpoint> {
+        let translation = self.clients.get_mut(&connection)?;
+        self.by_last_use.remove(&translation.last_used);
+        self.uses += 1;
+        translation.last_used = self.uses;
+        self.by_last_use.insert(self.uses, connection);
+        Some(translation.client)
+    }
+
+    fn forget_least_recently_used(&mut self) {
+        let (_, connection) = match self.by_last_use.pop_first() {
+            Some(oldest) => oldest,
+            None => return,
+        };
+        if let Some(translation) = self.clients.remove(&connection) {
+            self.ports.remove(&(
+                translation.client,
+                connection.virtual_end.address,
+                connection.backend_port,
+            ));
+        }
+    }
+}
+
+/// The connections that are currently translated between clients and backends
+#[derive(Debug, Default)]
+pub struct Translations(Mutex<Table>);
+
+impl Translations {
+    /// The port under which the backend sees `client`'s connection to `virtual_address` and
+    /// `backend_port`
+    fn port_for(&self, client: Endpoint, virtual_address: IpAddr, backend_port: u16) -> u16 {
+        let mut table = self.0.lock().expect("Translations are poisoned");
+        if let Some(&port) = table.ports.get(&(client, virtual_address, backend_port)) {
+            table.use_connection(Connection {
+                virtual_end: Endpoint {
+                    address: virtual_address,
+                    port,
+                    ..client
+                },
+                backend_port,
+            });
+            return port;
+        }
+
+        if table.clients.len() >= MAX_TRANSLATIONS {
+            table.forget_least_recently_used();
+        }
+        let mut connection = Connection {
+            virtual_end: Endpoint {
+                address: virtual_address,
+                ..client
+            },
+            backend_port,
+        };
+        while table.clients.contains_key(&connection) {
+            connection.virtual_end.port = connection.virtual_end.port.wrapping_add(1);
+        }
+        table.insert(connection, client);
+        connection.virtual_end.port
+    }
+
+    /// The client whose connection the backend sees as `connection`
+    fn client_for(&self, connection: Connection) -> Option<Endpoint> {
+        let mut table = self.0.lock().expect("Translations are poisoned");
+        table.use_connection(connection)
+    }
+}
+
+/// The backend that packets to `destination` are forwarded to, if any
+pub fn backend(program_args: &Arguments, destination: IpAddr) -> Option<IpAddr> {
+    program_args
+        .forwards
+        .iter()
+        .find(|forward| forward.virtual_address == destination)
+        .map(|forward| forward.backend)
+}
+
+/// Whether a packet from `source` to `destination` was sent by a backend to its virtual address
+///
+/// Such packets are only translated if they belong to a connection that was forwarded to the
+/// same port of the backend, so that others can not be sent to the clients with a forged source.
+pub fn is_from_backend(program_args: &Arguments, source: IpAddr, destination: IpAddr) -> bool {
+    program_args
+        .forwards
+        .iter()
+        .any(|forward| forward.backend == source && forward.virtual_address == destination)
+}
+
+/// Translate `packet`, which got through all virtual hops to its destination, into a packet to
+/// `backend` and write it to the end of `out`.
+///
+/// Returns `None` if no packet was written.
+pub fn forward(state: &State, packet: &[u8], backend: IpAddr, out: &mut Vec<u8>) -> Option<()> {
+    let layout = layout_of(packet)?;
+    let segment = &packet[layout.segment..layout.len];
+    let port_offset = match port_offset(layout.protocol, segment, End::Source) {
+        None => return drop_unsupported(layout.protocol),
+        Some(offset) => layout.segment + offset,
+    };
+    let client = Endpoint {
+        protocol: layout.protocol,
+        address: layout.source,
+        port: read_port(packet, port_offset),
+    };
+    let backend_port = backend_port(layout.protocol, segment, End::Destination);
+    let port = state
+        .translations
+        .port_for(client, layout.destination, backend_port);
+    debug!(
+        "Forwarding packet to backend [client={}, virtual_address={}, backend={}, port={}]",
+        layout.source, layout.destination, backend, port
+    );
+
+    let start = out.len();
+    out.extend_from_slice(&packet[..layout.len]);
+    let translated = &mut out[start..];
+    write_port(translated, port_offset, port);
+    rewrite_header(translated, layout.destination, backend, Some(FORWARDED_TTL));
+    update_checksum(
+        layout.protocol,
+        &mut translated[layout.segment..],
+        layout.destination,
+        backend,
+    );
+    STATS.forwarding.to_backend.increment();
+    Some(())
+}
+
+/// Translate `packet` from a backend to its virtual address back into a packet to the client
+/// whose connection it belongs to and write it to the end of `out`.
+///
+/// Returns `None` if no packet was written.
+pub fn translate_reply(state: &State, packet: &[u8], out: &mut Vec<u8>) -> Option<()> {
+    let layout = layout_of(packet)?;
+    let segment = &packet[layout.segment..layout.len];
+    let virtual_address = layout.destination;
+
+    // replies are addressed to the port under which the backend knows the connection
+    if let Some(offset) = port_offset(layout.protocol, segment, End::Destination) {
+        let connection = Connection {
+            virtual_end: Endpoint {
+                protocol: layout.protocol,
+                address: virtual_address,
+                port: read_port(segment, offset),
+            },
+            backend_port: backend_port(layout.protocol, segment, End::Source),
+        };
+        let client = client_for(state, connection)?;
+
+        let start = out.len();
+        out.extend_from_slice(&packet[..layout.len]);
+        let translated = &mut out[start..];
+        write_port(translated, layout.segment + offset, client.port);
+        rewrite_header(translated, virtual_address, client.address, None);
+        update_checksum(
+            layout.protocol,
+            &mut translated[layout.segment..],
+            virtual_address,
+            client.address,
+        );
+    }
+    // errors quote the forwarded packet which has to be translated as well
+    else if is_icmp_error(layout.protocol, segment) {
+        let quote = &segment[ICMP_ERROR_HEADER_LEN..];
+        let quoted = layout_of(quote)?;
+        let offset = match port_offset(quoted.protocol, &quote[quoted.segment..], End::Source) {
+            None => return drop_unsupported(quoted.protocol),
+            Some(offset) => quoted.segment + offset,
+        };
+        let quoted_segment = &quote[quoted.segment..];
+        let connection = Connection {
+            virtual_end: Endpoint {
+                protocol: quoted.protocol,
+                address: quoted.source,
+                port: read_port(quote, offset),
+            },
+            backend_port: backend_port(quoted.protocol, quoted_segment, End::Destination),
+        };
+        let client = client_for(state, connection)?;
+
+        let start = out.len();
+        out.extend_from_slice(&packet[..layout.len]);
+        let translated = &mut out[start..];
+        let quote = &mut translated[layout.segment + ICMP_ERROR_HEADER_LEN..];
+        write_port(quote, offset, client.port);
+        rewrite_header(quote, client.address, virtual_address, None);
+        rewrite_header(translated, virtual_address, client.address, None);
+        update_checksum(
+            layout.protocol,
+            &mut translated[layout.segment..],
+            virtual_address,
+            client.address,
+        );
+    } else {
+        return drop_unsupported(layout.protocol);
+    }
+
+    debug!(
+        "Translating packet from backend [backend={}, virtual_address={}]",
+        layout.source, virtual_address
+    );
+    STATS.forwarding.from_backend.increment();
+    Some(())
+}
+
+fn client_for(state: &State, connection: Connection) -> Option<Endpoint> {
+    let client = state.translations.client_for(connection);
+    if client.is_none() {
+        debug!(
+            "Dropping packet from backend that belongs to no translated connection [virtual_address={}, port={}, backend_port={}]",
+            connection.virtual_end.address, connection.virtual_end.port, connection.backend_port
+        );
+        STATS.forwarding.unknown_connection.increment();
+    }
+    client
+}
+
+fn drop_unsupported(protocol: IpNextHeaderProtocol) -> Option<()> {
+    debug!(
+        "Dropping packet that can not be translated [proto={}]",
+        protocol
+    );
+    STATS.forwarding.unsupported.increment();
+    None
+}
+
+/// Where the parts of an IP packet are that get translated
+#[derive(Debug, Copy, Clone)]
+struct Layout {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    /// Offset of the upper-layer header
+    segment: usize,
+    /// Length of the packet as announced by its IP header, or less if it was cut short like
+    /// the packets that ICMP errors quote
+    len: usize,
+}
+
+/// The layout of `packet` if it starts with an upper-layer header that has room for ports
+fn layout_of(packet: &[u8]) -> Option<Layout> {
+    let layout = match packet.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new(packet)?;
+            if packet.get_fragment_offset() != 0 {
+                return None;
+            }
+            Layout {
+                source: packet.get_source().into(),
+                destination: packet.get_destination().into(),
+                protocol: packet.get_next_level_protocol(),
+                segment: packet.get_header_length() as usize * 4,
+                len: (packet.get_total_length() as usize).min(packet.packet().len()),
+            }
+        }
+        6 => {
+            let packet = Ipv6Packet::new(packet)?;
+            let extension_headers = ipv6_extensions::parse_extension_headers(&packet);
+            if extension_headers.is_non_initial_fragment() || extension_headers.problem.is_some() {
+                return None;
+            }
+            Layout {
+                source: packet.get_source().into(),
+                destination: packet.get_destination().into(),
+                protocol: extension_headers.upper_layer_protocol,
+                segment: Ipv6Packet::minimum_packet_size() + extension_headers.upper_layer_offset,
+                len: (Ipv6Packet::minimum_packet_size() + packet.get_payload_length() as usize)
+                    .min(packet.packet().len()),
+            }
+        }
+        _ => return None,
+    };
+    // ports and ICMP identifiers are all within the first 8 bytes of the upper-layer header
+    if layout.segment < Ipv4Packet::minimum_packet_size() || layout.segment + 8 > layout.len {
+        return None;
+    }
+    Some(layout)
+}
+
+/// The end of a connection
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum End {
+    Source,
+    Destination,
+}
+
+/// Offset of the port of `end` in the upper-layer header `segment` of `protocol`, or `None` if
+/// its connections can not be translated
+///
+/// ICMP echo messages use the same identifier at both ends.
+fn port_offset(protocol: IpNextHeaderProtocol, segment: &[u8], end: End) -> Option<usize> {
+    match protocol {
+        IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp => match end {
+            End::Source => Some(0),
+            End::Destination => Some(2),
+        },
+        IpNextHeaderProtocols::Icmp
+            if segment[0] == IcmpTypes::EchoRequest.0 || segment[0] == IcmpTypes::EchoReply.0 =>
+        {
+            Some(4)
+        }
+        IpNextHeaderProtocols::Icmpv6
+            if segment[0] == Icmpv6Types::EchoRequest.0
+                || segment[0] == Icmpv6Types::EchoReply.0 =>
+        {
+            Some(4)
+        }
+        _ => None,
+    }
+}
+
+/// The port of the backend at `end` of the upper-layer header `segment` of `protocol`, which is
+/// always 0 for ICMP echo messages as they only have the translated identifier
+fn backend_port(protocol: IpNextHeaderProtocol, segment: &[u8], end: End) -> u16 {
+    match (protocol, end) {
+        (IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp, End::Source) => {
+            read_port(segment, 0)
+        }
+        (IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp, End::Destination) => {
+            read_port(segment, 2)
+        }
+        _ => 0,
+    }
+}
+
+/// Whether `segment` is an ICMP error message that quotes the packet it is about
+fn is_icmp_error(protocol: IpNextHeaderProtocol, segment: &[u8]) -> bool {
+    match protocol {
+        IpNextHeaderProtocols::Icmp => {
+            segment[0] == IcmpTypes::DestinationUnreachable.0
+                || segment[0] == IcmpTypes::TimeExceeded.0
+                || segment[0] == IcmpTypes::ParameterProblem.0
+        }
+        // ICMPv6 error messages all have a type value below 128 (RFC 4443 section 2.1)
+        IpNextHeaderProtocols::Icmpv6 => segment[0] < 128,
+        _ => false,
+    }
+}
+
+fn read_port(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn write_port(buffer: &mut [u8], offset: usize, port: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&port.to_be_bytes());
+}
+
+/// Change the addresses in the IP header of `packet`, and its TTL if `ttl` is given
+fn rewrite_header(packet: &mut [u8], source: IpAddr, destination: IpAddr, ttl: Option<u8>) {
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut packet = MutableIpv4Packet::new(packet).expect("Could not rewrite IPv4 header");
+            packet.set_source(source);
+            packet.set_destination(destination);
+            if let Some(ttl) = ttl {
+                packet.set_ttl(ttl);
+            }
+            packet.set_checksum(ipv4::checksum(&packet.to_immutable()));
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let mut packet = MutableIpv6Packet::new(packet).expect("Could not rewrite IPv6 header");
+            packet.set_source(source);
+            packet.set_destination(destination);
+            if let Some(ttl) = ttl {
+                packet.set_hop_limit(ttl);
+            }
+        }
+        _ => unreachable!("Forwards are never between address families"),
+    }
+}
+
+/// Recalculate the checksum of the upper-layer `segment` of `protocol` which is sent from
+/// `source` to `destination`
+fn update_checksum(
+    protocol: IpNextHeaderProtocol,
+    segment: &mut [u8],
+    source: IpAddr,
+    destination: IpAddr,
+) {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => {
+            if let Some(mut packet) = MutableTcpPacket::new(segment) {
+                let checksum = match (source, destination) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        tcp::ipv4_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                    (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                        tcp::ipv6_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                    _ => unreachable!("Forwards are never between address families"),
+                };
+                packet.set_checksum(checksum);
+            }
+        }
+        IpNextHeaderProtocols::Udp => {
+            if let Some(mut packet) = MutableUdpPacket::new(segment) {
+                let checksum = match (source, destination) {
+                    // a checksum of zero means that the sender did not calculate one
+                    (IpAddr::V4(_), IpAddr::V4(_)) if packet.get_checksum() == 0 => 0,
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        udp::ipv4_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                    (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                        udp::ipv6_checksum(&packet.to_immutable(), &source, &destination)
+                    }
+                    _ => unreachable!("Forwards are never between address families"),
+                };
+                packet.set_checksum(checksum);
+            }
+        }
+        IpNextHeaderProtocols::Icmp => {
+            if let Some(mut packet) = MutableIcmpPacket::new(segment) {
+                packet.set_checksum(icmp::checksum(&packet.to_immutable()));
+            }
+        }
+        IpNextHeaderProtocols::Icmpv6 => {
+            if let (Some(mut packet), IpAddr::V6(source), IpAddr::V6(destination)) =
+                (MutableIcmpv6Packet::new(segment), source, destination)
+            {
+                packet.set_checksum(icmpv6::checksum(
+                    &packet.to_immutable(),
+                    &source,
+                    &destination,
+                ));
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_forward_and_translate_reply() {
+    use pnet_packet::icmp::IcmpPacket;
+    use pnet_packet::udp::UdpPacket;
+    use pnet_packet::MutablePacket;
+    use std::net::Ipv4Addr;
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--nhosts",
+        "10",
+        "--forward",
+        "10.0.0.5=192.0.2.80",
+    ]);
+    let state = State::new(&args, 1500);
+    let client = Ipv4Addr::new(198, 51, 100, 1);
+    let virtual_address = Ipv4Addr::new(10, 0, 0, 5);
+    let backend = Ipv4Addr::new(192, 0, 2, 80);
+    assert_eq!(
+        super::forwarding::backend(&args, virtual_address.into()),
+        Some(backend.into())
+    );
+
+    let udp = |source: Ipv4Addr, destination: Ipv4Addr, ports: [u16; 2]| {
+        let mut packet = vec![0; 28];
+        let mut ip = MutableIpv4Packet::new(&mut packet).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(28);
+        ip.set_ttl(7);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip.set_source(source);
+        ip.set_destination(destination);
+        let mut segment = MutableUdpPacket::new(ip.payload_mut()).unwrap();
+        segment.set_source(ports[0]);
+        segment.set_destination(ports[1]);
+        segment.set_length(8);
+        segment.set_checksum(1);
+        packet
+    };
+
+    // a second client with the same port gets another one at the backend
+    let mut forwarded = Vec::new();
+    forward(
+        &state,
+        &udp(client, virtual_address, [4000, 33434]),
+        backend.into(),
+        &mut forwarded,
+    );
+    forward(
+        &state,
+        &udp(
+            Ipv4Addr::new(198, 51, 100, 2),
+            virtual_address,
+            [4000, 33434],
+        ),
+        backend.into(),
+        &mut forwarded,
+    );
+    let (first, second) = forwarded.split_at(28);
+    for (packet, port) in [(first, 4000), (second, 4001)] {
+        let ip = Ipv4Packet::new(packet).unwrap();
+        assert_eq!(ip.get_source(), virtual_address);
+        assert_eq!(ip.get_destination(), backend);
+        assert_eq!(ip.get_ttl(), FORWARDED_TTL);
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        let segment = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!(segment.get_source(), port);
+        assert_eq!(
+            segment.get_checksum(),
+            udp::ipv4_checksum(&segment, &virtual_address, &backend)
+        );
+    }
+
+    // the backend's port unreachable error reaches the second client as if the virtual address
+    // sent it
+    let mut error = udp(backend, virtual_address, [0, 0]);
+    error.truncate(20);
+    error.extend_from_slice(&[IcmpTypes::DestinationUnreachable.0, 3, 0, 0, 0, 0, 0, 0]);
+    error.extend_from_slice(second);
+    let mut ip = MutableIpv4Packet::new(&mut error).unwrap();
+    ip.set_total_length(56);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    let mut translated = Vec::new();
+    translate_reply(&state, &error, &mut translated).unwrap();
+    let ip = Ipv4Packet::new(&translated).unwrap();
+    assert_eq!(ip.get_source(), virtual_address);
+    assert_eq!(ip.get_destination(), Ipv4Addr::new(198, 51, 100, 2));
+    let icmp_packet = IcmpPacket::new(ip.payload()).unwrap();
+    assert_eq!(icmp_packet.get_checksum(), icmp::checksum(&icmp_packet));
+    let quote = Ipv4Packet::new(&ip.payload()[ICMP_ERROR_HEADER_LEN..]).unwrap();
+    assert_eq!(quote.get_source(), Ipv4Addr::new(198, 51, 100, 2));
+    assert_eq!(quote.get_destination(), virtual_address);
+    assert_eq!(UdpPacket::new(quote.payload()).unwrap().get_source(), 4000);
+
+    // replies to ports without a translated connection are dropped
+    translated.clear();
+    assert!(translate_reply(
+        &state,
+        &udp(backend, virtual_address, [80, 4002]),
+        &mut translated
+    )
+    .is_none());
+    assert!(translated.is_empty());
+}
+
+/// Build an IPv4 or IPv6 packet from `source` to `destination` that carries `segment`
+#[cfg(test)]
+fn build_test_packet(
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    segment: &[u8],
+) -> Vec<u8> {
+    let mut packet = match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut packet = vec![0; 20];
+            let mut ip = MutableIpv4Packet::new(&mut packet).unwrap();
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length((20 + segment.len()) as u16);
+            ip.set_ttl(7);
+            ip.set_next_level_protocol(protocol);
+            ip.set_source(source);
+            ip.set_destination(destination);
+            ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+            packet
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let mut packet = vec![0; 40];
+            let mut ip = MutableIpv6Packet::new(&mut packet).unwrap();
+            ip.set_version(6);
+            ip.set_payload_length(segment.len() as u16);
+            ip.set_next_header(protocol);
+            ip.set_hop_limit(7);
+            ip.set_source(source);
+            ip.set_destination(destination);
+            packet
+        }
+        _ => unreachable!(),
+    };
+    packet.extend_from_slice(segment);
+    let segment_start = packet.len() - segment.len();
+    update_checksum(protocol, &mut packet[segment_start..], source, destination);
+    packet
+}
+
+#[cfg(test)]
+#[test]
+fn test_forward_and_translate_reply_of_all_protocols() {
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "2001:db8::",
+        "--nhosts",
+        "10",
+        "--forward",
+        "10.0.0.5=192.0.2.80",
+        "--forward",
+        "2001:db8::5=2001:db8:1::80",
+    ]);
+    let state = State::new(&args, 1500);
+    let addresses = |client: &str, virtual_address: &str, backend: &str| {
+        [client, virtual_address, backend].map(|address| address.parse::<IpAddr>().unwrap())
+    };
+
+    // TCP and UDP segments and ICMP echo messages between ports[0] and ports[1]
+    let segment = |protocol: IpNextHeaderProtocol, ports: [u16; 2], reply: bool| {
+        let mut segment = vec![0; 20];
+        match protocol {
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp => {
+                write_port(&mut segment, 0, ports[0]);
+                write_port(&mut segment, 2, ports[1]);
+                if protocol == IpNextHeaderProtocols::Tcp {
+                    segment[12] = 5 << 4;
+                } else {
+                    segment.truncate(8);
+                    segment[5] = 8;
+                    segment[7] = 1;
+                }
+            }
+            _ => {
+                segment[0] = match (protocol, reply) {
+                    (IpNextHeaderProtocols::Icmp, false) => IcmpTypes::EchoRequest.0,
+                    (IpNextHeaderProtocols::Icmp, true) => IcmpTypes::EchoReply.0,
+                    (_, false) => Icmpv6Types::EchoRequest.0,
+                    (_, true) => Icmpv6Types::EchoReply.0,
+                };
+                // the identifier is the same for requests and replies
+                write_port(&mut segment, 4, ports[reply as usize]);
+            }
+        }
+        segment
+    };
+    let has_valid_checksum = |packet: &[u8]| {
+        let layout = layout_of(packet).unwrap();
+        let mut recalculated = packet.to_vec();
+        update_checksum(
+            layout.protocol,
+            &mut recalculated[layout.segment..],
+            layout.source,
+            layout.destination,
+        );
+        recalculated == packet
+    };
+
+    for [client, virtual_address, backend] in [
+        addresses("198.51.100.1", "10.0.0.5", "192.0.2.80"),
+        addresses("2001:db8:2::1", "2001:db8::5", "2001:db8:1::80"),
+    ] {
+        let icmp = match client {
+            IpAddr::V4(_) => IpNextHeaderProtocols::Icmp,
+            IpAddr::V6(_) => IpNextHeaderProtocols::Icmpv6,
+        };
+        for protocol in [IpNextHeaderProtocols::Tcp, IpNextHeaderProtocols::Udp, icmp] {
+            let request = build_test_packet(
+                client,
+                virtual_address,
+                protocol,
+                &segment(protocol, [4000, 80], false),
+            );
+            let mut forwarded = Vec::new();
+            forward(&state, &request, backend, &mut forwarded).unwrap();
+            let layout = layout_of(&forwarded).unwrap();
+            assert_eq!(layout.source, virtual_address);
+            assert_eq!(layout.destination, backend);
+            assert!(has_valid_checksum(&forwarded));
+            let port = read_port(
+                &forwarded,
+                layout.segment + if protocol == icmp { 4 } else { 0 },
+            );
+
+            // the backend's reply reaches the client's port
+            let reply = build_test_packet(
+                backend,
+                virtual_address,
+                protocol,
+                &segment(protocol, [80, port], true),
+            );
+            let mut translated = Vec::new();
+            translate_reply(&state, &reply, &mut translated).unwrap();
+            let layout = layout_of(&translated).unwrap();
+            assert_eq!(layout.source, virtual_address);
+            assert_eq!(layout.destination, client);
+            assert!(has_valid_checksum(&translated));
+            let offset = port_offset(protocol, &translated[layout.segment..], End::Destination);
+            assert_eq!(
+                read_port(&translated, layout.segment + offset.unwrap()),
+                4000
+            );
+
+            // packets from other ports of the backend are not part of the connection
+            if protocol != icmp {
+                let other = build_test_packet(
+                    backend,
+                    virtual_address,
+                    protocol,
+                    &segment(protocol, [81, port], true),
+                );
+                assert!(translate_reply(&state, &other, &mut Vec::new()).is_none());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_forget_least_recently_used() {
+    let translations = Translations::default();
+    let virtual_address = "10.0.0.5".parse().unwrap();
+    let client = |port| Endpoint {
+        protocol: IpNextHeaderProtocols::Udp,
+        address: "198.51.100.1".parse().unwrap(),
+        port,
+    };
+    let connection = |port| Connection {
+        virtual_end: Endpoint {
+            address: virtual_address,
+            ..client(port)
+        },
+        backend_port: 80,
+    };
+    for port in 0..MAX_TRANSLATIONS as u16 {
+        assert_eq!(
+            translations.port_for(client(port), virtual_address, 80),
+            port
+        );
+    }
+
+    // using the oldest connection makes the next one the least recently used
+    assert_eq!(translations.client_for(connection(0)), Some(client(0)));
+    translations.port_for(client(u16::MAX), virtual_address, 80);
+    assert_eq!(translations.client_for(connection(1)), None);
+    assert_eq!(translations.client_for(connection(0)), Some(client(0)));
+    assert_eq!(
+        translations.client_for(connection(u16::MAX)),
+        Some(client(u16::MAX))
+    );
+
+    // a connection that was forgotten gets a new translation
+    assert_eq!(translations.port_for(client(1), virtual_address, 80), 1);
+    assert_eq!(translations.client_for(connection(2)), None);
+    let table = translations.0.lock().unwrap();
+    assert_eq!(table.clients.len(), MAX_TRANSLATIONS);
+    assert_eq!(table.ports.len(), MAX_TRANSLATIONS);
+    assert_eq!(table.by_last_use.len(), MAX_TRANSLATIONS);
+}
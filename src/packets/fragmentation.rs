@@ -0,0 +1,329 @@
+//! Fragmentation of responses that do not fit into the link MTU
+
+use log::{trace, warn};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{checksum, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::ipv6::MutableIpv6Packet;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Bit in an IPv4 option type that marks the option as being copied into all fragments
+const OPTION_COPIED_FLAG: u8 = 0x80;
+
+/// Length of an IPv6 Fragment header
+const FRAGMENT_HEADER_LEN: usize = 8;
+
+/// Identification value of the next fragmented IPv6 response
+static NEXT_IDENTIFICATION6: AtomicU32 = AtomicU32::new(0);
+
+/// Split an IPv4 `packet` into fragments of at most `mtu` bytes each and append them to `out`.
+///
+/// The end of every fragment in `out` is pushed to `ends`.
+/// Packets that already fit are appended unchanged.
+pub fn fragment_ipv4(packet: &[u8], mtu: usize, out: &mut Vec<u8>, ends: &mut Vec<usize>) {
+    if packet.len() <= mtu {
+        return append_unchanged(packet, out, ends);
+    }
+
+    let ip_packet = Ipv4Packet::new(packet).expect("Could not parse IPv4 response packet");
+    let header_len = ip_packet.get_header_length() as usize * 4;
+    let total_len = ip_packet.get_total_length() as usize;
+    if mtu < header_len + 8 {
+        warn!(
+            "MTU is too small to fragment response, sending it as is [mtu={}]",
+            mtu
+        );
+        return append_unchanged(packet, out, ends);
+    }
+
+    let first_header = &packet[..header_len];
+    let data = &packet[header_len..total_len];
+
+    let mut n_fragments = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let start = out.len();
+        if offset == 0 {
+            out.extend_from_slice(first_header);
+        } else {
+            append_header_with_copied_options(first_header, out);
+        }
+        let header_len = out.len() - start;
+        // all fragments except the last one need to carry a multiple of 8 bytes
+        let max_data_len = (mtu - header_len) & !0b111;
+        let end = data.len().min(offset + max_data_len);
+        let more_fragments = end < data.len();
+        out.extend_from_slice(&data[offset..end]);
+
+        let mut fragment_packet = MutableIpv4Packet::new(&mut out[start..])
+            .expect("Could not create IPv4 fragment in its buffer");
+        fragment_packet.set_header_length((header_len / 4) as u8);
+        fragment_packet.set_total_length((header_len + end - offset) as u16);
+        fragment_packet.set_flags(if more_fragments {
+            Ipv4Flags::MoreFragments
+        } else {
+            0
+        });
+        fragment_packet.set_fragment_offset((offset / 8) as u16);
+        fragment_packet.set_checksum(checksum(&fragment_packet.to_immutable()));
+        ends.push(out.len());
+        n_fragments += 1;
+
+        offset = end;
+    }
+
+    trace!(
+        "Fragmented IPv4 response [len={}, n_fragments={}]",
+        packet.len(),
+        n_fragments
+    );
+}
+
+/// Split an IPv6 `packet` into fragments of at most `mtu` bytes each by inserting a Fragment
+/// header after its unfragmentable part and append them to `out`.
+///
+/// The end of every fragment in `out` is pushed to `ends`.
+/// Packets that already fit are appended unchanged.
+pub fn fragment_ipv6(packet: &[u8], mtu: usize, out: &mut Vec<u8>, ends: &mut Vec<usize>) {
+    if packet.len() <= mtu {
+        return append_unchanged(packet, out, ends);
+    }
+
+    let (header_len, next_header_field) = unfragmentable_part6(packet);
+    if mtu < header_len + FRAGMENT_HEADER_LEN + 8 {
+        warn!(
+            "MTU is too small to fragment response, sending it as is [mtu={}]",
+            mtu
+        );
+        return append_unchanged(packet, out, ends);
+    }
+
+    let header = &packet[..header_len];
+    let next_header = header[next_header_field];
+    let data = &packet[header_len..];
+    let identification = NEXT_IDENTIFICATION6.fetch_add(1, Ordering::Relaxed);
+    // all fragments except the last one need to carry a multiple of 8 bytes
+    let max_data_len = (mtu - header_len - FRAGMENT_HEADER_LEN) & !0b111;
+    let fixed_header_len = MutableIpv6Packet::minimum_packet_size();
+
+    let mut n_fragments = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = data.len().min(offset + max_data_len);
+        let more_fragments = end < data.len();
+        let offset_with_flags = (offset as u16) | more_fragments as u16;
+
+        let start = out.len();
+        out.extend_from_slice(header);
+        out[start + next_header_field] = IpNextHeaderProtocols::Ipv6Frag.0;
+        out.extend_from_slice(&[next_header, 0]);
+        out.extend_from_slice(&offset_with_flags.to_be_bytes());
+        out.extend_from_slice(&identification.to_be_bytes());
+        out.extend_from_slice(&data[offset..end]);
+
+        let mut fragment_packet = MutableIpv6Packet::new(&mut out[start..])
+            .expect("Could not create IPv6 fragment in its buffer");
+        fragment_packet.set_payload_length(
+            (header_len - fixed_header_len + FRAGMENT_HEADER_LEN + end - offset) as u16,
+        );
+        ends.push(out.len());
+        n_fragments += 1;
+
+        offset = end;
+    }
+
+    trace!(
+        "Fragmented IPv6 response [len={}, n_fragments={}]",
+        packet.len(),
+        n_fragments
+    );
+}
+
+fn append_unchanged(packet: &[u8], out: &mut Vec<u8>, ends: &mut Vec<usize>) {
+    out.extend_from_slice(packet);
+    ends.push(out.len());
+}
+
+/// Find the part of an IPv6 `packet` that is repeated in every fragment: the fixed header and
+/// the extension headers up to the last Hop-by-Hop Options or Routing header, which the nodes on
+/// the way to the destination need to see (RFC 8200 section 4.5).
+///
+/// Returns its length and the offset of the Next Header field in it that announces the first
+/// fragmentable header.
+fn unfragmentable_part6(packet: &[u8]) -> (usize, usize) {
+    let mut result = (MutableIpv6Packet::minimum_packet_size(), 6);
+    let mut next_header_field = result.1;
+    let mut offset = result.0;
+    loop {
+        let next_header = IpNextHeaderProtocol(packet[next_header_field]);
+        let is_unfragmentable = match next_header {
+            IpNextHeaderProtocols::Hopopt | IpNextHeaderProtocols::Ipv6Route => true,
+            // Destination Options are only unfragmentable if a Routing header follows
+            IpNextHeaderProtocols::Ipv6Opts => false,
+            _ => return result,
+        };
+        let header_len = match packet.get(offset + 1) {
+            Some(&len) if offset + (len as usize + 1) * 8 <= packet.len() => (len as usize + 1) * 8,
+            _ => return result,
+        };
+        next_header_field = offset;
+        offset += header_len;
+        if is_unfragmentable {
+            result = (offset, next_header_field);
+        }
+    }
+}
+
+/// Append the header of non-initial fragments to `out`, which is the header of the first one
+/// with only the options that need to be copied into all fragments (RFC 791)
+fn append_header_with_copied_options(header: &[u8], out: &mut Vec<u8>) {
+    let fixed_len = MutableIpv4Packet::minimum_packet_size();
+    let options = &header[fixed_len..];
+    let start = out.len();
+    out.extend_from_slice(&header[..fixed_len]);
+
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            // end of option list
+            0 => break,
+            // no operation
+            1 => i += 1,
+            option_type => {
+                let option_len = match options.get(i + 1) {
+                    Some(len) if *len >= 2 && i + *len as usize <= options.len() => *len as usize,
+                    _ => break,
+                };
+                if option_type & OPTION_COPIED_FLAG != 0 {
+                    out.extend_from_slice(&options[i..i + option_len]);
+                }
+                i += option_len;
+            }
+        }
+    }
+
+    // pad the options with zeroes which are end of option list markers
+    while !(out.len() - start).is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+/// Fragment `packet` behind another response in the same buffer and return the fragments
+#[cfg(test)]
+fn collect_fragments(
+    fragment: fn(&[u8], usize, &mut Vec<u8>, &mut Vec<usize>),
+    packet: &[u8],
+    mtu: usize,
+) -> Vec<Vec<u8>> {
+    let mut out = vec![0xff; 3];
+    let mut ends = vec![out.len()];
+    fragment(packet, mtu, &mut out, &mut ends);
+    assert_eq!(ends.last(), Some(&out.len()));
+    ends.windows(2)
+        .map(|range| out[range[0]..range[1]].to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_fragment_ipv4() {
+    use pnet_packet::Packet;
+
+    let mut packet = vec![0; 20 + 100];
+    let mut ip_packet = MutableIpv4Packet::new(&mut packet).unwrap();
+    ip_packet.set_version(4);
+    ip_packet.set_header_length(5);
+    ip_packet.set_total_length(120);
+    ip_packet.set_flags(Ipv4Flags::DontFragment);
+    ip_packet.set_ttl(64);
+    for (i, byte) in packet[20..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let fragments = collect_fragments(fragment_ipv4, &packet, 68);
+    assert_eq!(fragments.len(), 3);
+    let fragments: Vec<Ipv4Packet> = fragments
+        .iter()
+        .map(|fragment| Ipv4Packet::new(fragment).unwrap())
+        .collect();
+    assert_eq!(fragments[0].get_total_length(), 68);
+    assert_eq!(fragments[0].get_flags(), Ipv4Flags::MoreFragments);
+    assert_eq!(fragments[1].get_fragment_offset(), 6);
+    assert_eq!(fragments[2].get_flags(), 0);
+    assert_eq!(fragments[2].payload(), &packet[20 + 96..]);
+    for fragment in &fragments {
+        assert_eq!(fragment.get_checksum(), checksum(fragment));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_fragment_ipv6() {
+    use super::ipv6_extensions::parse_extension_headers;
+    use pnet_packet::ipv6::Ipv6Packet;
+
+    let mut packet = vec![0; 40 + 100];
+    let mut ip_packet = MutableIpv6Packet::new(&mut packet).unwrap();
+    ip_packet.set_version(6);
+    ip_packet.set_payload_length(100);
+    ip_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ip_packet.set_hop_limit(64);
+
+    let fragments = collect_fragments(fragment_ipv6, &packet, 96);
+    assert_eq!(fragments.len(), 3);
+    let fragments: Vec<Ipv6Packet> = fragments
+        .iter()
+        .map(|fragment| Ipv6Packet::new(fragment).unwrap())
+        .collect();
+    assert_eq!(fragments[0].get_payload_length(), 8 + 48);
+    assert_eq!(fragments[2].get_payload_length(), 8 + 4);
+
+    let last = parse_extension_headers(&fragments[2]);
+    let fragment = last.fragment.unwrap();
+    assert_eq!(fragment.offset, 12);
+    assert!(!fragment.more_fragments);
+    assert_eq!(last.upper_layer_protocol, IpNextHeaderProtocols::Icmpv6);
+}
+
+#[cfg(test)]
+#[test]
+fn test_fragment_ipv6_with_routing_header() {
+    use super::ipv6_extensions::parse_extension_headers;
+    use pnet_packet::ipv6::Ipv6Packet;
+    use pnet_packet::Packet;
+
+    // Hop-by-Hop Options and a Segment Routing header with one segment before 100 bytes of data
+    let mut packet = vec![0; 40 + 8 + 24 + 100];
+    let mut ip_packet = MutableIpv6Packet::new(&mut packet).unwrap();
+    ip_packet.set_version(6);
+    ip_packet.set_payload_length(8 + 24 + 100);
+    ip_packet.set_next_header(IpNextHeaderProtocols::Hopopt);
+    ip_packet.set_hop_limit(64);
+    packet[40] = IpNextHeaderProtocols::Ipv6Route.0;
+    packet[40 + 8..40 + 8 + 4].copy_from_slice(&[IpNextHeaderProtocols::Icmpv6.0, 2, 4, 1]);
+
+    let fragments = collect_fragments(fragment_ipv6, &packet, 144);
+    assert_eq!(fragments.len(), 2);
+    for fragment in &fragments {
+        // the extension headers are repeated in front of the Fragment header
+        assert_eq!(fragment[6..40 + 8], packet[6..40 + 8]);
+        assert_eq!(fragment[40 + 8], IpNextHeaderProtocols::Ipv6Frag.0);
+        assert_eq!(
+            fragment[40 + 8 + 1..40 + 8 + 24],
+            packet[40 + 8 + 1..40 + 8 + 24]
+        );
+
+        let fragment = Ipv6Packet::new(fragment).unwrap();
+        assert_eq!(
+            fragment.get_payload_length() as usize,
+            fragment.packet().len() - 40
+        );
+        let extension_headers = parse_extension_headers(&fragment);
+        assert_eq!(extension_headers.problem, None);
+        assert_eq!(extension_headers.segment_routing_header, Some(8));
+        assert_eq!(extension_headers.fragment.unwrap().header_offset, 8 + 24);
+        assert_eq!(
+            extension_headers.upper_layer_protocol,
+            IpNextHeaderProtocols::Icmpv6
+        );
+    }
+}
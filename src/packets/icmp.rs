@@ -1,19 +1,25 @@
-use log::{debug, trace};
-use pnet_packet::icmp::destination_unreachable::{
-    DestinationUnreachable, MutableDestinationUnreachablePacket,
-};
-use pnet_packet::icmp::time_exceeded::{MutableTimeExceededPacket, TimeExceeded};
-use pnet_packet::icmp::{checksum, Icmp, IcmpCode, IcmpPacket, IcmpTypes, MutableIcmpPacket};
-use pnet_packet::ipv4::Ipv4Packet;
-use pnet_packet::{Packet, PacketSize};
+use log::debug;
+use pnet_packet::icmp::destination_unreachable::MutableDestinationUnreachablePacket;
+use pnet_packet::icmp::time_exceeded::MutableTimeExceededPacket;
+use pnet_packet::icmp::{checksum, IcmpCode, IcmpPacket, IcmpType, IcmpTypes, MutableIcmpPacket};
+use pnet_packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::Packet;
 
-/// Handle an incoming ICMP packet and optionally return a response ICMP packet
-pub fn handle_icmp_packet(_ip_packet: &Ipv4Packet, icmp_packet: &IcmpPacket) -> Option<Vec<u8>> {
+/// ICMP time exceeded and destination unreachable messages have 4 unused bytes between header
+/// and quoted packet
+const UNUSED: [u8; 4] = [0; 4];
+
+/// Handle an incoming ICMP packet and optionally return a function that writes the response
+/// ICMP packet to the end of a buffer
+pub fn handle_icmp_packet<'p>(
+    _ip_packet: &Ipv4Packet,
+    icmp_packet: &'p IcmpPacket,
+) -> Option<impl FnOnce(&mut Vec<u8>) + 'p> {
     // if the incoming packet is an echo request, handle it and send back a proper response
     if icmp_packet.get_icmp_type() == IcmpTypes::EchoRequest
         && icmp_packet.get_icmp_code() == IcmpCode(0)
     {
-        Some(build_icmp_echo_response(icmp_packet.payload()))
+        Some(move |out: &mut Vec<u8>| build_icmp_echo_response(out, icmp_packet.payload()))
     }
     // ignore all other ICMP types
     else {
@@ -26,80 +32,98 @@ pub fn handle_icmp_packet(_ip_packet: &Ipv4Packet, icmp_packet: &IcmpPacket) ->
     }
 }
 
-/// Build an ICMP packet that is an echo response and has the provided payload.
+/// Build an ICMP packet that is an echo response and has the provided payload at the end of
+/// `out`.
 ///
 /// `icmp_payload` can usually simply be copied from an echo request because an echo response
 /// should normally just respond with all data as it was provided.
-fn build_icmp_echo_response(icmp_payload: &[u8]) -> Vec<u8> {
-    let mut result = vec![0; MutableIcmpPacket::minimum_packet_size() + icmp_payload.len()];
-
-    let mut packet = MutableIcmpPacket::new(&mut result)
-        .expect("Could not construct ICMP packet with vector as buffer");
-    packet.populate(&Icmp {
-        icmp_type: IcmpTypes::EchoReply,
-        icmp_code: IcmpCode(0),
-        checksum: 0,
-        payload: icmp_payload.to_vec(),
-    });
-    packet.set_checksum(checksum(&packet.to_immutable()));
-
-    trace!(
-        "Constructed ICMP response [response={:?}]",
-        packet.to_immutable()
-    );
-
-    result
+fn build_icmp_echo_response(out: &mut Vec<u8>, icmp_payload: &[u8]) {
+    write_icmp_message(out, IcmpTypes::EchoReply, IcmpCode(0), &[icmp_payload]);
 }
 
-/// Build an *ICMP timeout exceeded* packet.
+/// Build an *ICMP timeout exceeded* packet at the end of `out`.
 ///
 /// The *timeout exceeded* packets should be generated when an IP packet's time to live
-/// reaches 0. It also includes the failed original packet which can be provided via
-/// `original_ip_packet`.
-pub fn build_icmp_time_exceeded_response(original_ip_packet: &Ipv4Packet) -> Vec<u8> {
-    let mut result = vec![
-        0;
-        MutableTimeExceededPacket::minimum_packet_size()
-            + original_ip_packet.packet_size()
-    ];
-
-    let mut packet = MutableTimeExceededPacket::new(&mut result)
-        .expect("Could not create ICMP time exceeded packet from empty buffer");
-    packet.populate(&TimeExceeded {
-        icmp_type: IcmpTypes::TimeExceeded,
-        icmp_code: pnet_packet::icmp::time_exceeded::IcmpCodes::TimeToLiveExceededInTransit,
-        checksum: 0,
-        unused: 0,
-        payload: original_ip_packet.packet().to_vec(),
-    });
-    packet.set_checksum(checksum(&IcmpPacket::new(packet.packet()).unwrap()));
-
-    result
+/// reaches 0 or when a fragmented packet could not be reassembled in time which is
+/// distinguished by `icmp_code`. It also includes the failed original packet which can be
+/// provided via `original_ip_packet`.
+///
+/// The original packet is only quoted as far as the complete response IPv4 packet stays within
+/// `max_error_size` bytes.
+pub fn build_icmp_time_exceeded_response(
+    out: &mut Vec<u8>,
+    original_ip_packet: &Ipv4Packet,
+    icmp_code: IcmpCode,
+    max_error_size: usize,
+) {
+    let quote = quote_original_packet(
+        original_ip_packet,
+        MutableTimeExceededPacket::minimum_packet_size(),
+        max_error_size,
+    );
+    write_icmp_message(out, IcmpTypes::TimeExceeded, icmp_code, &[&UNUSED, quote]);
 }
 
-/// Build an *ICMP destination unreachable* packet.
+/// Build an *ICMP destination unreachable* packet at the end of `out`.
 ///
 /// The *destination unreachable* packets should be generated when a higher level protocol cannot
 /// be delivered. It also includes the failed original packet which can be provided via
 /// `original_ip_packet`.
-pub fn build_icmp_destination_unreachable_response(original_ip_packet: &Ipv4Packet) -> Vec<u8> {
-    let mut result = vec![
-        0;
-        MutableDestinationUnreachablePacket::minimum_packet_size()
-            + original_ip_packet.packet_size()
-    ];
-
-    let mut packet = MutableDestinationUnreachablePacket::new(&mut result).expect(
-        "Could not build view into buffer to construct destination unreachable ICMP packet",
+///
+/// The original packet is only quoted as far as the complete response IPv4 packet stays within
+/// `max_error_size` bytes.
+pub fn build_icmp_destination_unreachable_response(
+    out: &mut Vec<u8>,
+    original_ip_packet: &Ipv4Packet,
+    max_error_size: usize,
+) {
+    let quote = quote_original_packet(
+        original_ip_packet,
+        MutableDestinationUnreachablePacket::minimum_packet_size(),
+        max_error_size,
+    );
+    write_icmp_message(
+        out,
+        IcmpTypes::DestinationUnreachable,
+        IcmpCode(3),
+        &[&UNUSED, quote],
     );
-    packet.populate(&DestinationUnreachable {
-        icmp_type: IcmpTypes::DestinationUnreachable,
-        icmp_code: IcmpCode(3),
-        checksum: 0,
-        unused: 0,
-        payload: original_ip_packet.packet().to_vec(),
-    });
-    packet.set_checksum(checksum(&IcmpPacket::new(packet.packet()).unwrap()));
+}
+
+/// Write an ICMP message whose body is made up of `body_parts` to the end of `out` and
+/// calculate its checksum
+fn write_icmp_message(
+    out: &mut Vec<u8>,
+    icmp_type: IcmpType,
+    icmp_code: IcmpCode,
+    body_parts: &[&[u8]],
+) {
+    let start = out.len();
+    out.resize(start + MutableIcmpPacket::minimum_packet_size(), 0);
+    for part in body_parts {
+        out.extend_from_slice(part);
+    }
 
-    result
+    let mut packet = MutableIcmpPacket::new(&mut out[start..])
+        .expect("Could not construct ICMP packet in output buffer");
+    packet.set_icmp_type(icmp_type);
+    packet.set_icmp_code(icmp_code);
+    packet.set_checksum(checksum(&packet.to_immutable()));
+}
+
+/// Get the part of `original_ip_packet` that should be quoted in an ICMP error message with a
+/// header of `icmp_header_len` bytes so that the whole response stays within `max_error_size`.
+fn quote_original_packet<'p>(
+    original_ip_packet: &'p Ipv4Packet,
+    icmp_header_len: usize,
+    max_error_size: usize,
+) -> &'p [u8] {
+    let original = original_ip_packet.packet();
+    let quote_len = super::icmp_error_quote_len(
+        original.len(),
+        original_ip_packet.get_header_length() as usize * 4,
+        MutableIpv4Packet::minimum_packet_size() + icmp_header_len,
+        max_error_size,
+    );
+    &original[..quote_len]
 }
@@ -1,27 +1,35 @@
 use log::{debug, trace};
 use pnet_packet::icmpv6::{
-    checksum, Icmpv6, Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet,
+    checksum, Icmpv6Code, Icmpv6Packet, Icmpv6Type, Icmpv6Types, MutableIcmpv6Packet,
 };
-use pnet_packet::ipv6::Ipv6Packet;
-use pnet_packet::{Packet, PacketSize};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::Packet;
 use std::net::Ipv6Addr;
 
-/// Handle an incoming ICMPv6 packet and optionally return a response ICMPv6 packet
-pub fn handle_icmp6_packet(
-    packet: &Icmpv6Packet,
-    response_src_address: &Ipv6Addr,
-    response_dst_address: &Ipv6Addr,
-) -> Option<Vec<u8>> {
+/// ICMPv6 time exceeded and destination unreachable messages have 4 unused bytes between header
+/// and quoted packet
+const UNUSED: [u8; 4] = [0; 4];
+
+/// Handle an incoming ICMPv6 packet and optionally return a function that writes the response
+/// ICMPv6 packet to the end of a buffer
+pub fn handle_icmp6_packet<'p>(
+    packet: &'p Icmpv6Packet,
+    response_src_address: &'p Ipv6Addr,
+    response_dst_address: &'p Ipv6Addr,
+) -> Option<impl FnOnce(&mut Vec<u8>) + 'p> {
     // if the incoming packet is an echo request, handle it and send back a proper response
     if packet.get_icmpv6_type() == Icmpv6Types::EchoRequest
         && packet.get_icmpv6_code() == Icmpv6Code(0)
     {
         trace!("Handling ICMPv6 echo request by building an ICMPv6 echo response");
-        Some(build_icmp6_echo_response(
-            &packet.payload(),
-            response_src_address,
-            response_dst_address,
-        ))
+        Some(move |out: &mut Vec<u8>| {
+            build_icmp6_echo_response(
+                out,
+                packet.payload(),
+                response_src_address,
+                response_dst_address,
+            )
+        })
     }
     // ignore all other ICMP types
     else {
@@ -34,77 +42,62 @@ pub fn handle_icmp6_packet(
     }
 }
 
-/// Build an ICMPv6 packet that is an echo response and has the provided payload.
+/// Build an ICMPv6 packet that is an echo response and has the provided payload at the end of
+/// `out`.
 ///
 /// `icmp_payload` can usually be copied from an echo request because an echo response should
 /// normally just response with all data as it was sent.
 ///
 /// `src_address` and `dst_address` need to be provided to calculate an ICMPv6 checksum.
 fn build_icmp6_echo_response(
+    out: &mut Vec<u8>,
     icmp_payload: &[u8],
     my_src_address: &Ipv6Addr,
     my_dst_address: &Ipv6Addr,
-) -> Vec<u8> {
-    let mut response = vec![0; MutableIcmpv6Packet::minimum_packet_size() + icmp_payload.len()];
-
-    let mut packet = MutableIcmpv6Packet::new(&mut response)
-        .expect("Could not create ICMPv6 packet with vector as buffer");
-    packet.populate(&Icmpv6 {
-        icmpv6_type: Icmpv6Types::EchoReply,
-        icmpv6_code: Icmpv6Code(0),
-        checksum: 0,
-        payload: icmp_payload.to_vec(),
-    });
-    packet.set_checksum(checksum(
-        &packet.to_immutable(),
+) {
+    write_icmp6_message(
+        out,
+        Icmpv6Types::EchoReply,
+        Icmpv6Code(0),
+        &[icmp_payload],
         my_src_address,
         my_dst_address,
-    ));
-
-    response
+    );
 }
 
-/// Build an *ICMPv6 timeout exceeded* packet.
+/// Build an *ICMPv6 timeout exceeded* packet at the end of `out`.
 ///
-/// The *timeout exceeded* packets should be generated when an IPv6 packet's hop limit reaches 0.
+/// The *timeout exceeded* packets should be generated when an IPv6 packet's hop limit reaches 0
+/// or when a fragmented packet could not be reassembled in time which is distinguished by
+/// `icmp_code`.
 ///
 /// This packet includes the original IPv6 packet in it's payload to provide the sender with some
 /// context.
 ///
 /// `src_address` and `dst_address` need to be provided to calculate an ICMPv6 checksum.
+///
+/// The original packet is only quoted as far as the complete response IPv6 packet stays within
+/// `max_error_size` bytes.
 pub fn build_icmp6_time_exceeded_response(
+    out: &mut Vec<u8>,
     original_ip_packet: &Ipv6Packet,
+    icmp_code: Icmpv6Code,
     my_src_address: &Ipv6Addr,
     my_dst_address: &Ipv6Addr,
-) -> Vec<u8> {
-    // ICMP time exceeded responses have 4 8bit words of unused space between header and actual payload
-    const RESERVED_WORDS: usize = 4;
-
-    let mut result = vec![
-        0;
-        MutableIcmpv6Packet::minimum_packet_size()
-            + original_ip_packet.packet_size()
-            + RESERVED_WORDS
-    ];
-
-    let mut packet = MutableIcmpv6Packet::new(&mut result)
-        .expect("Could not create ICMPv6 time exceeded with vector as buffer");
-    packet.populate(&Icmpv6 {
-        icmpv6_type: Icmpv6Types::TimeExceeded,
-        icmpv6_code: Icmpv6Code(0),
-        checksum: 0,
-        payload: [&[0; RESERVED_WORDS], original_ip_packet.packet()].concat(),
-    });
-    packet.set_checksum(checksum(
-        &packet.to_immutable(),
+    max_error_size: usize,
+) {
+    let quote = quote_original_packet(original_ip_packet, UNUSED.len(), max_error_size);
+    write_icmp6_message(
+        out,
+        Icmpv6Types::TimeExceeded,
+        icmp_code,
+        &[&UNUSED, quote],
         my_src_address,
         my_dst_address,
-    ));
-
-    result
+    );
 }
 
-/// Build an *ICMPv6 destination unreachable* packet.
+/// Build an *ICMPv6 destination unreachable* packet at the end of `out`.
 ///
 /// The *destination unreachable* packets should be generated when a higher level protocol cannot
 /// be delivered.
@@ -113,34 +106,104 @@ pub fn build_icmp6_time_exceeded_response(
 /// context.
 ///
 /// `src_address` and `dst_address` need to be provided to calculate an ICMPv6 checksum.
+///
+/// The original packet is only quoted as far as the complete response IPv6 packet stays within
+/// `max_error_size` bytes.
 pub fn build_icmp6_destination_unreachable_response(
+    out: &mut Vec<u8>,
     original_ip_packet: &Ipv6Packet,
     my_src_address: &Ipv6Addr,
     my_dst_address: &Ipv6Addr,
-) -> Vec<u8> {
-    // ICMP destination unreachable messages have 4 8bit words of unused space between header and actual payload
-    const RESERVED_WORDS: usize = 4;
+    max_error_size: usize,
+) {
+    let quote = quote_original_packet(original_ip_packet, UNUSED.len(), max_error_size);
+    write_icmp6_message(
+        out,
+        Icmpv6Types::DestinationUnreachable,
+        Icmpv6Code(4),
+        &[&UNUSED, quote],
+        my_src_address,
+        my_dst_address,
+    );
+}
 
-    let mut result = vec![
-        0;
-        MutableIcmpv6Packet::minimum_packet_size()
-            + original_ip_packet.packet_size()
-            + RESERVED_WORDS
-    ];
+/// Build an *ICMPv6 parameter problem* packet at the end of `out`.
+///
+/// The *parameter problem* packets should be generated when a field of the IPv6 header or one of
+/// its extension headers cannot be processed.
+/// `pointer` identifies the octet of the original packet where the problem was found.
+///
+/// This packet includes the original IPv6 packet in it's payload to provide the sender with some
+/// context.
+///
+/// `src_address` and `dst_address` need to be provided to calculate an ICMPv6 checksum.
+///
+/// The original packet is only quoted as far as the complete response IPv6 packet stays within
+/// `max_error_size` bytes.
+pub fn build_icmp6_parameter_problem_response(
+    out: &mut Vec<u8>,
+    original_ip_packet: &Ipv6Packet,
+    code: u8,
+    pointer: u32,
+    my_src_address: &Ipv6Addr,
+    my_dst_address: &Ipv6Addr,
+    max_error_size: usize,
+) {
+    let pointer = pointer.to_be_bytes();
+    let quote = quote_original_packet(original_ip_packet, pointer.len(), max_error_size);
+    write_icmp6_message(
+        out,
+        Icmpv6Types::ParameterProblem,
+        Icmpv6Code(code),
+        &[&pointer, quote],
+        my_src_address,
+        my_dst_address,
+    );
+}
+
+/// Write an ICMPv6 message whose body is made up of `body_parts` to the end of `out` and
+/// calculate its checksum
+fn write_icmp6_message(
+    out: &mut Vec<u8>,
+    icmpv6_type: Icmpv6Type,
+    icmpv6_code: Icmpv6Code,
+    body_parts: &[&[u8]],
+    my_src_address: &Ipv6Addr,
+    my_dst_address: &Ipv6Addr,
+) {
+    let start = out.len();
+    out.resize(start + MutableIcmpv6Packet::minimum_packet_size(), 0);
+    for part in body_parts {
+        out.extend_from_slice(part);
+    }
 
-    let mut packet = MutableIcmpv6Packet::new(&mut result)
-        .expect("Could not build into buffer to construct destination unreachable ICMPv6 packet");
-    packet.populate(&Icmpv6 {
-        icmpv6_type: Icmpv6Types::DestinationUnreachable,
-        icmpv6_code: Icmpv6Code(4),
-        checksum: 0,
-        payload: [&[0; RESERVED_WORDS], original_ip_packet.packet()].concat(),
-    });
+    let mut packet = MutableIcmpv6Packet::new(&mut out[start..])
+        .expect("Could not construct ICMPv6 packet in output buffer");
+    packet.set_icmpv6_type(icmpv6_type);
+    packet.set_icmpv6_code(icmpv6_code);
     packet.set_checksum(checksum(
         &packet.to_immutable(),
         my_src_address,
         my_dst_address,
     ));
+}
 
-    result
+/// Get the part of `original_ip_packet` that should be quoted in an ICMPv6 error message with
+/// `reserved_len` bytes between ICMPv6 header and quote so that the whole response stays within
+/// `max_error_size`.
+fn quote_original_packet<'p>(
+    original_ip_packet: &'p Ipv6Packet,
+    reserved_len: usize,
+    max_error_size: usize,
+) -> &'p [u8] {
+    let original = original_ip_packet.packet();
+    let quote_len = super::icmp_error_quote_len(
+        original.len(),
+        MutableIpv6Packet::minimum_packet_size(),
+        MutableIpv6Packet::minimum_packet_size()
+            + MutableIcmpv6Packet::minimum_packet_size()
+            + reserved_len,
+        max_error_size,
+    );
+    &original[..quote_len]
 }
@@ -1,15 +1,29 @@
-use super::icmp;
+use super::reassembly::Incomplete;
+use super::{eligibility, forwarding, icmp, ipv4_options, FragmentKey4, State};
 use crate::argparse::Arguments;
 use crate::ip_addrs;
 use log::{debug, trace, warn};
+use pnet_packet::icmp::time_exceeded::IcmpCodes;
 use pnet_packet::icmp::IcmpPacket;
 use pnet_packet::ip::IpNextHeaderProtocols;
-use pnet_packet::ipv4::{checksum, Ipv4, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::ipv4::{checksum, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
 use pnet_packet::Packet;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Instant;
 
-/// Handle incoming IPv4 packet and optionally return a response IPv4 packet
-pub fn handle_ipv4_packet(program_args: &Arguments, packet: &Ipv4Packet) -> Option<Vec<u8>> {
+/// Identification value of the next response packet
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// Handle incoming IPv4 packet and optionally write a response IPv4 packet to the end of `out`.
+///
+/// Returns `None` if no response was written.
+pub fn handle_ipv4_packet(
+    program_args: &Arguments,
+    state: &State,
+    packet: &Ipv4Packet,
+    out: &mut Vec<u8>,
+) -> Option<()> {
     // The nth address in the virtual network if the request's TTL is used as n
     let nth_address_from_ttl = ip_addrs::get_nth_address_in_network4(
         packet.get_ttl() as u32,
@@ -22,11 +36,25 @@ pub fn handle_ipv4_packet(program_args: &Arguments, packet: &Ipv4Packet) -> Opti
         && nth_address_from_ttl != packet.get_destination()
     {
         debug!("Received IPv4 packet with small TTL, sending time exceeded response");
-        Some(build_ipv4_response(
-            &packet,
-            nth_address_from_ttl,
-            icmp::build_icmp_time_exceeded_response(&packet),
-        ))
+        build_time_exceeded(program_args, packet, nth_address_from_ttl, None, out)
+    }
+    // fragments are collected until the complete packet can be handled
+    else if packet.get_flags() & Ipv4Flags::MoreFragments != 0
+        || packet.get_fragment_offset() != 0
+    {
+        let reassembled = reassemble(state, packet)?;
+        let reassembled = Ipv4Packet::new(&reassembled)
+            .expect("Could not parse reassembled packet as IPv4 packet");
+        debug!(
+            "Reassembled fragmented IPv4 packet [len={}]",
+            reassembled.get_total_length()
+        );
+        handle_ipv4_packet(program_args, state, &reassembled, out)
+    }
+    // packets to a virtual address with a real backend are forwarded to it
+    else if let Some(backend) = forwarding::backend(program_args, packet.get_destination().into())
+    {
+        forwarding::forward(state, packet.packet(), backend, out)
     }
     // otherwise continue parsing the next layer
     else {
@@ -42,14 +70,18 @@ pub fn handle_ipv4_packet(program_args: &Arguments, packet: &Ipv4Packet) -> Opti
                         "Recognized and parsed ICMP packet [packet={:?}]",
                         icmp_packet
                     );
-                    match icmp::handle_icmp_packet(packet, &icmp_packet) {
-                        None => None,
-                        Some(icmp_response) => Some(build_ipv4_response(
-                            packet,
-                            packet.get_destination(),
-                            icmp_response,
-                        )),
-                    }
+                    let build_icmp_response = icmp::handle_icmp_packet(packet, &icmp_packet)?;
+                    let start = out.len();
+                    build_ipv4_response(
+                        out,
+                        packet,
+                        packet.get_destination(),
+                        packet.get_options_raw(),
+                        None,
+                        build_icmp_response,
+                    );
+                    ipv4_options::fill_echo_reply_options(program_args, packet, &mut out[start..]);
+                    Some(())
                 }
             }
         }
@@ -58,15 +90,21 @@ pub fn handle_ipv4_packet(program_args: &Arguments, packet: &Ipv4Packet) -> Opti
         else if packet.get_next_level_protocol() == IpNextHeaderProtocols::Udp
             || packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp
         {
+            if !eligibility::may_send_icmp_error4(program_args, packet) {
+                return None;
+            }
             debug!(
                 "Received {} packet. Responding with destination unreachable",
                 packet.get_next_level_protocol()
             );
-            Some(build_ipv4_response(
-                packet,
-                packet.get_destination(),
-                icmp::build_icmp_destination_unreachable_response(packet),
-            ))
+            build_ipv4_response(out, packet, packet.get_destination(), &[], None, |out| {
+                icmp::build_icmp_destination_unreachable_response(
+                    out,
+                    packet,
+                    program_args.icmp4_error_size,
+                )
+            });
+            Some(())
         }
         // all other upper layer protocols we don't know so we just don't respond at all
         else {
@@ -79,39 +117,177 @@ pub fn handle_ipv4_packet(program_args: &Arguments, packet: &Ipv4Packet) -> Opti
     }
 }
 
-/// Build an IPv4 packet in response to the provided one
+/// Add the fragment `packet` to the packet it belongs to and return that packet once it is
+/// complete
+fn reassemble(state: &State, packet: &Ipv4Packet) -> Option<Vec<u8>> {
+    let header_len = packet.get_header_length() as usize * 4;
+    let offset = packet.get_fragment_offset() as usize * 8;
+    let reassembled = state
+        .reassembly4
+        .lock()
+        .expect("IPv4 reassembly state is poisoned")
+        .insert(
+            FragmentKey4 {
+                source: packet.get_source(),
+                destination: packet.get_destination(),
+                protocol: packet.get_next_level_protocol(),
+                identification: packet.get_identification(),
+            },
+            Instant::now(),
+            offset,
+            packet.get_flags() & Ipv4Flags::MoreFragments != 0,
+            if offset == 0 {
+                packet.packet().get(..header_len)
+            } else {
+                None
+            },
+            packet.payload(),
+        )?;
+
+    let mut result = [reassembled.header.as_slice(), &reassembled.data].concat();
+    let mut result_packet = MutableIpv4Packet::new(&mut result)
+        .expect("Could not create IPv4 packet from reassembled buffer");
+    result_packet.set_total_length((reassembled.header.len() + reassembled.data.len()) as u16);
+    result_packet.set_flags(result_packet.get_flags() & !Ipv4Flags::MoreFragments);
+    result_packet.set_fragment_offset(0);
+    result_packet.set_checksum(checksum(&result_packet.to_immutable()));
+    Some(result)
+}
+
+/// Handle a fragmented packet whose fragments did not all arrive in time by writing an ICMP
+/// time exceeded message from its destination to the end of `out`.
 ///
-/// The generated response packet will have most of it's relevant data extracted from `request`
-/// except for it's own source address which is provided via `src_address`. It will also have
-/// the given `data` as its payload.
-fn build_ipv4_response(request: &Ipv4Packet, src_address: Ipv4Addr, data: Vec<u8>) -> Vec<u8> {
-    let mut response = vec![0; MutableIpv4Packet::minimum_packet_size() + data.len()];
+/// This is only done if the first fragment arrived because only it identifies the packet to
+/// its sender.
+pub fn handle_reassembly_timeout(
+    program_args: &Arguments,
+    incomplete: Incomplete,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    let first_fragment = [incomplete.header?, incomplete.data].concat();
+    let first_fragment = Ipv4Packet::new(&first_fragment)?;
+    if !eligibility::may_send_icmp_error4(program_args, &first_fragment) {
+        return None;
+    }
 
-    let mut packet = MutableIpv4Packet::new(&mut response)
-        .expect("Could not construct IPv4 packet with vector as buffer");
-    packet.populate(&Ipv4 {
-        version: 4,
-        header_length: 5,
-        dscp: 0,
-        ecn: 0,
-        total_length: 20_u16 + data.len() as u16,
-        identification: 42,
-        flags: Ipv4Flags::DontFragment,
-        fragment_offset: 0,
-        ttl: request.get_ttl() - 1,
-        next_level_protocol: IpNextHeaderProtocols::Icmp,
-        checksum: 0,
-        source: src_address,
-        destination: request.get_source(),
-        options: vec![],
-        payload: data,
+    debug!(
+        "Could not reassemble fragmented IPv4 packet in time, sending time exceeded response [src={}]",
+        first_fragment.get_source()
+    );
+    build_ipv4_response(
+        out,
+        &first_fragment,
+        first_fragment.get_destination(),
+        &[],
+        None,
+        |out| {
+            icmp::build_icmp_time_exceeded_response(
+                out,
+                &first_fragment,
+                IcmpCodes::FragmentReasemblyTimeExceeded,
+                program_args.icmp4_error_size,
+            )
+        },
+    );
+    Some(())
+}
+
+/// Write a time exceeded response from the virtual hop `hop` to the end of `out` if one may be
+/// sent for `packet`.
+///
+/// The response's TTL is `ttl` or, if that is `None`, one less than `packet`'s.
+/// Returns `None` if no response was written.
+pub fn build_time_exceeded(
+    program_args: &Arguments,
+    packet: &Ipv4Packet,
+    hop: Ipv4Addr,
+    ttl: Option<u8>,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    if !eligibility::may_send_icmp_error4(program_args, packet) {
+        return None;
+    }
+    build_ipv4_response(out, packet, hop, &[], ttl, |out| {
+        icmp::build_icmp_time_exceeded_response(
+            out,
+            packet,
+            IcmpCodes::TimeToLiveExceededInTransit,
+            program_args.icmp4_error_size,
+        )
     });
+    Some(())
+}
+
+/// Build an IPv4 packet in response to the provided one at the end of `out`
+///
+/// The generated response packet will have most of it's relevant data extracted from `request`
+/// except for it's own source address which is provided via `src_address`. Its payload is
+/// written directly behind its header by `write_data`.
+///
+/// `options` are the raw bytes of the IPv4 options that the response should carry.
+/// Their length needs to be a multiple of 4.
+/// The TTL is `ttl` or, if that is `None`, one less than the request's.
+fn build_ipv4_response(
+    out: &mut Vec<u8>,
+    request: &Ipv4Packet,
+    src_address: Ipv4Addr,
+    options: &[u8],
+    ttl: Option<u8>,
+    write_data: impl FnOnce(&mut Vec<u8>),
+) {
+    let start = out.len();
+    let header_length = MutableIpv4Packet::minimum_packet_size() + options.len();
+    out.resize(start + header_length, 0);
+    write_data(out);
+    let total_length = out.len() - start;
+
+    let mut packet = MutableIpv4Packet::new(&mut out[start..])
+        .expect("Could not construct IPv4 packet in output buffer");
+    packet.set_version(4);
+    packet.set_header_length((header_length / 4) as u8);
+    packet.set_total_length(total_length as u16);
+    packet.set_identification(NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed));
+    packet.set_flags(Ipv4Flags::DontFragment);
+    packet.set_ttl(ttl.unwrap_or(request.get_ttl().saturating_sub(1)));
+    packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    packet.set_source(src_address);
+    packet.set_destination(request.get_source());
+    packet.get_options_raw_mut().copy_from_slice(options);
     packet.set_checksum(checksum(&packet.to_immutable()));
 
     trace!(
         "Constructed IPv4 response [len={}, response={:?}]",
-        packet.get_total_length(),
+        total_length,
         packet.to_immutable(),
     );
-    response
+}
+
+#[cfg(test)]
+#[test]
+fn test_build_ipv4_response_with_options() {
+    let mut request = vec![0; 20];
+    let mut request_packet = MutableIpv4Packet::new(&mut request).unwrap();
+    request_packet.set_version(4);
+    request_packet.set_header_length(5);
+    request_packet.set_total_length(20);
+    request_packet.set_ttl(64);
+    request_packet.set_source(Ipv4Addr::new(192, 0, 2, 1));
+    request_packet.set_destination(Ipv4Addr::new(10, 0, 0, 5));
+
+    let options = [7, 7, 8, 10, 0, 0, 5, 0];
+    let mut response = vec![0xff; 3];
+    build_ipv4_response(
+        &mut response,
+        &Ipv4Packet::new(&request).unwrap(),
+        Ipv4Addr::new(10, 0, 0, 5),
+        &options,
+        None,
+        |out| out.extend_from_slice(&[1, 2, 3, 4]),
+    );
+    let response = Ipv4Packet::new(&response[3..]).unwrap();
+    assert_eq!(response.get_header_length(), 7);
+    assert_eq!(response.get_total_length(), 32);
+    assert_eq!(response.get_options_raw(), &options);
+    assert_eq!(response.payload(), &[1, 2, 3, 4]);
+    assert_eq!(response.get_checksum(), checksum(&response));
 }
@@ -0,0 +1,284 @@
+//! Filling of the IPv4 *Record Route* and *Timestamp* options (RFC 791)
+//!
+//! Probes like `ping -R` or `ping -T tsandaddr` ask every hop to write its address or a
+//! timestamp into the IPv4 header.
+//! Since the virtual hops never really see these packets, the options are filled in as if the
+//! request and its reply had passed through all of them.
+
+use crate::argparse::Arguments;
+use crate::ip_addrs;
+use log::trace;
+use pnet_packet::ipv4::{checksum, Ipv4Packet, MutableIpv4Packet};
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Option type of the *End of Option List* option
+const OPTION_END: u8 = 0;
+/// Option type of the *No Operation* option
+const OPTION_NOP: u8 = 1;
+/// Option type of the *Record Route* option
+const OPTION_RECORD_ROUTE: u8 = 7;
+/// Option type of the *Internet Timestamp* option
+const OPTION_TIMESTAMP: u8 = 68;
+
+/// Timestamp option flag for timestamps only
+const TIMESTAMP_ONLY: u8 = 0;
+/// Timestamp option flag for each timestamp being preceded by the recording hop's address
+const TIMESTAMP_AND_ADDRESS: u8 = 1;
+/// Timestamp option flag for timestamps only being recorded by prespecified hops
+const TIMESTAMP_PRESPECIFIED: u8 = 3;
+
+/// Fill the options of the echo `reply` to `request`, which are a copy of the request's, and
+/// update the reply's header checksum.
+///
+/// The Record Route and Timestamp options are filled with the virtual hops that the request
+/// passed on its way to its destination and that the reply passes on its way back.
+pub fn fill_echo_reply_options(program_args: &Arguments, request: &Ipv4Packet, reply: &mut [u8]) {
+    let mut reply =
+        MutableIpv4Packet::new(reply).expect("Could not parse IPv4 echo reply in its buffer");
+    let options = reply.get_options_raw_mut();
+    if options.is_empty() {
+        return;
+    }
+
+    let netmask_size = ip_addrs::calc_netmask_size_with_n_hosts4(program_args.n_hosts);
+    let destination = request.get_destination();
+    let destination_number = ip_addrs::get_host_number_in_network4(netmask_size, &destination);
+    let hops = (1..=destination_number)
+        .chain((1..destination_number).rev())
+        .map(|n| ip_addrs::get_nth_address_in_network4(n, netmask_size, &destination));
+
+    fill_options(options, hops, current_timestamp());
+    trace!("Filled IPv4 options of echo reply [options={:?}]", options);
+    reply.set_checksum(checksum(&reply.to_immutable()));
+}
+
+/// Fill all Record Route and Timestamp options in `options` as if a packet had passed all
+/// `hops` in order at the time `timestamp`.
+fn fill_options(options: &mut [u8], hops: impl Iterator<Item = Ipv4Addr> + Clone, timestamp: u32) {
+    let mut i = 0;
+    while i < options.len() {
+        let option_type = options[i];
+        if option_type == OPTION_END {
+            break;
+        }
+        if option_type == OPTION_NOP {
+            i += 1;
+            continue;
+        }
+        let option_len = match options.get(i + 1) {
+            Some(len) if *len >= 2 && i + *len as usize <= options.len() => *len as usize,
+            _ => break,
+        };
+
+        let option = &mut options[i..i + option_len];
+        match option_type {
+            OPTION_RECORD_ROUTE => fill_record_route(option, hops.clone()),
+            OPTION_TIMESTAMP => fill_timestamp(option, hops.clone(), timestamp),
+            _ => {}
+        }
+
+        i += option_len;
+    }
+}
+
+/// Write the address of every hop into the free slots of a Record Route `option`
+fn fill_record_route(option: &mut [u8], hops: impl Iterator<Item = Ipv4Addr>) {
+    if option.len() < 3 {
+        return;
+    }
+    for hop in hops {
+        // the pointer is the 1-based index of the next free slot
+        let pointer = option[2] as usize;
+        if pointer < 4 || pointer + 3 > option.len() {
+            break;
+        }
+        option[pointer - 1..pointer + 3].copy_from_slice(&hop.octets());
+        option[2] += 4;
+    }
+}
+
+/// Let every hop write a timestamp (and its address, depending on the option's flag) into a
+/// Timestamp `option`.
+///
+/// Hops that find no free slot increase the option's overflow counter instead.
+fn fill_timestamp(option: &mut [u8], hops: impl Iterator<Item = Ipv4Addr>, timestamp: u32) {
+    if option.len() < 4 {
+        return;
+    }
+    let flag = option[3] & 0x0f;
+    let entry_len = if flag == TIMESTAMP_ONLY { 4 } else { 8 };
+
+    for hop in hops {
+        let pointer = option[2] as usize;
+        if pointer < 5 {
+            break;
+        }
+        if pointer + entry_len - 1 > option.len() {
+            // prespecified hops can only be missing, not overflow
+            if flag != TIMESTAMP_PRESPECIFIED {
+                let overflow = option[3] >> 4;
+                if overflow < 0x0f {
+                    option[3] = ((overflow + 1) << 4) | flag;
+                }
+            }
+            continue;
+        }
+
+        let entry = &mut option[pointer - 1..pointer - 1 + entry_len];
+        match flag {
+            TIMESTAMP_ONLY => entry.copy_from_slice(&timestamp.to_be_bytes()),
+            TIMESTAMP_AND_ADDRESS => {
+                entry[..4].copy_from_slice(&hop.octets());
+                entry[4..].copy_from_slice(&timestamp.to_be_bytes());
+            }
+            TIMESTAMP_PRESPECIFIED => {
+                if entry[..4] != hop.octets() {
+                    continue;
+                }
+                entry[4..].copy_from_slice(&timestamp.to_be_bytes());
+            }
+            // unknown flags are left alone
+            _ => break,
+        }
+        option[2] += entry_len as u8;
+    }
+}
+
+/// The current time in milliseconds since midnight UT as used by the Timestamp option
+fn current_timestamp() -> u32 {
+    const MILLIS_PER_DAY: u128 = 24 * 60 * 60 * 1000;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the unix epoch");
+    (now.as_millis() % MILLIS_PER_DAY) as u32
+}
+
+#[cfg(test)]
+#[test]
+fn test_fill_options() {
+    let hops = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+
+    // record route with space for only one address, followed by a NOP for alignment
+    let mut options = [OPTION_RECORD_ROUTE, 7, 4, 0, 0, 0, 0, OPTION_NOP];
+    fill_options(&mut options, hops.iter().copied(), 42);
+    assert_eq!(
+        options,
+        [OPTION_RECORD_ROUTE, 7, 8, 10, 0, 0, 1, OPTION_NOP]
+    );
+
+    // timestamps and addresses with space for only one hop
+    let mut options = [
+        OPTION_TIMESTAMP,
+        12,
+        5,
+        TIMESTAMP_AND_ADDRESS,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    fill_options(&mut options, hops.iter().copied(), 42);
+    assert_eq!(
+        options,
+        [
+            OPTION_TIMESTAMP,
+            12,
+            13,
+            0x10 | TIMESTAMP_AND_ADDRESS,
+            10,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            42
+        ]
+    );
+
+    // timestamps of a prespecified hop
+    let mut options = [
+        OPTION_TIMESTAMP,
+        12,
+        5,
+        TIMESTAMP_PRESPECIFIED,
+        10,
+        0,
+        0,
+        2,
+        0,
+        0,
+        0,
+        0,
+    ];
+    fill_options(&mut options, hops.iter().copied(), 42);
+    assert_eq!(
+        options,
+        [
+            OPTION_TIMESTAMP,
+            12,
+            13,
+            TIMESTAMP_PRESPECIFIED,
+            10,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            42
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_fill_echo_reply_options() {
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--nhosts",
+        "10",
+    ]);
+    // a request to the 2nd virtual host with space for three recorded addresses
+    let mut request = vec![0; 20 + 16];
+    let mut request_packet = MutableIpv4Packet::new(&mut request).unwrap();
+    request_packet.set_version(4);
+    request_packet.set_header_length(9);
+    request_packet.set_total_length(36);
+    request_packet.set_destination(Ipv4Addr::new(10, 0, 0, 2));
+    request[20..24].copy_from_slice(&[OPTION_NOP, OPTION_RECORD_ROUTE, 15, 4]);
+
+    let mut reply = vec![0xff; 3];
+    reply.extend_from_slice(&request);
+    fill_echo_reply_options(&args, &Ipv4Packet::new(&request).unwrap(), &mut reply[3..]);
+    let reply = Ipv4Packet::new(&reply[3..]).unwrap();
+    assert_eq!(
+        reply.get_options_raw(),
+        [
+            OPTION_NOP,
+            OPTION_RECORD_ROUTE,
+            15,
+            16,
+            10,
+            0,
+            0,
+            1,
+            10,
+            0,
+            0,
+            2,
+            10,
+            0,
+            0,
+            1,
+        ]
+    );
+    assert_eq!(reply.get_checksum(), checksum(&reply));
+}
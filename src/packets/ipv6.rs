@@ -1,15 +1,62 @@
-use super::icmp6;
+use super::ipv6_extensions::{ExtensionHeaders, FragmentHeader, Problem, ProblemAction};
+use super::reassembly::Incomplete;
+use super::srv6::PathEnd;
+use super::{eligibility, forwarding, icmp6, ipv6_extensions, srv6, FragmentKey6, State};
 use crate::argparse::Arguments;
 use crate::ip_addrs;
 use log::{debug, trace, warn};
-use pnet_packet::icmpv6::Icmpv6Packet;
+use pnet_packet::icmpv6::{Icmpv6Code, Icmpv6Packet};
 use pnet_packet::ip::IpNextHeaderProtocols;
-use pnet_packet::ipv6::{Ipv6, Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
 use pnet_packet::Packet;
 use std::net::Ipv6Addr;
+use std::time::Instant;
+
+/// Length of an IPv6 Fragment header
+const FRAGMENT_HEADER_LEN: usize = 8;
+
+/// ICMPv6 time exceeded code for packets whose hop limit was exceeded in transit
+const HOP_LIMIT_EXCEEDED: Icmpv6Code = Icmpv6Code(0);
+
+/// ICMPv6 time exceeded code for packets that could not be reassembled in time
+const FRAGMENT_REASSEMBLY_TIME_EXCEEDED: Icmpv6Code = Icmpv6Code(1);
+
+/// Handle incoming IPv6 packet and optionally write a response IPv6 packet to the end of `out`.
+///
+/// Returns `None` if no response was written.
+pub fn handle_ipv6_packet(
+    program_args: &Arguments,
+    state: &State,
+    packet: &Ipv6Packet,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    let extension_headers = ipv6_extensions::parse_extension_headers(packet);
+
+    // problems in the Hop-by-Hop options header are already noticed by the first hop
+    if let Some(problem) = extension_headers.problem.filter(|p| p.in_hop_by_hop) {
+        let first_hop = ip_addrs::get_nth_address_in_network6(
+            1,
+            ip_addrs::calc_netmask_size_with_n_hosts6(program_args.n_hosts),
+            &packet.get_destination(),
+        );
+        return handle_extension_header_problem(
+            program_args,
+            packet,
+            &extension_headers,
+            problem,
+            first_hop,
+            out,
+        );
+    }
+
+    // segment routed packets take their own path through the virtual network
+    if let Some(srh_offset) = extension_headers.segment_routing_header {
+        let segments_left = packet.payload()[srh_offset + 3];
+        if segments_left != 0 && extension_headers.problem.is_none() {
+            return handle_segment_routed_packet(program_args, state, packet, srh_offset, out);
+        }
+    }
 
-/// Handle incoming IPv6 packet and optionally return a response IPv6 packet
-pub fn handle_ipv6_packet(program_args: &Arguments, packet: &Ipv6Packet) -> Option<Vec<u8>> {
     // The nth address in the virtual network if the request's TTL is used as n
     let nth_address_from_ttl = ip_addrs::get_nth_address_in_network6(
         packet.get_hop_limit() as usize,
@@ -27,22 +74,62 @@ pub fn handle_ipv6_packet(program_args: &Arguments, packet: &Ipv6Packet) -> Opti
             program_args.n_hosts,
             nth_address_from_ttl
         );
-        Some(build_ipv6_response(
+        build_time_exceeded(
+            program_args,
             packet,
+            &extension_headers,
             nth_address_from_ttl,
-            icmp6::build_icmp6_time_exceeded_response(
-                packet,
-                &nth_address_from_ttl,
-                &packet.get_source(),
-            ),
-            Some(64),
-        ))
+            64,
+            out,
+        )
+    }
+    // otherwise the packet has reached its destination
+    else {
+        handle_at_destination(program_args, state, packet, &extension_headers, out)
+    }
+}
+
+/// Handle a packet that has reached its destination inside the virtual network
+fn handle_at_destination(
+    program_args: &Arguments,
+    state: &State,
+    packet: &Ipv6Packet,
+    extension_headers: &ExtensionHeaders,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    // all other extension header problems are noticed by the destination
+    if let Some(problem) = extension_headers.problem {
+        handle_extension_header_problem(
+            program_args,
+            packet,
+            extension_headers,
+            problem,
+            packet.get_destination(),
+            out,
+        )
+    }
+    // fragments are collected until the complete packet can be handled
+    else if let Some(fragment) = extension_headers.fragment {
+        let reassembled = reassemble(state, packet, fragment)?;
+        let reassembled = Ipv6Packet::new(&reassembled)
+            .expect("Could not parse reassembled packet as IPv6 packet");
+        debug!(
+            "Reassembled fragmented IPv6 packet [payload_len={}]",
+            reassembled.get_payload_length()
+        );
+        handle_ipv6_packet(program_args, state, &reassembled, out)
+    }
+    // packets to a virtual address with a real backend are forwarded to it
+    else if let Some(backend) = forwarding::backend(program_args, packet.get_destination().into())
+    {
+        forwarding::forward(state, packet.packet(), backend, out)
     }
     // otherwise continue parsing the next layer
     else {
+        let upper_layer_protocol = extension_headers.upper_layer_protocol;
         // we know how to handle ICMP6 so try to parse and handle it
-        if packet.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
-            match Icmpv6Packet::new(packet.payload()) {
+        if upper_layer_protocol == IpNextHeaderProtocols::Icmpv6 {
+            match Icmpv6Packet::new(extension_headers.upper_layer_payload(packet)) {
                 None => {
                     warn!("Could not parse incoming ICMP6 packet");
                     None
@@ -52,75 +139,373 @@ pub fn handle_ipv6_packet(program_args: &Arguments, packet: &Ipv6Packet) -> Opti
                         "Recognized and parsed ICMP6 packet [packet={:?}]",
                         icmp_packet
                     );
-                    match icmp6::handle_icmp6_packet(
+                    let (response_src_address, response_dst_address) =
+                        (packet.get_destination(), packet.get_source());
+                    let build_icmp_response = icmp6::handle_icmp6_packet(
                         &icmp_packet,
-                        &packet.get_destination(),
-                        &packet.get_source(),
-                    ) {
-                        None => None,
-                        Some(icmp_response) => Some(build_ipv6_response(
-                            packet,
-                            packet.get_destination(),
-                            icmp_response,
-                            None,
-                        )),
-                    }
+                        &response_src_address,
+                        &response_dst_address,
+                    )?;
+                    build_ipv6_response(
+                        out,
+                        packet,
+                        response_src_address,
+                        None,
+                        build_icmp_response,
+                    );
+                    Some(())
                 }
             }
         }
         // if we receive a UDP or TCP packet, we send an ICMP destination unreachable response
         // to indicate that the port is closed
-        else if packet.get_next_header() == IpNextHeaderProtocols::Udp
-            || packet.get_next_header() == IpNextHeaderProtocols::Tcp
+        else if upper_layer_protocol == IpNextHeaderProtocols::Udp
+            || upper_layer_protocol == IpNextHeaderProtocols::Tcp
         {
-            Some(build_ipv6_response(
-                packet,
-                packet.get_destination(),
+            if !eligibility::may_send_icmp_error6(packet, extension_headers) {
+                return None;
+            }
+            build_ipv6_response(out, packet, packet.get_destination(), None, |out| {
                 icmp6::build_icmp6_destination_unreachable_response(
+                    out,
                     packet,
                     &packet.get_destination(),
                     &packet.get_source(),
-                ),
-                None,
-            ))
+                    program_args.icmp6_error_size,
+                )
+            });
+            Some(())
         }
         // all other upper layer protocols we don't know so we just don't respond at all
         else {
             debug!(
                 "Received IPv6 packet with unhandled higher protocol [proto={}]",
-                packet.get_next_header()
+                upper_layer_protocol
+            );
+            None
+        }
+    }
+}
+
+/// Handle a packet with a Segment Routing header that still has segments left to visit.
+///
+/// The packet is passed from one virtual segment endpoint to the next and may expire on the
+/// way there.
+/// If it visits all of its segments, it is handled by the final one.
+/// If it encounters a segment that is not a virtual host, it is dropped so that nobody can make
+/// this host send packets to arbitrary destinations.
+fn handle_segment_routed_packet(
+    program_args: &Arguments,
+    state: &State,
+    packet: &Ipv6Packet,
+    srh_offset: usize,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    let prefix_length = ip_addrs::calc_netmask_size_with_n_hosts6(program_args.n_hosts);
+    let path = srv6::virtual_path(prefix_length, packet, srh_offset);
+    trace!(
+        "Calculated virtual path of segment routed packet [path={:?}]",
+        path
+    );
+
+    // every hop except a final destination forwards the packet and decrements its hop limit
+    let hop_limit = packet.get_hop_limit() as usize;
+    let forwarding_hops = match path.end {
+        PathEnd::Delivered => path.hops.len().saturating_sub(1),
+        PathEnd::Exits => path.hops.len(),
+    };
+
+    if hop_limit <= forwarding_hops {
+        let hop = path.hops.get(hop_limit.max(1) - 1)?;
+        let mut expired = Vec::new();
+        srv6::rewrite(
+            &mut expired,
+            packet,
+            srh_offset,
+            hop.destination,
+            hop.segments_left,
+            1,
+        );
+        let expired = Ipv6Packet::new(&expired)
+            .expect("Could not parse rewritten segment routed packet as IPv6 packet");
+        let extension_headers = ipv6_extensions::parse_extension_headers(&expired);
+        if !eligibility::may_send_icmp_error6(&expired, &extension_headers) {
+            return None;
+        }
+        debug!(
+            "Segment routed IPv6 packet expired on its way, sending time exceeded response [v_addr={}, active_segment={}]",
+            hop.address, hop.destination
+        );
+        build_ipv6_response(out, &expired, hop.address, Some(64), |out| {
+            icmp6::build_icmp6_time_exceeded_response(
+                out,
+                &expired,
+                HOP_LIMIT_EXCEEDED,
+                &hop.address,
+                &expired.get_source(),
+                program_args.icmp6_error_size,
+            )
+        });
+        return Some(());
+    }
+
+    match path.end {
+        PathEnd::Delivered => {
+            let mut delivered = Vec::new();
+            srv6::rewrite(
+                &mut delivered,
+                packet,
+                srh_offset,
+                path.destination,
+                0,
+                packet.get_hop_limit(),
+            );
+            let delivered = Ipv6Packet::new(&delivered)
+                .expect("Could not parse rewritten segment routed packet as IPv6 packet");
+            let extension_headers = ipv6_extensions::parse_extension_headers(&delivered);
+            handle_at_destination(program_args, state, &delivered, &extension_headers, out)
+        }
+        PathEnd::Exits => {
+            debug!(
+                "Dropping segment routed IPv6 packet that would leave the virtual network [next_segment={}]",
+                path.destination
             );
             None
         }
     }
 }
 
-/// Build an IPv6 packet in response to the provided one
+/// Add the fragment `packet` to the packet it belongs to and return that packet once it is
+/// complete.
+///
+/// The Fragment header is removed from the reassembled packet (RFC 8200 section 4.5).
+fn reassemble(state: &State, packet: &Ipv6Packet, fragment: FragmentHeader) -> Option<Vec<u8>> {
+    let header_len = MutableIpv6Packet::minimum_packet_size();
+    let fragmentable_offset = fragment.header_offset + FRAGMENT_HEADER_LEN;
+    let offset = fragment.offset as usize * 8;
+    let reassembled = state
+        .reassembly6
+        .lock()
+        .expect("IPv6 reassembly state is poisoned")
+        .insert(
+            FragmentKey6 {
+                source: packet.get_source(),
+                destination: packet.get_destination(),
+                identification: fragment.identification,
+            },
+            Instant::now(),
+            offset,
+            fragment.more_fragments,
+            if offset == 0 {
+                packet.packet().get(..header_len + fragmentable_offset)
+            } else {
+                None
+            },
+            packet.payload().get(fragmentable_offset..)?,
+        )?;
+
+    // the header of the first fragment ends with its Fragment header
+    let first_header = Ipv6Packet::new(&reassembled.header)?;
+    let first_fragment = ipv6_extensions::parse_extension_headers(&first_header).fragment?;
+    let fragment_header_start = reassembled.header.len() - FRAGMENT_HEADER_LEN;
+    let next_header = reassembled.header[fragment_header_start];
+    let payload_len = fragment_header_start - header_len + reassembled.data.len();
+    if payload_len > u16::MAX as usize {
+        debug!("Dropping reassembled IPv6 packet that exceeds the maximum payload length");
+        return None;
+    }
+
+    let mut result = [
+        &reassembled.header[..fragment_header_start],
+        &reassembled.data,
+    ]
+    .concat();
+    result[first_fragment.next_header_field] = next_header;
+    let mut result_packet = MutableIpv6Packet::new(&mut result)
+        .expect("Could not create IPv6 packet from reassembled buffer");
+    result_packet.set_payload_length(payload_len as u16);
+    Some(result)
+}
+
+/// Handle a fragmented packet whose fragments did not all arrive in time by writing an ICMPv6
+/// time exceeded message from its destination to the end of `out`.
+///
+/// This is only done if the first fragment arrived because only it identifies the packet to
+/// its sender (RFC 8200 section 4.5).
+pub fn handle_reassembly_timeout(
+    program_args: &Arguments,
+    incomplete: Incomplete,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    let mut first_fragment = [incomplete.header?, incomplete.data].concat();
+    let payload_len = first_fragment.len() - MutableIpv6Packet::minimum_packet_size();
+    MutableIpv6Packet::new(&mut first_fragment)?.set_payload_length(payload_len as u16);
+    let first_fragment = Ipv6Packet::new(&first_fragment)?;
+    let extension_headers = ipv6_extensions::parse_extension_headers(&first_fragment);
+    if !eligibility::may_send_icmp_error6(&first_fragment, &extension_headers) {
+        return None;
+    }
+
+    debug!(
+        "Could not reassemble fragmented IPv6 packet in time, sending time exceeded response [src={}]",
+        first_fragment.get_source()
+    );
+    build_ipv6_response(
+        out,
+        &first_fragment,
+        first_fragment.get_destination(),
+        Some(64),
+        |out| {
+            icmp6::build_icmp6_time_exceeded_response(
+                out,
+                &first_fragment,
+                FRAGMENT_REASSEMBLY_TIME_EXCEEDED,
+                &first_fragment.get_destination(),
+                &first_fragment.get_source(),
+                program_args.icmp6_error_size,
+            )
+        },
+    );
+    Some(())
+}
+
+/// Handle a problem that was found while walking the extension header chain of `packet` by
+/// either dropping it or writing an ICMPv6 parameter problem sent from `src_address` to the end
+/// of `out`
+fn handle_extension_header_problem(
+    program_args: &Arguments,
+    packet: &Ipv6Packet,
+    extension_headers: &ExtensionHeaders,
+    problem: Problem,
+    src_address: Ipv6Addr,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    match problem.action {
+        ProblemAction::Discard => {
+            debug!("Discarding IPv6 packet with malformed or unsupported extension headers");
+            None
+        }
+        ProblemAction::ParameterProblem { code, pointer } => {
+            if !eligibility::may_send_parameter_problem6(packet, extension_headers, code) {
+                return None;
+            }
+            debug!(
+                "Received IPv6 packet with unsupported extension headers, sending parameter problem [code={}, pointer={}]",
+                code, pointer
+            );
+            build_ipv6_response(out, packet, src_address, Some(64), |out| {
+                icmp6::build_icmp6_parameter_problem_response(
+                    out,
+                    packet,
+                    code,
+                    pointer,
+                    &src_address,
+                    &packet.get_source(),
+                    program_args.icmp6_error_size,
+                )
+            });
+            Some(())
+        }
+    }
+}
+
+/// Write a time exceeded response with `hop_limit` from the virtual hop `hop` to the end of `out`
+/// if one may be sent for `packet`.
+///
+/// Returns `None` if no response was written.
+pub fn build_time_exceeded(
+    program_args: &Arguments,
+    packet: &Ipv6Packet,
+    extension_headers: &ExtensionHeaders,
+    hop: Ipv6Addr,
+    hop_limit: u8,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    if !eligibility::may_send_icmp_error6(packet, extension_headers) {
+        return None;
+    }
+    build_ipv6_response(out, packet, hop, Some(hop_limit), |out| {
+        icmp6::build_icmp6_time_exceeded_response(
+            out,
+            packet,
+            HOP_LIMIT_EXCEEDED,
+            &hop,
+            &packet.get_source(),
+            program_args.icmp6_error_size,
+        )
+    });
+    Some(())
+}
+
+/// Build an IPv6 packet in response to the provided one at the end of `out`
 ///
 /// The generated response packet will have most of it's relevant data extracted from `request`
 /// except for it's own source address which is provided via `src_address`.
-/// It will also have the given `data` as it's paylaod.
+/// Its payload is written directly behind its header by `write_data`.
 fn build_ipv6_response(
+    out: &mut Vec<u8>,
     request: &Ipv6Packet,
     src_address: Ipv6Addr,
-    data: Vec<u8>,
     hop_limit: Option<u8>,
-) -> Vec<u8> {
-    let mut response = vec![0; MutableIpv6Packet::minimum_packet_size() + data.len()];
-
-    let mut packet = MutableIpv6Packet::new(&mut response)
-        .expect("Could not construct IPv6 packet with vector as buffer");
-    packet.populate(&Ipv6 {
-        version: 6,
-        traffic_class: 0,
-        flow_label: 0,
-        payload_length: data.len() as u16,
-        next_header: IpNextHeaderProtocols::Icmpv6,
-        hop_limit: hop_limit.unwrap_or(request.get_hop_limit() - 1),
-        source: src_address,
-        destination: request.get_source(),
-        payload: data,
-    });
+    write_data: impl FnOnce(&mut Vec<u8>),
+) {
+    let start = out.len();
+    let header_length = MutableIpv6Packet::minimum_packet_size();
+    out.resize(start + header_length, 0);
+    write_data(out);
+    let payload_length = out.len() - start - header_length;
+
+    let mut packet = MutableIpv6Packet::new(&mut out[start..])
+        .expect("Could not construct IPv6 packet in output buffer");
+    packet.set_version(6);
+    packet.set_payload_length(payload_length as u16);
+    packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    packet.set_hop_limit(hop_limit.unwrap_or(request.get_hop_limit().saturating_sub(1)));
+    packet.set_source(src_address);
+    packet.set_destination(request.get_source());
+}
+
+#[cfg(test)]
+#[test]
+fn test_segment_routed_packet_does_not_leave_virtual_network() {
+    use super::Responses;
+    use pnet_packet::ip::IpNextHeaderProtocols;
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "2001:db8::",
+        "--nhosts",
+        "10",
+    ]);
+    let state = State::new(&args, 1500);
+    let external: Ipv6Addr = "2001:db8:ffff::1".parse().unwrap();
+    let virtual_host: Ipv6Addr = "2001:db8::2".parse().unwrap();
+
+    // a UDP probe that visits the virtual host ::2 and then an external final segment
+    let build = |hop_limit: u8| {
+        let mut buffer = vec![0; 40];
+        buffer[0] = 6 << 4;
+        buffer[4..6].copy_from_slice(&48u16.to_be_bytes());
+        buffer[6] = IpNextHeaderProtocols::Ipv6Route.0;
+        buffer[7] = hop_limit;
+        buffer[8..24].copy_from_slice(&"2001:db8:1::1".parse::<Ipv6Addr>().unwrap().octets());
+        buffer[24..40].copy_from_slice(&virtual_host.octets());
+        buffer.extend_from_slice(&[IpNextHeaderProtocols::Udp.0, 4, 4, 1, 1, 0, 0, 0]);
+        buffer.extend_from_slice(&external.octets());
+        buffer.extend_from_slice(&virtual_host.octets());
+        buffer.extend_from_slice(&[0x80, 0, 0x82, 0x9a, 0, 8, 0, 0]);
+        buffer
+    };
+
+    let mut responses = Responses::new();
+    super::handle(&args, &state, &build(64), &mut responses);
+    assert_eq!(responses.iter().count(), 0);
 
-    response
+    // the virtual hops on the way are still visible
+    super::handle(&args, &state, &build(1), &mut responses);
+    let response = responses.iter().next().unwrap();
+    let response = Ipv6Packet::new(response).unwrap();
+    assert_eq!(response.get_next_header(), IpNextHeaderProtocols::Icmpv6);
+    assert_eq!(response.payload()[0], 3);
 }
@@ -0,0 +1,355 @@
+//! Walking of the IPv6 extension header chain
+//!
+//! An IPv6 packet may carry any number of extension headers between its fixed header and the
+//! actual upper-layer protocol (RFC 8200 section 4).
+//! Before a packet can be handled, this chain needs to be walked to find out which upper-layer
+//! protocol is really carried and where its header starts.
+
+use log::trace;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::Packet;
+
+/// Option type of the *Pad1* option
+const OPTION_PAD1: u8 = 0;
+/// Option type of the *PadN* option
+const OPTION_PADN: u8 = 1;
+/// Option type of the *Router Alert* option (RFC 2711)
+const OPTION_ROUTER_ALERT: u8 = 5;
+
+/// Routing type of the Segment Routing header (RFC 8754)
+pub const ROUTING_TYPE_SEGMENT_ROUTING: u8 = 4;
+
+/// ICMPv6 parameter problem code for an erroneous header field
+pub const PARAMETER_PROBLEM_ERRONEOUS_HEADER: u8 = 0;
+/// ICMPv6 parameter problem code for an unrecognized next header type
+pub const PARAMETER_PROBLEM_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+/// ICMPv6 parameter problem code for an unrecognized IPv6 option
+pub const PARAMETER_PROBLEM_UNRECOGNIZED_OPTION: u8 = 2;
+
+/// Information about a Fragment extension header
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FragmentHeader {
+    /// Offset of the fragment's data in 8-octet units
+    pub offset: u16,
+    pub more_fragments: bool,
+    pub identification: u32,
+    /// Offset of the Fragment header relative to the start of the IPv6 payload
+    pub header_offset: usize,
+    /// Offset of the Next Header field that announces the Fragment header relative to the start
+    /// of the IPv6 packet
+    pub next_header_field: usize,
+}
+
+/// What needs to be done with a packet whose extension headers could not be processed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProblemAction {
+    /// Silently drop the packet
+    Discard,
+    /// Drop the packet and send an ICMPv6 parameter problem message.
+    ///
+    /// `pointer` is the offset of the offending octet from the start of the IPv6 packet.
+    ParameterProblem { code: u8, pointer: u32 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Problem {
+    pub action: ProblemAction,
+    /// Whether the problem was found in a Hop-by-Hop Options header which means that it needs to
+    /// be handled by every hop instead of only the final destination
+    pub in_hop_by_hop: bool,
+}
+
+/// The result of walking through the extension header chain of an IPv6 packet
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ExtensionHeaders {
+    /// Protocol of the header that follows the extension header chain
+    pub upper_layer_protocol: IpNextHeaderProtocol,
+    /// Offset of the upper-layer header relative to the start of the IPv6 payload
+    pub upper_layer_offset: usize,
+    pub fragment: Option<FragmentHeader>,
+    /// Offset of a Segment Routing header relative to the start of the IPv6 payload
+    pub segment_routing_header: Option<usize>,
+    /// The first problem that was encountered while walking the chain.
+    ///
+    /// If this is set, walking stopped at the offending header.
+    pub problem: Option<Problem>,
+}
+
+impl ExtensionHeaders {
+    /// Whether this packet is a fragment that does not contain the upper-layer header
+    pub fn is_non_initial_fragment(&self) -> bool {
+        matches!(self.fragment, Some(fragment) if fragment.offset != 0)
+    }
+
+    /// The upper-layer part of `packet`'s payload
+    pub fn upper_layer_payload<'p>(&self, packet: &'p Ipv6Packet) -> &'p [u8] {
+        packet
+            .payload()
+            .get(self.upper_layer_offset..)
+            .unwrap_or_default()
+    }
+}
+
+/// Walk through all extension headers of `packet`.
+pub fn parse_extension_headers(packet: &Ipv6Packet) -> ExtensionHeaders {
+    let payload = packet.payload();
+    let mut result = ExtensionHeaders {
+        upper_layer_protocol: packet.get_next_header(),
+        upper_layer_offset: 0,
+        fragment: None,
+        segment_routing_header: None,
+        problem: None,
+    };
+    // pointer to the Next Header field that announced the header currently being looked at
+    let mut next_header_pointer = 6;
+
+    loop {
+        let offset = result.upper_layer_offset;
+        let header = &payload[offset.min(payload.len())..];
+        let header_len = match result.upper_layer_protocol {
+            IpNextHeaderProtocols::Hopopt | IpNextHeaderProtocols::Ipv6Opts => {
+                let in_hop_by_hop = result.upper_layer_protocol == IpNextHeaderProtocols::Hopopt;
+                // Hop-by-Hop Options are only allowed immediately after the IPv6 header
+                if in_hop_by_hop && offset != 0 {
+                    result.problem = Some(Problem {
+                        action: ProblemAction::ParameterProblem {
+                            code: PARAMETER_PROBLEM_UNRECOGNIZED_NEXT_HEADER,
+                            pointer: next_header_pointer,
+                        },
+                        in_hop_by_hop: false,
+                    });
+                    break;
+                }
+                let len = match extension_len(header, 8) {
+                    None => {
+                        result.problem = Some(truncated(in_hop_by_hop));
+                        break;
+                    }
+                    Some(len) => len,
+                };
+                if let Some(action) =
+                    process_options(packet, &header[2..len], offset + 2, in_hop_by_hop)
+                {
+                    result.problem = Some(Problem {
+                        action,
+                        in_hop_by_hop,
+                    });
+                    break;
+                }
+                len
+            }
+            IpNextHeaderProtocols::Ipv6Route => {
+                let len = match extension_len(header, 8) {
+                    None => {
+                        result.problem = Some(truncated(false));
+                        break;
+                    }
+                    Some(len) => len,
+                };
+                let routing_type = header[2];
+                let segments_left = header[3];
+                if routing_type == ROUTING_TYPE_SEGMENT_ROUTING {
+                    // segment routing headers are processed separately once the packet reaches
+                    // one of its segment endpoints but their segment list needs to be sane
+                    let last_entry = header[4] as usize;
+                    if segments_left as usize > last_entry + 1 || len < 8 + (last_entry + 1) * 16 {
+                        result.problem = Some(Problem {
+                            action: ProblemAction::ParameterProblem {
+                                code: PARAMETER_PROBLEM_ERRONEOUS_HEADER,
+                                pointer: pointer_to(offset + 3),
+                            },
+                            in_hop_by_hop: false,
+                        });
+                        break;
+                    }
+                    result.segment_routing_header = Some(offset);
+                }
+                // other routing types are not supported so their headers can only be ignored if
+                // they have already been fully processed (RFC 8200 section 4.4)
+                else if segments_left != 0 {
+                    result.problem = Some(Problem {
+                        action: ProblemAction::ParameterProblem {
+                            code: PARAMETER_PROBLEM_ERRONEOUS_HEADER,
+                            pointer: pointer_to(offset + 2),
+                        },
+                        in_hop_by_hop: false,
+                    });
+                    break;
+                }
+                len
+            }
+            IpNextHeaderProtocols::Ipv6Frag => {
+                if header.len() < 8 {
+                    result.problem = Some(truncated(false));
+                    break;
+                }
+                let offset_with_flags = u16::from_be_bytes([header[2], header[3]]);
+                let fragment = FragmentHeader {
+                    offset: offset_with_flags >> 3,
+                    more_fragments: offset_with_flags & 0b1 != 0,
+                    identification: u32::from_be_bytes([
+                        header[4], header[5], header[6], header[7],
+                    ]),
+                    header_offset: offset,
+                    next_header_field: next_header_pointer as usize,
+                };
+                result.fragment = Some(fragment);
+                // non-initial fragments don't contain any further headers
+                if fragment.offset != 0 {
+                    result.upper_layer_protocol = IpNextHeaderProtocol(header[0]);
+                    result.upper_layer_offset += 8;
+                    break;
+                }
+                8
+            }
+            IpNextHeaderProtocols::Ah => {
+                if header.len() < 2 || header.len() < (header[1] as usize + 2) * 4 {
+                    result.problem = Some(truncated(false));
+                    break;
+                }
+                (header[1] as usize + 2) * 4
+            }
+            // everything else is either an upper-layer protocol or an extension header whose
+            // contents cannot be interpreted (e.g. ESP)
+            _ => break,
+        };
+
+        result.upper_layer_protocol = IpNextHeaderProtocol(header[0]);
+        result.upper_layer_offset += header_len;
+        next_header_pointer = pointer_to(offset);
+    }
+
+    trace!("Walked IPv6 extension header chain [result={:?}]", result);
+    result
+}
+
+/// Total length of an extension header that encodes its length in units of `unit` octets
+/// (not including the first `unit` octets) in its second octet.
+///
+/// `None` is returned if the header does not fit into `header`.
+fn extension_len(header: &[u8], unit: usize) -> Option<usize> {
+    let len = (*header.get(1)? as usize + 1) * unit;
+    if header.len() < len {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// Process the TLV encoded options of a Hop-by-Hop or Destination Options header.
+///
+/// `options_offset` is the offset of `options` relative to the start of the IPv6 payload.
+/// Unrecognized options are handled according to the two high-order bits of their type as
+/// described by RFC 8200 section 4.2.
+fn process_options(
+    packet: &Ipv6Packet,
+    options: &[u8],
+    options_offset: usize,
+    in_hop_by_hop: bool,
+) -> Option<ProblemAction> {
+    let mut i = 0;
+    while i < options.len() {
+        let option_type = options[i];
+        if option_type == OPTION_PAD1 {
+            i += 1;
+            continue;
+        }
+        let option_len = match options.get(i + 1) {
+            None => return Some(ProblemAction::Discard),
+            Some(len) => *len as usize + 2,
+        };
+        if i + option_len > options.len() {
+            return Some(ProblemAction::Discard);
+        }
+
+        let is_known =
+            option_type == OPTION_PADN || (in_hop_by_hop && option_type == OPTION_ROUTER_ALERT);
+        if !is_known {
+            let parameter_problem = ProblemAction::ParameterProblem {
+                code: PARAMETER_PROBLEM_UNRECOGNIZED_OPTION,
+                pointer: pointer_to(options_offset + i),
+            };
+            match option_type >> 6 {
+                0b00 => {}
+                0b01 => return Some(ProblemAction::Discard),
+                0b10 => return Some(parameter_problem),
+                _ => {
+                    return if packet.get_destination().is_multicast() {
+                        Some(ProblemAction::Discard)
+                    } else {
+                        Some(parameter_problem)
+                    }
+                }
+            }
+        }
+
+        i += option_len;
+    }
+    None
+}
+
+fn truncated(in_hop_by_hop: bool) -> Problem {
+    Problem {
+        action: ProblemAction::Discard,
+        in_hop_by_hop,
+    }
+}
+
+/// Convert an offset relative to the IPv6 payload into a parameter problem pointer which is
+/// relative to the start of the IPv6 packet
+fn pointer_to(payload_offset: usize) -> u32 {
+    (MutableIpv6Packet::minimum_packet_size() + payload_offset) as u32
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_extension_headers() {
+    let build = |next_header: u8, extension_headers: &[u8]| {
+        let mut buffer = vec![0; 40];
+        buffer[0] = 6 << 4;
+        buffer[4..6].copy_from_slice(&(extension_headers.len() as u16 + 8).to_be_bytes());
+        buffer[6] = next_header;
+        buffer[7] = 64;
+        buffer.extend_from_slice(extension_headers);
+        // an ICMPv6 echo request without any data
+        buffer.extend_from_slice(&[128, 0, 0, 0, 0, 0, 0, 0]);
+        buffer
+    };
+
+    // Hop-by-Hop options with a router alert followed by Destination options with padding
+    let packet = build(0, &[60, 0, 5, 2, 0, 0, 1, 0, 58, 0, 1, 4, 0, 0, 0, 0]);
+    let result = parse_extension_headers(&Ipv6Packet::new(&packet).unwrap());
+    assert_eq!(result.upper_layer_protocol, IpNextHeaderProtocols::Icmpv6);
+    assert_eq!(result.upper_layer_offset, 16);
+    assert_eq!(result.problem, None);
+
+    // Destination options with an unknown option that requires a parameter problem
+    let packet = build(60, &[58, 0, 0b1000_0001, 4, 0, 0, 0, 0]);
+    let result = parse_extension_headers(&Ipv6Packet::new(&packet).unwrap());
+    assert_eq!(
+        result.problem,
+        Some(Problem {
+            action: ProblemAction::ParameterProblem {
+                code: PARAMETER_PROBLEM_UNRECOGNIZED_OPTION,
+                pointer: 42
+            },
+            in_hop_by_hop: false,
+        })
+    );
+
+    // a non-initial fragment
+    let packet = build(44, &[17, 0, 0, 0b1000_1001, 0, 0, 0, 1]);
+    let result = parse_extension_headers(&Ipv6Packet::new(&packet).unwrap());
+    assert_eq!(
+        result.fragment,
+        Some(FragmentHeader {
+            offset: 17,
+            more_fragments: true,
+            identification: 1,
+            header_offset: 0,
+            next_header_field: 6,
+        })
+    );
+    assert!(result.is_non_initial_fragment());
+}
@@ -1,16 +1,318 @@
 use crate::argparse::Arguments;
+use crate::ip_addrs::Prefix;
+use crate::stats::STATS;
 use log::{debug, trace, warn};
+use pnet_packet::ip::IpNextHeaderProtocol;
 use pnet_packet::ipv4::Ipv4Packet;
 use pnet_packet::ipv6::Ipv6Packet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Instant;
 
+mod eligibility;
+mod ethernet;
+mod forwarding;
+mod fragmentation;
 mod icmp;
 mod icmp6;
 mod ipv4;
+mod ipv4_options;
 mod ipv6;
+mod ipv6_extensions;
+mod reassembly;
+mod srv6;
 
-/// Handle generic incoming bytes that were received from the wire and optionally generate a
-/// response that should be written back to the wire.
-pub fn handle(program_args: &Arguments, buffer: &[u8]) -> Option<Vec<u8>> {
+/// MTU of virtual links that are not backed by a TUN device if none is configured
+pub const DEFAULT_MTU: usize = 1500;
+
+/// TTL of responses to packets that are answered on their way to a real address
+const REMOTE_RESPONSE_TTL: u8 = 64;
+
+/// Largest reassembled IPv4 packet, whose total length includes its header
+const MAX_REASSEMBLED_LEN4: usize = u16::MAX as usize;
+
+/// Largest header and data of a fragmented IPv6 packet, whose payload length excludes the
+/// fixed header and whose Fragment header is removed when it is reassembled
+const MAX_REASSEMBLED_LEN6: usize = 40 + 8 + u16::MAX as usize;
+
+/// Identifies the IPv4 packet that a fragment belongs to (RFC 791)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct FragmentKey4 {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    identification: u16,
+}
+
+/// Identifies the IPv6 packet that a fragment belongs to (RFC 8200 section 4.5)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct FragmentKey6 {
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    identification: u32,
+}
+
+/// State that is kept by the packet handlers of one device between individual packets
+///
+/// It is shared by the workers of all queues of that device.
+#[derive(Debug)]
+pub struct State {
+    /// MTU of the link that responses are written to
+    mtu: usize,
+    /// The networks that packets are accepted for, or all if empty
+    networks: Vec<Prefix>,
+    /// The neighbors on the Ethernet segment if packets are read from a TAP device
+    ethernet: Option<ethernet::Neighbors>,
+    /// The connections of clients whose packets are forwarded to a backend
+    translations: forwarding::Translations,
+    reassembly4: Mutex<reassembly::Reassembler<FragmentKey4>>,
+    reassembly6: Mutex<reassembly::Reassembler<FragmentKey6>>,
+}
+
+impl State {
+    pub fn new(program_args: &Arguments, mtu: usize) -> Self {
+        Self {
+            mtu,
+            networks: Vec::new(),
+            ethernet: None,
+            translations: forwarding::Translations::default(),
+            reassembly4: Mutex::new(reassembly::Reassembler::new(
+                program_args.reassembly_timeout,
+                program_args.reassembly_memory,
+                MAX_REASSEMBLED_LEN4,
+            )),
+            reassembly6: Mutex::new(reassembly::Reassembler::new(
+                program_args.reassembly_timeout,
+                program_args.reassembly_memory,
+                MAX_REASSEMBLED_LEN6,
+            )),
+        }
+    }
+
+    /// Only accept packets for `networks`, e.g. because the device only has routes for them.
+    ///
+    /// A device can serve several IPv4 and IPv6 networks at once.
+    pub fn with_networks(mut self, networks: Vec<Prefix>) -> Self {
+        self.networks = networks;
+        self
+    }
+
+    /// Read and write Ethernet frames of a TAP device instead of plain IP packets
+    pub fn with_ethernet(mut self) -> Self {
+        self.ethernet = Some(ethernet::Neighbors::default());
+        self
+    }
+
+    /// MTU of the link that this state's packets are read from and written to
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Size of the largest packet including its link layer header that fits through the link
+    pub fn max_frame_len(&self) -> usize {
+        match self.ethernet {
+            None => self.mtu,
+            Some(_) => self.mtu + ethernet::HEADER_LEN,
+        }
+    }
+
+    /// Whether packets to `destination` are handled
+    fn serves(&self, destination: IpAddr) -> bool {
+        self.networks.is_empty()
+            || self
+                .networks
+                .iter()
+                .any(|network| network.contains(&destination))
+    }
+}
+
+/// Reusable buffer that responses are written into before they are sent
+///
+/// Every worker owns one so that no memory needs to be allocated for responses once the buffer
+/// has grown to fit them.
+#[derive(Debug, Default)]
+pub struct Responses {
+    buffer: Vec<u8>,
+    /// End of each response in `buffer`
+    ends: Vec<usize>,
+    /// Copy of the responses that are being fragmented or framed
+    scratch: Vec<u8>,
+}
+
+impl Responses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget all responses while keeping the memory that was allocated for them
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.ends.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let mut start = 0;
+        self.ends.iter().map(move |&end| {
+            let response = &self.buffer[start..end];
+            start = end;
+            response
+        })
+    }
+
+    /// Put a link layer header that `write_header` appends to `out` in front of every response
+    /// from the `first`th on and drop the responses for which it returns `None`
+    fn prepend_headers(
+        &mut self,
+        first: usize,
+        mut write_header: impl FnMut(&[u8], &mut Vec<u8>) -> Option<()>,
+    ) {
+        if first >= self.ends.len() {
+            return;
+        }
+        let start = first.checked_sub(1).map_or(0, |i| self.ends[i]);
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&self.buffer[start..]);
+        self.buffer.truncate(start);
+        // the framed responses are written over the ends of the packets that were read already
+        let mut n_framed = first;
+        let mut packet_start = 0;
+        for i in first..self.ends.len() {
+            let packet = &self.scratch[packet_start..self.ends[i] - start];
+            packet_start = self.ends[i] - start;
+            let header_start = self.buffer.len();
+            if write_header(packet, &mut self.buffer).is_none() {
+                self.buffer.truncate(header_start);
+                continue;
+            }
+            self.buffer.extend_from_slice(packet);
+            self.ends[n_framed] = self.buffer.len();
+            n_framed += 1;
+        }
+        self.ends.truncate(n_framed);
+    }
+
+    /// Let `write` append a response to the buffer and fragment it if it is larger than `mtu`
+    fn push_with(&mut self, mtu: usize, write: impl FnOnce(&mut Vec<u8>) -> Option<()>) {
+        let start = self.buffer.len();
+        if write(&mut self.buffer).is_none() {
+            self.buffer.truncate(start);
+            return;
+        }
+
+        if self.buffer.len() - start <= mtu {
+            self.ends.push(self.buffer.len());
+            return;
+        }
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&self.buffer[start..]);
+        self.buffer.truncate(start);
+        if (self.scratch[0] >> 4) == 0b0100 {
+            fragmentation::fragment_ipv4(&self.scratch, mtu, &mut self.buffer, &mut self.ends);
+        } else {
+            fragmentation::fragment_ipv6(&self.scratch, mtu, &mut self.buffer, &mut self.ends);
+        }
+    }
+}
+
+/// Handle generic incoming bytes that were received from the wire and add the packets that
+/// should be written back to the wire in response to `responses`.
+///
+/// Responses larger than the link's MTU are fragmented.
+pub fn handle(program_args: &Arguments, state: &State, buffer: &[u8], responses: &mut Responses) {
+    match &state.ethernet {
+        None => responses.push_with(state.mtu, |out| {
+            handle_packet(program_args, state, buffer, out)
+        }),
+        Some(neighbors) => {
+            ethernet::handle_frame(program_args, state, neighbors, buffer, responses)
+        }
+    }
+}
+
+/// Write a time exceeded response from the virtual hop `hop` to the IP packet in `buffer` to
+/// the end of `out`, e.g. for packets that are on their way to a real address instead of a
+/// virtual one.
+///
+/// Such packets were not forwarded by the kernel, so nothing is known about how far away their
+/// sender is and the response always gets a TTL of `REMOTE_RESPONSE_TTL`.
+/// Returns `None` if no response was written.
+pub fn build_time_exceeded(
+    program_args: &Arguments,
+    buffer: &[u8],
+    hop: IpAddr,
+    out: &mut Vec<u8>,
+) -> Option<()> {
+    match hop {
+        IpAddr::V4(hop) => {
+            let packet = Ipv4Packet::new(buffer)?;
+            ipv4::build_time_exceeded(program_args, &packet, hop, Some(REMOTE_RESPONSE_TTL), out)
+        }
+        IpAddr::V6(hop) => {
+            let packet = Ipv6Packet::new(buffer)?;
+            let extension_headers = ipv6_extensions::parse_extension_headers(&packet);
+            ipv6::build_time_exceeded(
+                program_args,
+                &packet,
+                &extension_headers,
+                hop,
+                REMOTE_RESPONSE_TTL,
+                out,
+            )
+        }
+    }
+}
+
+/// The IP packet in something that was read from or written to the link, which is `None` for
+/// Ethernet frames that carry something else
+pub fn ip_packet<'a>(state: &State, buffer: &'a [u8]) -> Option<&'a [u8]> {
+    match state.ethernet {
+        None => Some(buffer),
+        Some(_) => ethernet::ip_packet_of_frame(buffer),
+    }
+}
+
+/// Handle everything that is due because time has passed, like giving up on the reassembly of
+/// incomplete packets, and add the packets that should be written to the wire because of it to
+/// `responses`.
+pub fn handle_timeouts(program_args: &Arguments, state: &State, responses: &mut Responses) {
+    let first = responses.ends.len();
+    let now = Instant::now();
+    let expired4 = state
+        .reassembly4
+        .lock()
+        .expect("IPv4 reassembly state is poisoned")
+        .expire(now);
+    let expired6 = state
+        .reassembly6
+        .lock()
+        .expect("IPv6 reassembly state is poisoned")
+        .expire(now);
+
+    for incomplete in expired4 {
+        responses.push_with(state.mtu, |out| {
+            ipv4::handle_reassembly_timeout(program_args, incomplete, out)
+        });
+    }
+    for incomplete in expired6 {
+        responses.push_with(state.mtu, |out| {
+            ipv6::handle_reassembly_timeout(program_args, incomplete, out)
+        });
+    }
+    if let Some(neighbors) = &state.ethernet {
+        ethernet::frame_responses(neighbors, responses, first, None);
+    }
+}
+
+/// Handle a single packet and optionally write a response that is not yet fragmented to the end
+/// of `out`.
+///
+/// Returns `None` if no response was written.
+fn handle_packet(
+    program_args: &Arguments,
+    state: &State,
+    buffer: &[u8],
+    out: &mut Vec<u8>,
+) -> Option<()> {
     // peek into the packet and see if its ip header defines it as IPv4
     if (buffer[0] >> 4) == 0b0100 {
         match Ipv4Packet::new(buffer) {
@@ -18,9 +320,30 @@ pub fn handle(program_args: &Arguments, buffer: &[u8]) -> Option<Vec<u8>> {
                 warn!("Could not parse incoming packet as IPv4 packet even though header byte matched");
                 None
             }
+            Some(packet) if packet.get_total_length() as usize > buffer.len() => {
+                debug!(
+                    "Dropping truncated IPv4 packet [len={}, total_length={}]",
+                    buffer.len(),
+                    packet.get_total_length()
+                );
+                STATS.tun_reads.truncated.increment();
+                None
+            }
+            Some(packet) if !state.serves(packet.get_destination().into()) => {
+                drop_unknown_destination(packet.get_destination().into())
+            }
+            Some(packet)
+                if forwarding::is_from_backend(
+                    program_args,
+                    packet.get_source().into(),
+                    packet.get_destination().into(),
+                ) =>
+            {
+                forwarding::translate_reply(state, buffer, out)
+            }
             Some(packet) => {
                 trace!("Recognized and parsed IPv4 packet [packet={:?}]", packet);
-                ipv4::handle_ipv4_packet(program_args, &packet)
+                ipv4::handle_ipv4_packet(program_args, state, &packet, out)
             }
         }
     }
@@ -31,9 +354,33 @@ pub fn handle(program_args: &Arguments, buffer: &[u8]) -> Option<Vec<u8>> {
                 warn!("Could not parse incoming packet as IPv6 packet even though header byte matched");
                 None
             }
+            Some(packet)
+                if Ipv6Packet::minimum_packet_size() + packet.get_payload_length() as usize
+                    > buffer.len() =>
+            {
+                debug!(
+                    "Dropping truncated IPv6 packet [len={}, payload_length={}]",
+                    buffer.len(),
+                    packet.get_payload_length()
+                );
+                STATS.tun_reads.truncated.increment();
+                None
+            }
+            Some(packet) if !state.serves(packet.get_destination().into()) => {
+                drop_unknown_destination(packet.get_destination().into())
+            }
+            Some(packet)
+                if forwarding::is_from_backend(
+                    program_args,
+                    packet.get_source().into(),
+                    packet.get_destination().into(),
+                ) =>
+            {
+                forwarding::translate_reply(state, buffer, out)
+            }
             Some(packet) => {
                 trace!("Recognized and parsed IPv6 packet [packet={:?}]", packet);
-                ipv6::handle_ipv6_packet(program_args, &packet)
+                ipv6::handle_ipv6_packet(program_args, state, &packet, out)
             }
         }
     } else {
@@ -46,3 +393,156 @@ pub fn handle(program_args: &Arguments, buffer: &[u8]) -> Option<Vec<u8>> {
 
     // TODO handle IPv6 packets
 }
+
+/// Drop a packet whose destination is not part of any network of the device, e.g. the
+/// kernel's own IPv6 router solicitations on a device that only serves IPv4 networks
+fn drop_unknown_destination(destination: IpAddr) -> Option<()> {
+    debug!(
+        "Dropping packet to a destination outside of the device's networks [destination={}]",
+        destination
+    );
+    STATS.tun_reads.unknown_destination.increment();
+    None
+}
+
+/// Calculate how many bytes of an offending packet should be quoted in an ICMP error message.
+///
+/// The quote is cut short so that the whole error datagram (`overhead` bytes of IP and ICMP
+/// headers plus the quote) does not exceed `max_error_size`.
+/// It is however never shorter than the offending packet's IP header plus 8 bytes of its payload
+/// because that is what the sender needs to match the error to one of its probes.
+fn icmp_error_quote_len(
+    original_len: usize,
+    original_header_len: usize,
+    overhead: usize,
+    max_error_size: usize,
+) -> usize {
+    let min_quote_len = original_header_len + 8;
+    max_error_size
+        .saturating_sub(overhead)
+        .max(min_quote_len)
+        .min(original_len)
+}
+
+#[cfg(test)]
+#[test]
+fn test_icmp_error_quote_len() {
+    // small packets are quoted completely
+    assert_eq!(icmp_error_quote_len(60, 20, 28, 576), 60);
+    // large IPv4 packets are cut so that the error is at most 576 bytes long
+    assert_eq!(icmp_error_quote_len(1500, 20, 28, 576), 548);
+    // large IPv6 packets are cut so that the error is at most 1280 bytes long
+    assert_eq!(icmp_error_quote_len(4000, 40, 48, 1280), 1232);
+    // the IP header and 8 bytes of payload are always included
+    assert_eq!(icmp_error_quote_len(1500, 20, 28, 0), 28);
+    assert_eq!(icmp_error_quote_len(1500, 60, 28, 64), 68);
+}
+
+#[cfg(test)]
+#[test]
+fn test_handle_only_serves_networks_of_device() {
+    use std::str::FromStr;
+
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "2001:db8::",
+        "--nhosts",
+        "10",
+    ]);
+    let state =
+        State::new(&args, 1500).with_networks(vec![Prefix::from_str("10.0.0.0/28").unwrap()]);
+    let mut responses = Responses::new();
+    handle(&args, &state, &build_bench_request(false), &mut responses);
+    handle(&args, &state, &build_bench_request(true), &mut responses);
+    // only the IPv4 request was answered
+    assert_eq!(responses.iter().count(), 1);
+    assert_eq!(responses.iter().next().unwrap()[0] >> 4, 4);
+}
+
+#[cfg(test)]
+#[test]
+fn test_build_time_exceeded_has_fixed_ttl() {
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "2001:db8::",
+        "--nhosts",
+        "10",
+    ]);
+    for (ipv6, hop) in [(false, "10.0.0.1"), (true, "2001:db8::1")] {
+        // locally delivered packets may arrive with any TTL, even one that is already used up
+        for ttl in [0, 1, 5] {
+            let mut request = build_bench_request(ipv6);
+            request[if ipv6 { 7 } else { 8 }] = ttl;
+            let mut out = Vec::new();
+            build_time_exceeded(&args, &request, hop.parse().unwrap(), &mut out).unwrap();
+            let response_ttl = if ipv6 {
+                Ipv6Packet::new(&out).unwrap().get_hop_limit()
+            } else {
+                Ipv4Packet::new(&out).unwrap().get_ttl()
+            };
+            assert_eq!(response_ttl, REMOTE_RESPONSE_TTL);
+        }
+    }
+}
+
+/// Build an IPv4 ICMP echo request or IPv6 UDP probe that is sent to the 5th virtual host
+#[cfg(test)]
+fn build_bench_request(ipv6: bool) -> Vec<u8> {
+    use pnet_packet::ip::IpNextHeaderProtocols;
+    use pnet_packet::ipv4::MutableIpv4Packet;
+    use pnet_packet::ipv6::MutableIpv6Packet;
+
+    let mut request = vec![0; if ipv6 { 40 } else { 20 } + 8 + 56];
+    if ipv6 {
+        let mut packet = MutableIpv6Packet::new(&mut request).unwrap();
+        packet.set_version(6);
+        packet.set_payload_length(64);
+        packet.set_next_header(IpNextHeaderProtocols::Udp);
+        packet.set_hop_limit(64);
+        packet.set_source("2001:db8:1::1".parse().unwrap());
+        packet.set_destination("2001:db8::5".parse().unwrap());
+    } else {
+        let mut packet = MutableIpv4Packet::new(&mut request).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(84);
+        packet.set_ttl(64);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        packet.set_source(Ipv4Addr::new(192, 0, 2, 1));
+        packet.set_destination(Ipv4Addr::new(10, 0, 0, 5));
+        request[20] = 8;
+    }
+    request
+}
+
+#[cfg(test)]
+#[bench]
+fn bench_handle(b: &mut test::Bencher) {
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "2001:db8::",
+        "--nhosts",
+        "10",
+    ]);
+    let state = State::new(&args, 1500);
+    let requests = [build_bench_request(false), build_bench_request(true)];
+    let mut responses = Responses::new();
+    for request in &requests {
+        handle(&args, &state, request, &mut responses);
+    }
+    assert_eq!(responses.iter().count(), 2);
+
+    b.iter(|| {
+        responses.clear();
+        for request in &requests {
+            handle(&args, &state, test::black_box(request), &mut responses);
+        }
+        test::black_box(&responses);
+    });
+}
@@ -0,0 +1,322 @@
+//! Reassembly of fragmented IP packets
+//!
+//! Fragments are collected per original packet until all of its data has arrived.
+//! Incomplete packets are given up after a timeout and the memory that all incomplete packets
+//! may use together is limited so that a flood of fragments cannot exhaust it.
+//! Packets with overlapping fragments are discarded as a whole as required by RFC 5722.
+
+use crate::stats::STATS;
+use log::debug;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Memory that is counted for every incomplete packet in addition to its header and data, so
+/// that a flood of tiny fragments of different packets is limited as well
+const BUFFER_OVERHEAD: usize = 256;
+
+/// A packet whose fragments have all been received
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Reassembled {
+    /// Header of the first fragment
+    pub header: Vec<u8>,
+    /// Data of all fragments put back together
+    pub data: Vec<u8>,
+}
+
+/// A packet whose fragments did not all arrive in time
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Incomplete {
+    /// Header of the first fragment if it was received
+    pub header: Option<Vec<u8>>,
+    /// Data from the start of the packet up to the first missing fragment
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct FragmentBuffer {
+    created: Instant,
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    /// Sorted and non-overlapping ranges of `data` that have been received
+    received: Vec<Range<usize>>,
+    /// Length of the complete data once the last fragment has been received
+    total_len: Option<usize>,
+    /// Whether fragments overlapped, so that the packet and all of its fragments that are still
+    /// to come are discarded
+    discarded: bool,
+}
+
+impl FragmentBuffer {
+    fn memory_usage(&self) -> usize {
+        BUFFER_OVERHEAD + self.data.len() + self.header.as_ref().map_or(0, Vec::len)
+    }
+
+    fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.received
+            .iter()
+            .any(|received| received.start < range.end && range.start < received.end)
+    }
+
+    /// Free the data but keep the buffer until the timeout to drop the remaining fragments
+    fn discard(&mut self) {
+        self.header = None;
+        self.data = Vec::new();
+        self.received = Vec::new();
+        self.total_len = None;
+        self.discarded = true;
+    }
+
+    fn mark_received(&mut self, range: Range<usize>) {
+        self.received.push(range);
+        self.received.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.received.len());
+        for range in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.received = merged;
+    }
+
+    /// Length of the data that has been received contiguously from its start
+    fn contiguous_len(&self) -> usize {
+        match self.received.first() {
+            Some(range) if range.start == 0 => range.end,
+            _ => 0,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.total_len == Some(self.contiguous_len())
+    }
+}
+
+/// Collects fragments of packets identified by keys of type `K`
+#[derive(Debug)]
+pub struct Reassembler<K> {
+    buffers: HashMap<K, FragmentBuffer>,
+    timeout: Duration,
+    memory_limit: usize,
+    memory_usage: usize,
+    /// Largest length of the header of the first fragment and all data together
+    max_len: usize,
+}
+
+impl<K: Hash + Eq + Clone> Reassembler<K> {
+    pub fn new(timeout: Duration, memory_limit: usize, max_len: usize) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            timeout,
+            memory_limit,
+            memory_usage: 0,
+            max_len,
+        }
+    }
+
+    /// Add a fragment of the packet identified by `key`.
+    ///
+    /// `offset` is the position of `data` in the original packet's data, `header` needs to be
+    /// given for the first fragment only.
+    /// Once all fragments have been received, the reassembled packet is returned.
+    pub fn insert(
+        &mut self,
+        key: K,
+        now: Instant,
+        offset: usize,
+        more_fragments: bool,
+        header: Option<&[u8]>,
+        data: &[u8],
+    ) -> Option<Reassembled> {
+        let end = offset + data.len();
+        if let Some(buffer) = self.buffers.get_mut(&key) {
+            if buffer.discarded {
+                return None;
+            }
+            if buffer.overlaps(&(offset..end)) {
+                debug!(
+                    "Discarding packet with overlapping fragments [offset={}]",
+                    offset
+                );
+                STATS.reassembly.overlapping.increment();
+                self.memory_usage -= buffer.memory_usage();
+                buffer.discard();
+                self.memory_usage += buffer.memory_usage();
+                return None;
+            }
+        }
+        let existing = self.buffers.get(&key);
+        // the header of the first fragment may also arrive after the other fragments
+        let header_len = header
+            .or_else(|| existing.and_then(|buffer| buffer.header.as_deref()))
+            .map_or(0, <[u8]>::len);
+        let data_len = existing.map_or(end, |buffer| end.max(buffer.data.len()));
+        if header_len + data_len > self.max_len {
+            debug!("Dropping fragment that would exceed the maximum packet size");
+            STATS.reassembly.invalid.increment();
+            return None;
+        }
+
+        // the limit is checked before a new packet is added so that it cannot be exceeded
+        let (previous_usage, additional_usage) = match existing {
+            Some(buffer) => (
+                buffer.memory_usage(),
+                end.saturating_sub(buffer.data.len())
+                    + header
+                        .filter(|_| buffer.header.is_none())
+                        .map_or(0, <[u8]>::len),
+            ),
+            None => (0, BUFFER_OVERHEAD + end + header.map_or(0, <[u8]>::len)),
+        };
+        if self.memory_usage + additional_usage > self.memory_limit {
+            debug!("Dropping fragment because the reassembly memory limit has been reached");
+            STATS.reassembly.memory_limit_reached.increment();
+            return None;
+        }
+
+        let buffer = self
+            .buffers
+            .entry(key.clone())
+            .or_insert_with(|| FragmentBuffer {
+                created: now,
+                header: None,
+                data: Vec::new(),
+                received: Vec::new(),
+                total_len: None,
+                discarded: false,
+            });
+        if !more_fragments {
+            buffer.total_len = Some(end);
+        }
+        if buffer.data.len() < end {
+            buffer.data.resize(end, 0);
+        }
+        buffer.data[offset..end].copy_from_slice(data);
+        buffer.mark_received(offset..end);
+        if let Some(header) = header {
+            buffer.header.get_or_insert_with(|| header.to_vec());
+        }
+        self.memory_usage = self.memory_usage - previous_usage + buffer.memory_usage();
+
+        if !buffer.is_complete() {
+            return None;
+        }
+
+        let buffer = self.buffers.remove(&key)?;
+        self.memory_usage -= buffer.memory_usage();
+        STATS.reassembly.reassembled.increment();
+        Some(Reassembled {
+            header: buffer.header.unwrap_or_default(),
+            data: buffer.data,
+        })
+    }
+
+    /// Give up all packets whose fragments did not arrive within the timeout
+    pub fn expire(&mut self, now: Instant) -> Vec<Incomplete> {
+        let timeout = self.timeout;
+        let mut result = Vec::new();
+        let mut freed = 0;
+        self.buffers.retain(|_, buffer| {
+            if now.duration_since(buffer.created) < timeout {
+                return true;
+            }
+            freed += buffer.memory_usage();
+            if buffer.discarded {
+                return false;
+            }
+            let contiguous_len = buffer.contiguous_len();
+            result.push(Incomplete {
+                header: buffer.header.take(),
+                data: buffer.data[..contiguous_len].to_vec(),
+            });
+            false
+        });
+        self.memory_usage -= freed;
+        for _ in &result {
+            STATS.reassembly.timed_out.increment();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_reassembler() {
+    let start = Instant::now();
+    let mut reassembler = Reassembler::new(Duration::from_secs(30), 1024, 1024);
+
+    // fragments arriving out of order are put back together
+    assert_eq!(reassembler.insert(1, start, 8, false, None, &[3; 4]), None);
+    assert_eq!(
+        reassembler.insert(1, start, 0, true, Some(&[0xff]), &[1; 8]),
+        Some(Reassembled {
+            header: vec![0xff],
+            data: vec![1, 1, 1, 1, 1, 1, 1, 1, 3, 3, 3, 3],
+        })
+    );
+
+    // fragments exceeding the memory limit are dropped
+    assert_eq!(
+        reassembler.insert(2, start, 0, true, Some(&[0xff]), &[1; 1024]),
+        None
+    );
+    assert_eq!(
+        reassembler.insert(2, start, 0, true, Some(&[0xff]), &[1; 16]),
+        None
+    );
+
+    // incomplete packets are given up after the timeout
+    assert!(reassembler
+        .expire(start + Duration::from_secs(29))
+        .is_empty());
+    assert_eq!(
+        reassembler.expire(start + Duration::from_secs(30)),
+        vec![Incomplete {
+            header: Some(vec![0xff]),
+            data: vec![1; 16],
+        }]
+    );
+    assert_eq!(reassembler.memory_usage, 0);
+
+    // packets with overlapping fragments are discarded together with their remaining fragments
+    assert_eq!(
+        reassembler.insert(3, start, 0, true, Some(&[0xff]), &[1; 8]),
+        None
+    );
+    assert_eq!(reassembler.insert(3, start, 4, true, None, &[2; 8]), None);
+    assert_eq!(reassembler.memory_usage, BUFFER_OVERHEAD);
+    assert_eq!(reassembler.insert(3, start, 8, false, None, &[3; 4]), None);
+    assert_eq!(
+        reassembler.insert(3, start, 0, true, Some(&[0xff]), &[1; 8]),
+        None
+    );
+    assert!(reassembler
+        .expire(start + Duration::from_secs(60))
+        .is_empty());
+    assert_eq!(reassembler.memory_usage, 0);
+
+    // every incomplete packet counts towards the limit, even if its fragments are tiny
+    for key in 4..16 {
+        reassembler.insert(key, start, 0, true, None, &[1]);
+    }
+    assert_eq!(reassembler.buffers.len(), 1024 / (BUFFER_OVERHEAD + 1));
+
+    // the header of the first fragment and the data together may not exceed the maximum length
+    let mut reassembler = Reassembler::new(Duration::from_secs(30), 4096, 1024);
+    assert_eq!(
+        reassembler.insert(1, start, 4, true, None, &[1; 1012]),
+        None
+    );
+    assert_eq!(
+        reassembler.insert(1, start, 1016, false, Some(&[0xff; 8]), &[1; 4]),
+        None
+    );
+    assert!(reassembler
+        .insert(1, start, 0, true, Some(&[0xff; 4]), &[1; 4])
+        .is_none());
+    assert!(reassembler
+        .insert(1, start, 1016, false, None, &[1; 4])
+        .is_some());
+}
@@ -0,0 +1,166 @@
+//! Processing of IPv6 Segment Routing headers (RFC 8754) inside the virtual network
+//!
+//! A probe carrying a Segment Routing header visits all of its segments in order before it
+//! reaches its final destination.
+//! When these segments are virtual hosts, the probe is "routed" through the virtual network
+//! from one segment endpoint to the next which means that all virtual hosts in between show up
+//! as hops as well.
+
+use crate::ip_addrs;
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::Packet;
+use std::net::Ipv6Addr;
+
+/// No packet can traverse more hops than this because its hop limit would be exceeded first
+const MAX_HOPS: usize = u8::MAX as usize;
+
+/// One hop on the virtual path of a segment routed packet
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Hop {
+    pub address: Ipv6Addr,
+    /// Destination address of the packet when it arrives at this hop
+    pub destination: Ipv6Addr,
+    /// Segments Left value of the packet when it arrives at this hop
+    pub segments_left: u8,
+}
+
+/// What happens to a segment routed packet after it passed all of its virtual hops
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PathEnd {
+    /// The packet has visited its last segment which is its final destination
+    Delivered,
+    /// The next segment is not part of the virtual network so the packet would leave it again
+    Exits,
+}
+
+/// The virtual path a segment routed packet takes
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VirtualPath {
+    pub hops: Vec<Hop>,
+    pub end: PathEnd,
+    /// Destination address of the packet after the last hop
+    pub destination: Ipv6Addr,
+    /// Segments Left value of the packet after the last hop
+    pub segments_left: u8,
+}
+
+/// Calculate the path that `packet` takes through the virtual network.
+///
+/// `srh_offset` is the offset of the packet's Segment Routing header relative to its payload
+/// and `prefix_length` the length of the virtual network's prefix.
+pub fn virtual_path(prefix_length: usize, packet: &Ipv6Packet, srh_offset: usize) -> VirtualPath {
+    let srh = &packet.payload()[srh_offset..];
+    let network = packet.get_destination();
+    let mut result = VirtualPath {
+        hops: Vec::new(),
+        end: PathEnd::Delivered,
+        destination: network,
+        segments_left: srh[3],
+    };
+
+    // host 0 is outside of the virtual network from where the packet enters it
+    let mut position = 0;
+    loop {
+        let target = ip_addrs::get_host_number_in_network6(prefix_length, &result.destination);
+        while position != target && result.hops.len() < MAX_HOPS {
+            position = if target > position {
+                position + 1
+            } else {
+                position - 1
+            };
+            result.hops.push(Hop {
+                address: ip_addrs::get_nth_address_in_network6(
+                    position as usize,
+                    prefix_length,
+                    &network,
+                ),
+                destination: result.destination,
+                segments_left: result.segments_left,
+            });
+        }
+
+        if result.segments_left == 0 {
+            result.end = PathEnd::Delivered;
+            break;
+        }
+
+        // the segment endpoint activates the next segment
+        result.segments_left -= 1;
+        result.destination = segment(srh, result.segments_left);
+        if !ip_addrs::is_same_network6(prefix_length, &result.destination, &network) {
+            result.end = PathEnd::Exits;
+            break;
+        }
+    }
+
+    result
+}
+
+/// Get the segment at `index` from the segment list of a Segment Routing `header`
+fn segment(header: &[u8], index: u8) -> Ipv6Addr {
+    let start = 8 + index as usize * 16;
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&header[start..start + 16]);
+    Ipv6Addr::from(octets)
+}
+
+/// Copy `packet` to the end of `out` as it looks after its Segment Routing header has been
+/// processed up to the point where `destination` is the active segment and `segments_left`
+/// segments remain.
+pub fn rewrite(
+    out: &mut Vec<u8>,
+    packet: &Ipv6Packet,
+    srh_offset: usize,
+    destination: Ipv6Addr,
+    segments_left: u8,
+    hop_limit: u8,
+) {
+    let start = out.len();
+    out.extend_from_slice(packet.packet());
+    let mut rewritten = MutableIpv6Packet::new(&mut out[start..])
+        .expect("Could not create IPv6 packet from copy of an IPv6 packet");
+    rewritten.set_destination(destination);
+    rewritten.set_hop_limit(hop_limit);
+    out[start + MutableIpv6Packet::minimum_packet_size() + srh_offset + 3] = segments_left;
+}
+
+#[cfg(test)]
+#[test]
+fn test_virtual_path() {
+    use pnet_packet::ip::IpNextHeaderProtocols;
+
+    let host = |n: u16| Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n);
+
+    // segments are stored in reverse order so the probe visits ::4, then ::2 and ends at ::3
+    let mut buffer = vec![0; 40];
+    buffer[0] = 6 << 4;
+    buffer[4..6].copy_from_slice(&56u16.to_be_bytes());
+    buffer[6] = IpNextHeaderProtocols::Ipv6Route.0;
+    buffer[7] = 64;
+    buffer[24..40].copy_from_slice(&host(4).octets());
+    buffer.extend_from_slice(&[59, 6, 4, 2, 2, 0, 0, 0]);
+    for segment in [host(3), host(2), host(4)] {
+        buffer.extend_from_slice(&segment.octets());
+    }
+    let packet = Ipv6Packet::new(&buffer).unwrap();
+
+    let path = virtual_path(120, &packet, 0);
+    let addresses: Vec<Ipv6Addr> = path.hops.iter().map(|hop| hop.address).collect();
+    assert_eq!(
+        addresses,
+        vec![
+            host(1),
+            host(2),
+            host(3),
+            host(4),
+            host(3),
+            host(2),
+            host(3)
+        ]
+    );
+    assert_eq!(path.hops[4].destination, host(2));
+    assert_eq!(path.hops[4].segments_left, 1);
+    assert_eq!(path.end, PathEnd::Delivered);
+    assert_eq!(path.destination, host(3));
+    assert_eq!(path.segments_left, 0);
+}
@@ -0,0 +1,587 @@
+//! Reading and writing of packet captures in the pcap and pcapng formats
+//!
+//! Only as much of both formats is implemented as is needed to replay captured probes and to
+//! record the packets that are handled.
+//! See <https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-01.html> and
+//! <https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html>.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Link type of captures that contain raw IPv4 and IPv6 packets without any link layer header
+pub const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_IF_TSRESOL: u16 = 9;
+const PCAPNG_OPTION_EPB_FLAGS: u16 = 2;
+/// `if_tsresol` value of nanosecond timestamps
+const PCAPNG_NANOSECONDS: u8 = 9;
+
+/// The largest packet that is written to captures
+const SNAPLEN: u32 = 65535;
+
+/// A packet read from a capture file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CapturedPacket<'a> {
+    /// Time since the UNIX epoch at which the packet was captured
+    pub timestamp: Duration,
+    pub link_type: u16,
+    /// The packet including its link layer header
+    pub data: &'a [u8],
+}
+
+impl<'a> CapturedPacket<'a> {
+    /// Get the IP packet that this packet carries by stripping its link layer header.
+    ///
+    /// `None` is returned for packets of unsupported link types and packets that do not carry
+    /// IPv4 or IPv6.
+    pub fn ip_packet(&self) -> Option<&'a [u8]> {
+        let data = self.data;
+        let (ethertype, payload) = match self.link_type {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => return Some(data),
+            LINKTYPE_ETHERNET => {
+                // skip over all VLAN tags
+                let mut offset = 12;
+                while let ETHERTYPE_VLAN | ETHERTYPE_QINQ = be_u16_at(data, offset)? {
+                    offset += 4;
+                }
+                (be_u16_at(data, offset)?, data.get(offset + 2..)?)
+            }
+            LINKTYPE_LINUX_SLL => (be_u16_at(data, 14)?, data.get(16..)?),
+            LINKTYPE_LINUX_SLL2 => (be_u16_at(data, 0)?, data.get(20..)?),
+            _ => return None,
+        };
+        match ethertype {
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(payload),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Interface {
+    link_type: u16,
+    /// Number of timestamp units per second
+    timestamp_resolution: u64,
+}
+
+#[derive(Debug)]
+enum Format {
+    Pcap {
+        link_type: u16,
+        /// Number of nanoseconds per unit of the fractional timestamp
+        nanos_per_unit: u32,
+    },
+    Pcapng {
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// Reader of the packets in a pcap or pcapng capture that has been loaded into memory
+#[derive(Debug)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    big_endian: bool,
+    format: Format,
+}
+
+impl<'a> Reader<'a> {
+    /// Detect the format of the capture in `data` and read its header
+    pub fn new(data: &'a [u8]) -> io::Result<Self> {
+        let magic_le = u32_at(data, 0, false)?;
+        let magic_be = u32_at(data, 0, true)?;
+
+        if magic_le == PCAPNG_SECTION_HEADER {
+            return Ok(Self {
+                data,
+                position: 0,
+                big_endian: false,
+                format: Format::Pcapng {
+                    interfaces: Vec::new(),
+                },
+            });
+        }
+
+        let (big_endian, nanos_per_unit) = match (magic_le, magic_be) {
+            (PCAP_MAGIC_MICROS, _) => (false, 1000),
+            (PCAP_MAGIC_NANOS, _) => (false, 1),
+            (_, PCAP_MAGIC_MICROS) => (true, 1000),
+            (_, PCAP_MAGIC_NANOS) => (true, 1),
+            _ => return Err(invalid_data("unknown capture file format")),
+        };
+        let header = data
+            .get(..PCAP_HEADER_LEN)
+            .ok_or_else(|| invalid_data("pcap header is truncated"))?;
+        Ok(Self {
+            data,
+            position: PCAP_HEADER_LEN,
+            big_endian,
+            format: Format::Pcap {
+                // the upper bits of the link type field may contain additional information
+                link_type: u32_at(header, 20, big_endian)? as u16,
+                nanos_per_unit,
+            },
+        })
+    }
+
+    /// Read the next packet or return `None` at the end of the capture
+    pub fn next_packet(&mut self) -> io::Result<Option<CapturedPacket<'a>>> {
+        loop {
+            if self.position >= self.data.len() {
+                return Ok(None);
+            }
+            let result = match self.format {
+                Format::Pcap { .. } => self.next_pcap_record().map(Some),
+                Format::Pcapng { .. } => self.next_pcapng_block(),
+            };
+            match result {
+                Ok(None) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn next_pcap_record(&mut self) -> io::Result<CapturedPacket<'a>> {
+        let (link_type, nanos_per_unit) = match self.format {
+            Format::Pcap {
+                link_type,
+                nanos_per_unit,
+            } => (link_type, nanos_per_unit),
+            Format::Pcapng { .. } => unreachable!(),
+        };
+        let header = self
+            .data
+            .get(self.position..self.position + PCAP_RECORD_HEADER_LEN)
+            .ok_or_else(|| invalid_data("pcap record header is truncated"))?;
+        let seconds = u32_at(header, 0, self.big_endian)?;
+        let fraction = u32_at(header, 4, self.big_endian)?;
+        let captured_len = u32_at(header, 8, self.big_endian)? as usize;
+
+        let start = self.position + PCAP_RECORD_HEADER_LEN;
+        let data = self
+            .data
+            .get(start..start + captured_len)
+            .ok_or_else(|| invalid_data("pcap record is truncated"))?;
+        self.position = start + captured_len;
+
+        Ok(CapturedPacket {
+            timestamp: Duration::new(seconds as u64, fraction.saturating_mul(nanos_per_unit)),
+            link_type,
+            data,
+        })
+    }
+
+    /// Read the next pcapng block and return the packet it contains if it is a packet block
+    fn next_pcapng_block(&mut self) -> io::Result<Option<CapturedPacket<'a>>> {
+        let data = self.data;
+        let header = data
+            .get(self.position..self.position + 12)
+            .ok_or_else(|| invalid_data("pcapng block header is truncated"))?;
+        // the section header block type reads the same in both byte orders and every section
+        // may have a different byte order
+        if u32_at(header, 0, false)? == PCAPNG_SECTION_HEADER {
+            self.big_endian = u32_at(header, 8, false)? != PCAPNG_BYTE_ORDER_MAGIC;
+        }
+        let block_type = u32_at(header, 0, self.big_endian)?;
+        let block_len = u32_at(header, 4, self.big_endian)? as usize;
+        if block_len < 12 || !block_len.is_multiple_of(4) {
+            return Err(invalid_data("pcapng block has an invalid length"));
+        }
+        let block = data
+            .get(self.position..self.position + block_len)
+            .ok_or_else(|| invalid_data("pcapng block is truncated"))?;
+        self.position += block_len;
+        let body = &block[8..block_len - 4];
+
+        let interfaces = match &mut self.format {
+            Format::Pcapng { interfaces } => interfaces,
+            Format::Pcap { .. } => unreachable!(),
+        };
+        let big_endian = self.big_endian;
+        let field = |offset: usize| u32_at(body, offset, big_endian);
+
+        match block_type {
+            PCAPNG_SECTION_HEADER => {
+                interfaces.clear();
+                Ok(None)
+            }
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = field(0)? as u16;
+                let mut timestamp_resolution = 1_000_000;
+                for (code, value) in options(body.get(8..).unwrap_or_default(), big_endian) {
+                    if code == PCAPNG_OPTION_IF_TSRESOL && !value.is_empty() {
+                        let exponent = (value[0] & 0x7f) as u32;
+                        let base: u64 = if value[0] & 0x80 == 0 { 10 } else { 2 };
+                        timestamp_resolution = base
+                            .checked_pow(exponent)
+                            .ok_or_else(|| invalid_data("unsupported timestamp resolution"))?;
+                    }
+                }
+                interfaces.push(Interface {
+                    link_type,
+                    timestamp_resolution,
+                });
+                Ok(None)
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = *interfaces
+                    .get(field(0)? as usize)
+                    .ok_or_else(|| invalid_data("packet refers to an unknown interface"))?;
+                let timestamp = (field(4)? as u64) << 32 | field(8)? as u64;
+                let captured_len = field(12)? as usize;
+                let data = body
+                    .get(20..20 + captured_len)
+                    .ok_or_else(|| invalid_data("enhanced packet block is truncated"))?;
+                let nanos =
+                    timestamp as u128 * 1_000_000_000 / interface.timestamp_resolution as u128;
+                Ok(Some(CapturedPacket {
+                    timestamp: Duration::from_nanos(nanos as u64),
+                    link_type: interface.link_type,
+                    data,
+                }))
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let interface = *interfaces
+                    .first()
+                    .ok_or_else(|| invalid_data("packet refers to an unknown interface"))?;
+                let original_len = field(0)? as usize;
+                let data = &body[4..];
+                Ok(Some(CapturedPacket {
+                    timestamp: Duration::ZERO,
+                    link_type: interface.link_type,
+                    data: &data[..original_len.min(data.len())],
+                }))
+            }
+            // all other blocks do not matter here
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Iterate over the code and value of all options in a pcapng options `block`
+fn options(block: &[u8], big_endian: bool) -> impl Iterator<Item = (u16, &[u8])> {
+    let u16_at = move |offset: usize| {
+        let bytes = [block[offset], block[offset + 1]];
+        if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    };
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        if position + 4 > block.len() {
+            return None;
+        }
+        let code = u16_at(position);
+        let len = u16_at(position + 2) as usize;
+        let value = block.get(position + 4..position + 4 + len)?;
+        if code == PCAPNG_OPTION_END {
+            return None;
+        }
+        position += 4 + padded_len(len);
+        Some((code, value))
+    })
+}
+
+/// Writer of a pcap capture of raw IP packets with nanosecond timestamps
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(PCAP_HEADER_LEN);
+        header.extend_from_slice(&PCAP_MAGIC_NANOS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&(LINKTYPE_RAW as u32).to_le_bytes());
+        inner.write_all(&header)?;
+        Ok(Self { inner })
+    }
+
+    /// Write `packet` that was captured at `timestamp` (since the UNIX epoch)
+    pub fn write_packet(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        let captured_len = packet.len().min(SNAPLEN as usize);
+        let mut header = Vec::with_capacity(PCAP_RECORD_HEADER_LEN);
+        header.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&timestamp.subsec_nanos().to_le_bytes());
+        header.extend_from_slice(&(captured_len as u32).to_le_bytes());
+        header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&packet[..captured_len])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read the 32 bit number at `offset` in `bytes`
+fn u32_at(bytes: &[u8], offset: usize, big_endian: bool) -> io::Result<u32> {
+    let bytes: [u8; 4] = bytes
+        .get(offset..offset + 4)
+        .ok_or_else(|| invalid_data("capture is truncated"))?
+        .try_into()
+        .unwrap();
+    Ok(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+/// Read the 16 bit network byte order number at `offset` in `bytes`
+fn be_u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *bytes.get(offset)?,
+        *bytes.get(offset + 1)?,
+    ]))
+}
+
+/// Direction of a packet relative to the capturing interface
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Writer of a pcapng capture of raw IP packets with nanosecond timestamps and direction
+/// annotations
+#[derive(Debug)]
+pub struct PcapngWriter<W: Write> {
+    inner: W,
+    /// Number of bytes written so far
+    len: u64,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start a capture by writing a section header and the description of its only interface
+    pub fn new(inner: W) -> io::Result<Self> {
+        let mut writer = Self { inner, len: 0 };
+
+        let mut section_header = Vec::new();
+        section_header.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        section_header.extend_from_slice(&1u16.to_le_bytes());
+        section_header.extend_from_slice(&0u16.to_le_bytes());
+        // the section length is not known in advance
+        section_header.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.write_block(PCAPNG_SECTION_HEADER, &section_header)?;
+
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        interface.extend_from_slice(&SNAPLEN.to_le_bytes());
+        push_option(
+            &mut interface,
+            PCAPNG_OPTION_IF_TSRESOL,
+            &[PCAPNG_NANOSECONDS],
+        );
+        push_option(&mut interface, PCAPNG_OPTION_END, &[]);
+        writer.write_block(PCAPNG_INTERFACE_DESCRIPTION, &interface)?;
+
+        Ok(writer)
+    }
+
+    /// Write `packet` that was captured at `timestamp` (since the UNIX epoch) while it passed
+    /// in `direction`
+    pub fn write_packet(
+        &mut self,
+        timestamp: Duration,
+        direction: Direction,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        let captured_len = packet.len().min(SNAPLEN as usize);
+        let nanos = timestamp.as_nanos() as u64;
+        let flags: u32 = match direction {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        };
+
+        let mut body = Vec::with_capacity(20 + padded_len(captured_len) + 12);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&(captured_len as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet[..captured_len]);
+        body.resize(20 + padded_len(captured_len), 0);
+        push_option(&mut body, PCAPNG_OPTION_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, PCAPNG_OPTION_END, &[]);
+        self.write_block(PCAPNG_ENHANCED_PACKET, &body)
+    }
+
+    /// Number of bytes that have been written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.len
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let block_len = (12 + body.len()) as u32;
+        self.inner.write_all(&block_type.to_le_bytes())?;
+        self.inner.write_all(&block_len.to_le_bytes())?;
+        self.inner.write_all(body)?;
+        self.inner.write_all(&block_len.to_le_bytes())?;
+        self.len += block_len as u64;
+        Ok(())
+    }
+}
+
+/// Append a pcapng option with `code` and `value` to `options`
+fn push_option(options: &mut Vec<u8>, code: u16, value: &[u8]) {
+    options.extend_from_slice(&code.to_le_bytes());
+    options.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    options.extend_from_slice(value);
+    options.resize(options.len() + padded_len(value.len()) - value.len(), 0);
+}
+
+/// Length of a pcapng field of `len` bytes including its padding to 32 bits
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+#[test]
+fn test_pcap_roundtrip() {
+    let mut capture = Vec::new();
+    let mut writer = PcapWriter::new(&mut capture).unwrap();
+    writer
+        .write_packet(Duration::new(1_600_000_000, 123_456_789), &[0x45, 1, 2])
+        .unwrap();
+    writer
+        .write_packet(Duration::new(1_600_000_001, 0), &[0x60])
+        .unwrap();
+
+    let mut reader = Reader::new(&capture).unwrap();
+    let first = reader.next_packet().unwrap().unwrap();
+    assert_eq!(first.timestamp, Duration::new(1_600_000_000, 123_456_789));
+    assert_eq!(first.ip_packet(), Some(&[0x45, 1, 2][..]));
+    let second = reader.next_packet().unwrap().unwrap();
+    assert_eq!(second.data, &[0x60]);
+    assert_eq!(reader.next_packet().unwrap(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_read_pcapng_with_ethernet() {
+    let block = |block_type: u32, body: &[u8]| {
+        let len = (12 + body.len()) as u32;
+        [
+            &block_type.to_le_bytes()[..],
+            &len.to_le_bytes(),
+            body,
+            &len.to_le_bytes(),
+        ]
+        .concat()
+    };
+    let mut frame = vec![0; 12];
+    frame.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+    frame.extend_from_slice(&[0, 1]);
+    frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+    frame.extend_from_slice(&[0x60, 0, 0, 0]);
+
+    let section_header = [
+        &PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes()[..],
+        &1u16.to_le_bytes(),
+        &0u16.to_le_bytes(),
+        &(-1i64).to_le_bytes(),
+    ]
+    .concat();
+    // millisecond timestamps
+    let interface = [
+        &LINKTYPE_ETHERNET.to_le_bytes()[..],
+        &[0, 0],
+        &SNAPLEN.to_le_bytes(),
+        &PCAPNG_OPTION_IF_TSRESOL.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &[3, 0, 0, 0],
+        &[0, 0, 0, 0],
+    ]
+    .concat();
+    let packet = [
+        &0u32.to_le_bytes()[..],
+        &0u32.to_le_bytes(),
+        &1_500u32.to_le_bytes(),
+        &(frame.len() as u32).to_le_bytes(),
+        &(frame.len() as u32).to_le_bytes(),
+        &frame,
+        &[0, 0],
+    ]
+    .concat();
+    let capture = [
+        block(PCAPNG_SECTION_HEADER, &section_header),
+        block(PCAPNG_INTERFACE_DESCRIPTION, &interface),
+        block(PCAPNG_ENHANCED_PACKET, &packet),
+    ]
+    .concat();
+
+    let mut reader = Reader::new(&capture).unwrap();
+    let packet = reader.next_packet().unwrap().unwrap();
+    assert_eq!(packet.timestamp, Duration::from_millis(1_500));
+    assert_eq!(packet.ip_packet(), Some(&[0x60, 0, 0, 0][..]));
+    assert_eq!(reader.next_packet().unwrap(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_pcapng_roundtrip() {
+    let mut capture = Vec::new();
+    let mut writer = PcapngWriter::new(&mut capture).unwrap();
+    writer
+        .write_packet(
+            Duration::new(1_600_000_000, 5),
+            Direction::Inbound,
+            &[0x45, 1],
+        )
+        .unwrap();
+    writer
+        .write_packet(
+            Duration::new(1_600_000_000, 7),
+            Direction::Outbound,
+            &[0x45, 2, 3],
+        )
+        .unwrap();
+    let bytes_written = writer.bytes_written();
+    assert_eq!(bytes_written, capture.len() as u64);
+
+    let mut reader = Reader::new(&capture).unwrap();
+    let first = reader.next_packet().unwrap().unwrap();
+    assert_eq!(first.timestamp, Duration::new(1_600_000_000, 5));
+    assert_eq!(first.ip_packet(), Some(&[0x45, 1][..]));
+    let second = reader.next_packet().unwrap().unwrap();
+    assert_eq!(second.data, &[0x45, 2, 3]);
+    assert_eq!(reader.next_packet().unwrap(), None);
+}
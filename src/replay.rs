@@ -0,0 +1,59 @@
+//! Offline replay of captured probes
+//!
+//! Every packet of a capture is handled as if it had been read from a TUN device and the
+//! responses are written to another capture with the timestamp of the packet they respond to.
+//! This makes it possible to see how a user's probes are answered without any TUN device or
+//! special privileges.
+//! Incomplete fragmented packets are never given up on because no time passes during a replay.
+
+use crate::argparse::Arguments;
+use crate::packets;
+use crate::pcap::{PcapWriter, Reader};
+use log::{debug, info};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+/// Handle all packets of the capture at `input` and write the responses to a new capture at
+/// `output`
+pub fn replay(program_args: &Arguments, input: &Path, output: &Path) -> io::Result<()> {
+    let capture = std::fs::read(input)?;
+    let mut reader = Reader::new(&capture)?;
+    let mut writer = PcapWriter::new(BufWriter::new(File::create(output)?))?;
+
+    let mtu = program_args
+        .mtu
+        .map_or(packets::DEFAULT_MTU, |mtu| mtu as usize);
+    let state = packets::State::new(program_args, mtu);
+    let mut responses = packets::Responses::new();
+    let (mut n_packets, mut n_skipped, mut n_responses) = (0, 0, 0);
+
+    while let Some(packet) = reader.next_packet()? {
+        n_packets += 1;
+        let ip_packet = match packet.ip_packet() {
+            Some(ip_packet) if !ip_packet.is_empty() => ip_packet,
+            _ => {
+                debug!(
+                    "Skipping captured packet that is not an IP packet [link_type={}]",
+                    packet.link_type
+                );
+                n_skipped += 1;
+                continue;
+            }
+        };
+
+        responses.clear();
+        packets::handle(program_args, &state, ip_packet, &mut responses);
+        for response in responses.iter() {
+            writer.write_packet(packet.timestamp, response)?;
+            n_responses += 1;
+        }
+    }
+    writer.flush()?;
+
+    info!(
+        "Replayed capture [packets={}, skipped={}, responses={}]",
+        n_packets, n_skipped, n_responses
+    );
+    Ok(())
+}
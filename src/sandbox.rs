@@ -0,0 +1,171 @@
+//! Dropping of privileges once the TUN devices have been set up
+//!
+//! Handling packets needs no privileges at all, so after setup the process can switch to an
+//! unprivileged user, give up all capabilities and restrict itself to the system calls that the
+//! packet loop needs.
+//! The switch of the user and the seccomp filter apply to all threads of the process, because
+//! the C library broadcasts credential changes and the filter is synchronized. The bounding set
+//! and the remaining capability sets can only be changed for the calling thread, but the user
+//! switch already clears the capabilities of all other threads and `no_new_privs`, which is set
+//! before any other threads are started, keeps them from gaining new ones.
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod seccomp;
+
+use log::info;
+use std::ffi::CString;
+use std::io;
+
+/// Resolve a user name or numeric user id
+pub fn lookup_user(user: &str) -> io::Result<libc::uid_t> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user)?;
+    // SAFETY: nothing else in the program reads the user database concurrently and the result
+    // is copied right away
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {} does not exist", user),
+        ));
+    }
+    // SAFETY: passwd was checked to point to a valid entry
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// Resolve a group name or numeric group id
+pub fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    // SAFETY: see lookup_user()
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("group {} does not exist", group),
+        ));
+    }
+    // SAFETY: entry was checked to point to a valid entry
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Make sure that no thread can gain privileges by executing programs, e.g. setuid ones, once
+/// `drop_privileges()` was called.
+///
+/// This only applies to the calling thread and the threads that it starts afterwards, so it
+/// needs to be called before any other threads exist. Programs that are executed during the
+/// setup still get the capabilities of root.
+pub fn prepare_privilege_drop() -> io::Result<()> {
+    // SAFETY: prctl with PR_SET_NO_NEW_PRIVS takes plain integers
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })
+}
+
+/// Switch all threads to the unprivileged `uid` and `gid` and give up all capabilities for good.
+///
+/// Switching away from root makes the kernel clear the permitted and effective capabilities of
+/// every thread. `prepare_privilege_drop()` needs to have been called before.
+pub fn drop_privileges(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    if uid == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "privileges can only be dropped by switching to a user other than root",
+        ));
+    }
+
+    // the bounding set can only be changed while CAP_SETPCAP is still held
+    drop_bounding_set()?;
+    // SAFETY: the C library applies these to all threads and only reads the given group list
+    unsafe {
+        check(libc::setgroups(1, &gid))?;
+        check(libc::setresgid(gid, gid, gid))?;
+        check(libc::setresuid(uid, uid, uid))?;
+    }
+    clear_capabilities()?;
+
+    // make sure that root can not be regained
+    // SAFETY: setuid has no memory safety requirements
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other("root privileges could be regained"));
+    }
+    info!("Dropped privileges [uid={}, gid={}]", uid, gid);
+    Ok(())
+}
+
+/// Remove all capabilities from the bounding set so that they can never be gained again
+fn drop_bounding_set() -> io::Result<()> {
+    for capability in 0.. {
+        // SAFETY: prctl with PR_CAPBSET_DROP takes plain integers
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, capability, 0, 0, 0) } != 0 {
+            let error = io::Error::last_os_error();
+            // EINVAL marks the first capability that the kernel does not know
+            return match error.raw_os_error() {
+                Some(libc::EINVAL) if capability > 0 => Ok(()),
+                _ => Err(error),
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Clear the permitted, effective and inheritable capabilities of the calling thread
+fn clear_capabilities() -> io::Result<()> {
+    #[repr(C)]
+    struct CapUserHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct CapUserData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+    // _LINUX_CAPABILITY_VERSION_3 which uses two data structs for 64 capabilities
+    let header = CapUserHeader {
+        version: 0x2008_0522,
+        pid: 0,
+    };
+    let data = [CapUserData {
+        effective: 0,
+        permitted: 0,
+        inheritable: 0,
+    }; 2];
+    // SAFETY: header and data have the layout that capset expects
+    check(unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } as libc::c_int)?;
+    // SAFETY: prctl with PR_CAP_AMBIENT takes plain integers
+    check(unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    })
+}
+
+/// Set `no_new_privs` and only allow the system calls of the packet loop from now on.
+///
+/// Other system calls fail with `EPERM` instead of killing the process so that e.g. a log
+/// message that could not be written does not take the service down.
+pub fn apply_seccomp_filter() -> io::Result<()> {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    return seccomp::apply();
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "seccomp filter is not supported on this architecture",
+    ))
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
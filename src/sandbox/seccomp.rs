@@ -0,0 +1,232 @@
+//! The seccomp filter that restricts the process to the system calls of the packet loop
+//!
+//! Only built for the architectures whose `AUDIT_ARCH_*` value and system call numbers are known.
+
+use super::check;
+use log::info;
+use std::io;
+
+/// Mask of `seccomp_data.nr` values that belong to the x32 ABI
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// `AUDIT_ARCH_*` value of the architecture this program is built for
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+// classic BPF instructions (see linux/bpf_common.h)
+/// `BPF_LD | BPF_W | BPF_ABS`
+const BPF_LD_W_ABS: u16 = 0x20;
+/// `BPF_JMP | BPF_JEQ | BPF_K`
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// `BPF_JMP | BPF_JGE | BPF_K`
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// `BPF_RET | BPF_K`
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets into `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// The system calls that the packet loop, the tokio runtime and logging use
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // packet I/O and logging
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_fcntl,
+    libc::SYS_close,
+    // rotation of capture files
+    libc::SYS_openat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    // tokio's reactor and scheduler
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_ppoll,
+    // threads and memory
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_prctl,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_getrandom,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_renameat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+];
+
+/// Only allow the system calls of the packet loop from now on
+pub fn apply() -> io::Result<()> {
+    let mut program = filter_program(ALLOWED_SYSCALLS);
+    let program = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: prctl with PR_SET_NO_NEW_PRIVS takes plain integers
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+    // SAFETY: the program stays alive for the duration of the call which copies it
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    match result {
+        0 => {}
+        // with TSYNC, a positive result is the id of a thread that could not be synchronized
+        tid if tid > 0 => {
+            return Err(io::Error::other(format!(
+                "could not apply seccomp filter to thread {}",
+                tid
+            )))
+        }
+        _ => return Err(io::Error::last_os_error()),
+    }
+    info!(
+        "Applied seccomp filter [allowed_syscalls={}]",
+        ALLOWED_SYSCALLS.len()
+    );
+    Ok(())
+}
+
+/// Build a BPF program that allows the `allowed` system calls of the native architecture and
+/// makes all others fail with `EPERM`
+fn filter_program(allowed: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    let mut program = Vec::with_capacity(allowed.len() + 8);
+    program.push(bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH));
+    program.push(bpf_jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0));
+    program.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    program.push(bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR));
+    #[cfg(target_arch = "x86_64")]
+    {
+        program.push(bpf_jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+        program.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    for (i, &syscall) in allowed.iter().enumerate() {
+        // jump over the remaining comparisons and the final deny to the allow
+        let to_allow = (allowed.len() - i) as u8;
+        program.push(bpf_jump(BPF_JMP_JEQ_K, syscall as u32, to_allow, 0));
+    }
+    program.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    program.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    program
+}
+
+fn bpf_stmt(code: u16, k: u32) -> libc::sock_filter {
+    bpf_jump(code, k, 0, 0)
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+#[cfg(test)]
+#[test]
+fn test_filter_program() {
+    // run the program like the kernel does for the given architecture and system call number
+    fn run(program: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            match instruction.code {
+                BPF_LD_W_ABS if instruction.k == SECCOMP_DATA_ARCH => accumulator = arch,
+                BPF_LD_W_ABS if instruction.k == SECCOMP_DATA_NR => accumulator = nr,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K => {
+                    let taken = match instruction.code {
+                        BPF_JMP_JEQ_K => accumulator == instruction.k,
+                        _ => accumulator >= instruction.k,
+                    };
+                    let offset = if taken {
+                        instruction.jt
+                    } else {
+                        instruction.jf
+                    };
+                    pc += offset as usize;
+                }
+                BPF_RET_K => return instruction.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    let denied = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let allowed = [3, 1, 200];
+    let program = filter_program(&allowed);
+    // every jump has to land inside the program
+    for (i, instruction) in program.iter().enumerate() {
+        if instruction.code == BPF_RET_K {
+            continue;
+        }
+        let target = i + 1 + instruction.jt.max(instruction.jf) as usize;
+        assert!(target < program.len());
+    }
+    for &nr in &allowed {
+        assert_eq!(run(&program, AUDIT_ARCH, nr as u32), SECCOMP_RET_ALLOW);
+    }
+    for nr in [0, 2, 4, 199, 201] {
+        assert_eq!(run(&program, AUDIT_ARCH, nr), denied);
+    }
+    assert_eq!(run(&program, AUDIT_ARCH ^ 1, 3), denied);
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(run(&program, AUDIT_ARCH, X32_SYSCALL_BIT | 3), denied);
+    assert_eq!(run(&filter_program(&[]), AUDIT_ARCH, 3), denied);
+}
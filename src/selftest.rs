@@ -0,0 +1,311 @@
+//! End-to-end self-test in private user and network namespaces
+//!
+//! The program executes itself again inside a new user and network namespace in which it may
+//! create TUN devices without any privileges on the host.
+//! There it brings up the configured devices and sends real probes through raw sockets so that
+//! the kernel's routing and the responses of the packet handlers are checked together.
+
+use crate::argparse::{Arguments, ProbeProtocol};
+use crate::ip_addrs;
+use crate::packet_io::{self, RawSender};
+use crate::simulate::{self, Outcome};
+use crate::tun_management;
+use log::{error, info};
+use pnet_packet::icmpv6::Icmpv6Packet;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use tokio::sync::watch;
+
+/// Environment variable that tells the executed program that it already runs inside the private
+/// namespaces
+const NAMESPACE_ENV: &str = "VIP_TRACEROUTER_SELFTEST_NAMESPACE";
+
+/// How long to wait for the response to a probe
+const RESPONSE_TIMEOUT: libc::timeval = libc::timeval {
+    tv_sec: 1,
+    tv_usec: 0,
+};
+
+const PROTOCOLS: [ProbeProtocol; 3] = [ProbeProtocol::Icmp, ProbeProtocol::Udp, ProbeProtocol::Tcp];
+
+/// Check that probes through the kernel are answered as expected and return whether they were
+pub async fn selftest(program_args: &Arguments) -> bool {
+    if std::env::var_os(NAMESPACE_ENV).is_some() {
+        check_in_namespaces(program_args).await
+    } else {
+        match run_in_namespaces().await {
+            Ok(passed) => passed,
+            Err(e) => {
+                error!(
+                    "Could not run self-test in private namespaces, are unprivileged user namespaces enabled? [error={}]",
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Execute this program again with the same arguments in a new user and network namespace and
+/// return whether it succeeded
+async fn run_in_namespaces() -> io::Result<bool> {
+    // SAFETY: getuid and getgid cannot fail
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    // root inside the namespaces is the calling user outside of them
+    let uid_map = format!("0 {} 1", uid);
+    let gid_map = format!("0 {} 1", gid);
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(NAMESPACE_ENV, "1");
+    // SAFETY: only async-signal-safe syscalls are made between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            write_proc_file(b"/proc/self/setgroups\0", b"deny")?;
+            write_proc_file(b"/proc/self/uid_map\0", uid_map.as_bytes())?;
+            write_proc_file(b"/proc/self/gid_map\0", gid_map.as_bytes())
+        });
+    }
+    let mut child = tokio::process::Command::from(command).spawn()?;
+    Ok(child.wait().await?.success())
+}
+
+/// Write `content` to the file at the NUL terminated `path` without allocating
+fn write_proc_file(path: &[u8], content: &[u8]) -> io::Result<()> {
+    // SAFETY: path is NUL terminated and the file descriptor is closed again
+    unsafe {
+        let fd = libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, content.as_ptr() as *const libc::c_void, content.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Bring up the TUN devices and probe every network with each protocol
+async fn check_in_namespaces(program_args: &Arguments) -> bool {
+    if program_args.n_hosts < 2 {
+        error!("The self-test needs at least two hosts per network");
+        return false;
+    }
+    if let Err(e) = allow_local_sources() {
+        error!(
+            "Could not configure the private network namespace [error={}]",
+            e
+        );
+        return false;
+    }
+
+    let tun_devices = tun_management::create_tun_devices(program_args).await;
+    // the workers run for as long as the checks because the sender is kept until the end
+    let (_shutdown, stopped) = watch::channel(false);
+    crate::spawn_workers(program_args, tun_devices, stopped);
+
+    // the sockets block while waiting for responses which must not stall the workers
+    let args = program_args.clone();
+    let failures = tokio::task::spawn_blocking(move || {
+        args.networks
+            .iter()
+            .map(|network| check_network(&args, *network))
+            .sum::<usize>()
+    })
+    .await
+    .expect("Self-test checks panicked");
+
+    if failures == 0 {
+        info!("Self-test passed");
+        true
+    } else {
+        error!("Self-test failed [failures={}]", failures);
+        false
+    }
+}
+
+/// Let the kernel accept responses from the first hop.
+///
+/// The first hop answers with the address of the TUN device itself which the kernel would
+/// otherwise drop as a spoofed local source.
+/// TAP devices would also keep their IPv6 addresses unusable during duplicate address
+/// detection, which nothing else in the private namespace could take part in anyway.
+fn allow_local_sources() -> io::Result<()> {
+    std::fs::write("/proc/sys/net/ipv4/conf/all/accept_local", "1")?;
+    std::fs::write("/proc/sys/net/ipv4/conf/all/rp_filter", "0")?;
+    std::fs::write("/proc/sys/net/ipv4/conf/default/rp_filter", "0")?;
+    std::fs::write("/proc/sys/net/ipv6/conf/default/accept_dad", "0")
+}
+
+/// Traceroute the last host of `network` with all protocols and return the number of probes
+/// that were not answered as expected
+fn check_network(program_args: &Arguments, network: IpAddr) -> usize {
+    let n_hosts = program_args.n_hosts;
+    let nth_address = |n: usize| -> IpAddr {
+        match network {
+            IpAddr::V4(network) => ip_addrs::get_nth_address_in_network4(
+                n as u32,
+                ip_addrs::calc_netmask_size_with_n_hosts4(n_hosts),
+                &network,
+            )
+            .into(),
+            IpAddr::V6(network) => ip_addrs::get_nth_address_in_network6(
+                n,
+                ip_addrs::calc_netmask_size_with_n_hosts6(n_hosts),
+                &network,
+            )
+            .into(),
+        }
+    };
+    // probes are sent from the TUN device's own address so that responses are routed back
+    let source = nth_address(1);
+    let target = nth_address(n_hosts);
+
+    let socket = match ProbeSocket::new(network) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Could not open raw sockets [error={}]", e);
+            return 1;
+        }
+    };
+
+    let mut failures = 0;
+    let mut sequence: u16 = 0;
+    for protocol in PROTOCOLS {
+        for ttl in 1..=n_hosts.min(u8::MAX as usize) {
+            let expected_address = nth_address(ttl);
+            let expected_outcome = match protocol {
+                _ if ttl < n_hosts => Outcome::Hop,
+                ProbeProtocol::Icmp => Outcome::Reached,
+                ProbeProtocol::Udp | ProbeProtocol::Tcp => Outcome::Unreachable(String::new()),
+            };
+
+            let probe = simulate::build_probe(protocol, source, target, ttl as u8, sequence);
+            sequence = sequence.wrapping_add(1);
+            match socket
+                .send
+                .send_to(&probe, target)
+                .and_then(|()| socket.receive())
+            {
+                Ok(Some((address, outcome)))
+                    if address == expected_address && outcome == expected_outcome =>
+                {
+                    info!(
+                        "Probe was answered as expected [protocol={:?}, target={}, ttl={}, address={}, outcome={:?}]",
+                        protocol, target, ttl, address, outcome
+                    );
+                }
+                Ok(Some((address, outcome))) => {
+                    error!(
+                        "Probe was answered unexpectedly [protocol={:?}, target={}, ttl={}, address={}, outcome={:?}, expected_address={}, expected_outcome={:?}]",
+                        protocol, target, ttl, address, outcome, expected_address, expected_outcome
+                    );
+                    failures += 1;
+                }
+                Ok(None) => {
+                    error!(
+                        "Probe was not answered [protocol={:?}, target={}, ttl={}]",
+                        protocol, target, ttl
+                    );
+                    failures += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Could not send probe [protocol={:?}, target={}, ttl={}, error={}]",
+                        protocol, target, ttl, e
+                    );
+                    failures += 1;
+                }
+            }
+        }
+    }
+    failures
+}
+
+/// Raw sockets that send complete IP packets and receive the ICMP responses to them
+#[derive(Debug)]
+struct ProbeSocket {
+    send: RawSender,
+    receive: OwnedFd,
+    ipv4: bool,
+}
+
+impl ProbeSocket {
+    fn new(network: IpAddr) -> io::Result<Self> {
+        let (family, icmp) = match network {
+            IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+            IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+        };
+        let send = RawSender::new(network)?;
+        let receive = packet_io::raw_socket(family, icmp)?;
+        // SAFETY: the option value is a timeval as SO_RCVTIMEO requires
+        let result = unsafe {
+            libc::setsockopt(
+                receive.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &RESPONSE_TIMEOUT as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            send,
+            receive,
+            ipv4: network.is_ipv4(),
+        })
+    }
+
+    /// Wait for the next ICMP response that traceroute would not ignore and return its sender
+    /// and meaning or `None` if none arrives in time
+    fn receive(&self) -> io::Result<Option<(IpAddr, Outcome)>> {
+        let mut buffer = [0u8; u16::MAX as usize];
+        loop {
+            // SAFETY: all-zero bytes are valid for the plain C address structs
+            let mut address: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            let mut address_len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            // SAFETY: the packet and address buffers are valid for the given lengths
+            let len = unsafe {
+                libc::recvfrom(
+                    self.receive.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                    &mut address as *mut _ as *mut libc::sockaddr,
+                    &mut address_len,
+                )
+            };
+            if len < 0 {
+                let error = io::Error::last_os_error();
+                return match error.kind() {
+                    io::ErrorKind::WouldBlock => Ok(None),
+                    _ => Err(error),
+                };
+            }
+
+            let packet = &buffer[..len as usize];
+            // IPv4 raw sockets receive the IP header as well while IPv6 ones do not
+            let response = if self.ipv4 {
+                simulate::parse_response(packet)
+            } else {
+                Icmpv6Packet::new(packet)
+                    .and_then(|icmp| simulate::icmp6_outcome(&icmp))
+                    .map(|outcome| (Ipv6Addr::from(address.sin6_addr.s6_addr).into(), outcome))
+            };
+            if response.is_some() {
+                return Ok(response);
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Offline simulation of traceroute
+//!
+//! Probes like the ones that Linux' `traceroute` sends are built for increasing TTLs and handled
+//! in-process as if they had been read from a TUN device.
+//! The responses are then interpreted the way `traceroute -n` does it so that the printed
+//! output is what a user would see, except that the times are how long handling each probe
+//! took instead of real round trip times.
+
+use crate::argparse::{Arguments, ProbeProtocol};
+use crate::packets;
+use pnet_packet::icmp::{self, IcmpPacket, IcmpTypes};
+use pnet_packet::icmpv6::{self, Icmpv6Packet, Icmpv6Types};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet_packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet_packet::Packet;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+/// Destination port of the first UDP probe which is incremented for every further probe
+const UDP_BASE_PORT: u16 = 33434;
+
+/// Destination port of TCP probes
+const TCP_PORT: u16 = 80;
+
+/// Number of data bytes that ICMP and UDP probes carry
+const PROBE_DATA_LEN: usize = 32;
+
+/// What a probe's response tells about the path
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    /// A router on the way sent a time exceeded message
+    Hop,
+    /// The target answered
+    Reached,
+    /// The target is unreachable for the reason given by the annotation
+    Unreachable(String),
+}
+
+/// Print what `traceroute -n` would show for probes from `source` to `target` to `out`
+pub fn simulate(
+    program_args: &Arguments,
+    target: IpAddr,
+    source: Option<IpAddr>,
+    protocol: ProbeProtocol,
+    queries: usize,
+    max_hops: u8,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let source = match (source, target) {
+        (Some(source), _) if source.is_ipv4() == target.is_ipv4() => source,
+        (Some(_), _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and target need to be of the same address family",
+            ))
+        }
+        (None, IpAddr::V4(_)) => Ipv4Addr::new(192, 0, 2, 1).into(),
+        (None, IpAddr::V6(_)) => Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+    };
+
+    let mtu = program_args
+        .mtu
+        .map_or(packets::DEFAULT_MTU, |mtu| mtu as usize);
+    let state = packets::State::new(program_args, mtu);
+    let mut responses = packets::Responses::new();
+    let mut sequence: u16 = 0;
+
+    writeln!(
+        out,
+        "traceroute to {} ({}), {} hops max, {} byte packets, times are processing times",
+        target,
+        target,
+        max_hops,
+        build_probe(protocol, source, target, 1, 0).len()
+    )?;
+    for ttl in 1..=max_hops {
+        write!(out, "{:2} ", ttl)?;
+        let mut last_address = None;
+        let mut done = false;
+
+        for _ in 0..queries {
+            let probe = build_probe(protocol, source, target, ttl, sequence);
+            sequence = sequence.wrapping_add(1);
+
+            responses.clear();
+            let start = Instant::now();
+            packets::handle(program_args, &state, &probe, &mut responses);
+            let elapsed = start.elapsed();
+
+            let (address, outcome) = match responses.iter().find_map(parse_response) {
+                None => {
+                    write!(out, " *")?;
+                    continue;
+                }
+                Some(reply) => reply,
+            };
+            if last_address != Some(address) {
+                write!(out, " {}", address)?;
+                last_address = Some(address);
+            }
+            write!(out, "  {:.3} ms", elapsed.as_secs_f64() * 1000.0)?;
+            match outcome {
+                Outcome::Hop => {}
+                Outcome::Reached => done = true,
+                Outcome::Unreachable(annotation) => {
+                    if !annotation.is_empty() {
+                        write!(out, " {}", annotation)?;
+                    }
+                    done = true;
+                }
+            }
+        }
+
+        writeln!(out)?;
+        if done {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Build the probe with the given `ttl` and `sequence` number as a complete IP packet
+pub fn build_probe(
+    protocol: ProbeProtocol,
+    source: IpAddr,
+    target: IpAddr,
+    ttl: u8,
+    sequence: u16,
+) -> Vec<u8> {
+    // probes are told apart from those of other simulations the same way traceroute does it
+    let identifier = std::process::id() as u16;
+    let (transport_len, next_header) = match protocol {
+        ProbeProtocol::Icmp if target.is_ipv4() => {
+            (8 + PROBE_DATA_LEN, IpNextHeaderProtocols::Icmp)
+        }
+        ProbeProtocol::Icmp => (8 + PROBE_DATA_LEN, IpNextHeaderProtocols::Icmpv6),
+        ProbeProtocol::Udp => (8 + PROBE_DATA_LEN, IpNextHeaderProtocols::Udp),
+        ProbeProtocol::Tcp => (TcpPacket::minimum_packet_size(), IpNextHeaderProtocols::Tcp),
+    };
+    let ip_header_len = match target {
+        IpAddr::V4(_) => Ipv4Packet::minimum_packet_size(),
+        IpAddr::V6(_) => Ipv6Packet::minimum_packet_size(),
+    };
+
+    let mut probe = vec![0u8; ip_header_len + transport_len];
+    let (ip_header, transport) = probe.split_at_mut(ip_header_len);
+    match protocol {
+        ProbeProtocol::Icmp => {
+            transport[0] = match target {
+                IpAddr::V4(_) => IcmpTypes::EchoRequest.0,
+                IpAddr::V6(_) => Icmpv6Types::EchoRequest.0,
+            };
+            transport[4..6].copy_from_slice(&identifier.to_be_bytes());
+            transport[6..8].copy_from_slice(&sequence.to_be_bytes());
+        }
+        ProbeProtocol::Udp => {
+            let mut udp = MutableUdpPacket::new(transport).unwrap();
+            udp.set_source(identifier | 0x8000);
+            udp.set_destination(UDP_BASE_PORT.wrapping_add(sequence));
+            udp.set_length(transport_len as u16);
+        }
+        ProbeProtocol::Tcp => {
+            let mut tcp = MutableTcpPacket::new(transport).unwrap();
+            tcp.set_source(identifier | 0x8000);
+            tcp.set_destination(TCP_PORT);
+            tcp.set_sequence(sequence as u32);
+            tcp.set_data_offset(5);
+            tcp.set_flags(TcpFlags::SYN);
+            tcp.set_window(u16::MAX);
+        }
+    }
+
+    let checksum = match (source, target) {
+        (IpAddr::V4(source), IpAddr::V4(target)) => {
+            write_ipv4_header(
+                ip_header,
+                source,
+                target,
+                ttl,
+                sequence,
+                transport_len,
+                next_header,
+            );
+            match protocol {
+                ProbeProtocol::Icmp => icmp::checksum(&IcmpPacket::new(transport).unwrap()),
+                ProbeProtocol::Udp => {
+                    udp::ipv4_checksum(&UdpPacket::new(transport).unwrap(), &source, &target)
+                }
+                ProbeProtocol::Tcp => {
+                    tcp::ipv4_checksum(&TcpPacket::new(transport).unwrap(), &source, &target)
+                }
+            }
+        }
+        (IpAddr::V6(source), IpAddr::V6(target)) => {
+            let mut ipv6 = MutableIpv6Packet::new(ip_header).unwrap();
+            ipv6.set_version(6);
+            ipv6.set_payload_length(transport_len as u16);
+            ipv6.set_next_header(next_header);
+            ipv6.set_hop_limit(ttl);
+            ipv6.set_source(source);
+            ipv6.set_destination(target);
+            match protocol {
+                ProbeProtocol::Icmp => {
+                    icmpv6::checksum(&Icmpv6Packet::new(transport).unwrap(), &source, &target)
+                }
+                ProbeProtocol::Udp => {
+                    udp::ipv6_checksum(&UdpPacket::new(transport).unwrap(), &source, &target)
+                }
+                ProbeProtocol::Tcp => {
+                    tcp::ipv6_checksum(&TcpPacket::new(transport).unwrap(), &source, &target)
+                }
+            }
+        }
+        _ => unreachable!("source and target are of the same address family"),
+    };
+    let checksum_offset = match protocol {
+        ProbeProtocol::Icmp => 2,
+        ProbeProtocol::Udp => 6,
+        ProbeProtocol::Tcp => 16,
+    };
+    transport[checksum_offset..checksum_offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    probe
+}
+
+fn write_ipv4_header(
+    buffer: &mut [u8],
+    source: Ipv4Addr,
+    target: Ipv4Addr,
+    ttl: u8,
+    identification: u16,
+    transport_len: usize,
+    next_header: IpNextHeaderProtocol,
+) {
+    let mut ipv4 = MutableIpv4Packet::new(buffer).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length((Ipv4Packet::minimum_packet_size() + transport_len) as u16);
+    ipv4.set_identification(identification);
+    ipv4.set_ttl(ttl);
+    ipv4.set_next_level_protocol(next_header);
+    ipv4.set_source(source);
+    ipv4.set_destination(target);
+    let checksum = ipv4::checksum(&ipv4.to_immutable());
+    ipv4.set_checksum(checksum);
+}
+
+/// Get the sender of a response and what it means for the traceroute.
+///
+/// `None` is returned for responses that traceroute would ignore.
+pub fn parse_response(response: &[u8]) -> Option<(IpAddr, Outcome)> {
+    match response.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new(response)?;
+            if packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+                return None;
+            }
+            let outcome = icmp_outcome(&IcmpPacket::new(packet.payload())?)?;
+            Some((packet.get_source().into(), outcome))
+        }
+        6 => {
+            let packet = Ipv6Packet::new(response)?;
+            if packet.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+                return None;
+            }
+            let outcome = icmp6_outcome(&Icmpv6Packet::new(packet.payload())?)?;
+            Some((packet.get_source().into(), outcome))
+        }
+        _ => None,
+    }
+}
+
+/// Interpret an ICMP response to a probe or return `None` if traceroute would ignore it
+pub fn icmp_outcome(icmp: &IcmpPacket) -> Option<Outcome> {
+    match icmp.get_icmp_type() {
+        IcmpTypes::TimeExceeded => Some(Outcome::Hop),
+        IcmpTypes::EchoReply => Some(Outcome::Reached),
+        IcmpTypes::DestinationUnreachable => {
+            Some(Outcome::Unreachable(match icmp.get_icmp_code().0 {
+                0 => "!N".to_string(),
+                1 => "!H".to_string(),
+                2 => "!P".to_string(),
+                // the port unreachable message comes from the target itself
+                3 => String::new(),
+                4 => "!F".to_string(),
+                5 => "!S".to_string(),
+                9 | 10 | 13 => "!X".to_string(),
+                14 => "!V".to_string(),
+                15 => "!C".to_string(),
+                code => format!("!<{}>", code),
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Interpret an ICMPv6 response to a probe or return `None` if traceroute would ignore it
+pub fn icmp6_outcome(icmp: &Icmpv6Packet) -> Option<Outcome> {
+    match icmp.get_icmpv6_type() {
+        Icmpv6Types::TimeExceeded => Some(Outcome::Hop),
+        Icmpv6Types::EchoReply => Some(Outcome::Reached),
+        Icmpv6Types::DestinationUnreachable => {
+            Some(Outcome::Unreachable(match icmp.get_icmpv6_code().0 {
+                0 => "!N".to_string(),
+                1 => "!X".to_string(),
+                3 => "!H".to_string(),
+                // the port unreachable message comes from the target itself
+                4 => String::new(),
+                code => format!("!<{}>", code),
+            }))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_simulate() {
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--net",
+        "2001:db8:1::",
+        "--nhosts",
+        "10",
+        "--forward",
+        "10.0.0.4=192.0.2.80",
+    ]);
+    let hops = |target: &str, protocol, max_hops| {
+        let mut out = Vec::new();
+        simulate(
+            &args,
+            target.parse().unwrap(),
+            None,
+            protocol,
+            2,
+            max_hops,
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| {
+                // only keep addresses, annotations and drops
+                line.split_whitespace()
+                    .skip(1)
+                    .filter(|word| {
+                        word.parse::<IpAddr>().is_ok() || word.starts_with('!') || *word == "*"
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        hops("10.0.0.3", ProbeProtocol::Icmp, 30),
+        ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+    );
+    assert_eq!(
+        hops("2001:db8:1::2", ProbeProtocol::Udp, 30),
+        ["2001:db8:1::1", "2001:db8:1::2"]
+    );
+    assert_eq!(
+        hops("10.0.0.3", ProbeProtocol::Tcp, 30),
+        ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+    );
+    assert_eq!(
+        hops("2001:db8:1::3", ProbeProtocol::Tcp, 30),
+        ["2001:db8:1::1", "2001:db8:1::2", "2001:db8:1::3"]
+    );
+    // probes that get through to a forwarded address are not answered by the simulation
+    assert_eq!(
+        hops("10.0.0.4", ProbeProtocol::Udp, 4),
+        ["10.0.0.1", "10.0.0.2", "10.0.0.3", "* *"]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_response_annotations() {
+    let args = crate::argparse::parse_arguments_from([
+        "vip_tracerouter",
+        "--net",
+        "10.0.0.0",
+        "--net",
+        "2001:db8:1::",
+        "--nhosts",
+        "10",
+    ]);
+    let state = packets::State::new(&args, 1500);
+    // the target's port unreachable error with its code changed to the one under test
+    let unreachable = |target: IpAddr, code: u8| {
+        let source = match target {
+            IpAddr::V4(_) => Ipv4Addr::new(192, 0, 2, 1).into(),
+            IpAddr::V6(_) => Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        };
+        let probe = build_probe(ProbeProtocol::Udp, source, target, 64, 0);
+        let mut responses = packets::Responses::new();
+        packets::handle(&args, &state, &probe, &mut responses);
+        let mut response = responses.iter().next().unwrap().to_vec();
+        let header_len = if target.is_ipv4() { 20 } else { 40 };
+        response[header_len + 1] = code;
+        response
+    };
+
+    let target4 = IpAddr::from([10, 0, 0, 3]);
+    let target6 = "2001:db8:1::3".parse().unwrap();
+    for (target, code, annotation) in [
+        (target4, 3, ""),
+        (target4, 1, "!H"),
+        (target4, 13, "!X"),
+        (target6, 4, ""),
+        (target6, 3, "!H"),
+        (target6, 1, "!X"),
+    ] {
+        assert_eq!(
+            parse_response(&unreachable(target, code)),
+            Some((target, Outcome::Unreachable(annotation.to_string())))
+        );
+    }
+}
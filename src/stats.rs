@@ -0,0 +1,174 @@
+//! Process wide counters about handled packets
+//!
+//! All counters are plain atomics so that they can be updated from every packet handling task
+//! without any further synchronization.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A monotonically increasing event counter
+#[derive(Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters for ICMP error messages that were not sent because the offending packet was not
+/// eligible for one
+#[derive(Debug)]
+pub struct SuppressedIcmpErrors {
+    pub icmp_error: Counter,
+    pub broadcast_destination: Counter,
+    pub multicast_destination: Counter,
+    pub non_initial_fragment: Counter,
+    pub invalid_source: Counter,
+}
+
+/// Counters about the reassembly of fragmented packets
+#[derive(Debug)]
+pub struct Reassembly {
+    pub reassembled: Counter,
+    pub timed_out: Counter,
+    pub memory_limit_reached: Counter,
+    pub invalid: Counter,
+    pub overlapping: Counter,
+}
+
+/// Counters about packets read from TUN devices that could not be handled as a whole
+#[derive(Debug)]
+pub struct TunReads {
+    /// The packet is shorter than its IP header announces
+    pub truncated: Counter,
+    /// The packet is larger than the device's MTU
+    pub oversized: Counter,
+    /// The packet's destination is not part of any network that the device serves
+    pub unknown_destination: Counter,
+}
+
+/// Counters about packets that are translated between clients and real backends
+#[derive(Debug)]
+pub struct Forwarding {
+    pub to_backend: Counter,
+    pub from_backend: Counter,
+    /// The backend's packet does not belong to any translated connection
+    pub unknown_connection: Counter,
+    /// The packet's protocol can not be translated
+    pub unsupported: Counter,
+}
+
+/// Counters about the live capture of packets
+#[derive(Debug)]
+pub struct Capture {
+    /// The packet was not captured because the capture file could not be written fast enough
+    pub dropped: Counter,
+}
+
+#[derive(Debug)]
+pub struct Statistics {
+    pub suppressed_icmp_errors: SuppressedIcmpErrors,
+    pub reassembly: Reassembly,
+    pub tun_reads: TunReads,
+    pub forwarding: Forwarding,
+    pub capture: Capture,
+}
+
+pub static STATS: Statistics = Statistics {
+    suppressed_icmp_errors: SuppressedIcmpErrors {
+        icmp_error: Counter::new(),
+        broadcast_destination: Counter::new(),
+        multicast_destination: Counter::new(),
+        non_initial_fragment: Counter::new(),
+        invalid_source: Counter::new(),
+    },
+    reassembly: Reassembly {
+        reassembled: Counter::new(),
+        timed_out: Counter::new(),
+        memory_limit_reached: Counter::new(),
+        invalid: Counter::new(),
+        overlapping: Counter::new(),
+    },
+    tun_reads: TunReads {
+        truncated: Counter::new(),
+        oversized: Counter::new(),
+        unknown_destination: Counter::new(),
+    },
+    forwarding: Forwarding {
+        to_backend: Counter::new(),
+        from_backend: Counter::new(),
+        unknown_connection: Counter::new(),
+        unsupported: Counter::new(),
+    },
+    capture: Capture {
+        dropped: Counter::new(),
+    },
+};
+
+/// All counters as `group.counter=value` pairs for the log
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suppressed = &self.suppressed_icmp_errors;
+        let reassembly = &self.reassembly;
+        let tun_reads = &self.tun_reads;
+        let forwarding = &self.forwarding;
+        let capture = &self.capture;
+        let counters = [
+            ("suppressed_icmp_errors.icmp_error", &suppressed.icmp_error),
+            (
+                "suppressed_icmp_errors.broadcast_destination",
+                &suppressed.broadcast_destination,
+            ),
+            (
+                "suppressed_icmp_errors.multicast_destination",
+                &suppressed.multicast_destination,
+            ),
+            (
+                "suppressed_icmp_errors.non_initial_fragment",
+                &suppressed.non_initial_fragment,
+            ),
+            (
+                "suppressed_icmp_errors.invalid_source",
+                &suppressed.invalid_source,
+            ),
+            ("reassembly.reassembled", &reassembly.reassembled),
+            ("reassembly.timed_out", &reassembly.timed_out),
+            (
+                "reassembly.memory_limit_reached",
+                &reassembly.memory_limit_reached,
+            ),
+            ("reassembly.invalid", &reassembly.invalid),
+            ("reassembly.overlapping", &reassembly.overlapping),
+            ("tun_reads.truncated", &tun_reads.truncated),
+            ("tun_reads.oversized", &tun_reads.oversized),
+            (
+                "tun_reads.unknown_destination",
+                &tun_reads.unknown_destination,
+            ),
+            ("forwarding.to_backend", &forwarding.to_backend),
+            ("forwarding.from_backend", &forwarding.from_backend),
+            (
+                "forwarding.unknown_connection",
+                &forwarding.unknown_connection,
+            ),
+            ("forwarding.unsupported", &forwarding.unsupported),
+            ("capture.dropped", &capture.dropped),
+        ];
+        for (i, (name, counter)) in counters.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", name, counter.get())?;
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,121 @@
-use crate::ip_addrs;
+use crate::argparse::Arguments;
+use crate::ip_addrs::{self, Prefix};
+use crate::netlink;
+use crate::packet_io::TunFd;
 use log::info;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
 use tokio_tun::{Tun, TunBuilder};
 
-pub async fn create_tun_devices(
-    base_name: &str,
-    n_hosts: usize,
-    networks: &Vec<IpAddr>,
-) -> Vec<Tun> {
-    let mut result = Vec::with_capacity(networks.len());
+/// Directory in which `ip netns` keeps the named network namespaces
+const NETNS_DIR: &str = "/run/netns";
+
+/// `ioctl` request that attaches a file descriptor of `/dev/net/tun` to a device
+/// (see `linux/if_tun.h`)
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+/// `ioctl` request that reads the name and flags of the device that a file descriptor is
+/// attached to
+const TUNGETIFF: libc::c_ulong = 0x8004_54d2;
+
+/// `ioctl` request that opens the network namespace of the device that a file descriptor is
+/// attached to (Linux 5.2 and later)
+const TUNGETDEVNETNS: libc::c_ulong = 0x54e3;
+
+/// First file descriptor that systemd passes with socket activation (`sd_listen_fds(3)`)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The layout of `struct ifreq` with its union as plain bytes
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    data: [u8; 24],
+}
+
+impl IfReq {
+    fn new(name: &str) -> io::Result<Self> {
+        let mut request = Self {
+            name: [0; libc::IFNAMSIZ],
+            data: [0; 24],
+        };
+        // the name needs to be NUL terminated
+        if name.len() >= request.name.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("interface name {} is too long", name),
+            ));
+        }
+        request.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(request)
+    }
+
+    fn name(&self) -> String {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.name.len());
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+
+    fn flags(&self) -> libc::c_int {
+        libc::c_short::from_ne_bytes([self.data[0], self.data[1]]) as libc::c_int
+    }
+
+    fn set_flags(&mut self, flags: libc::c_int) {
+        self.data[..2].copy_from_slice(&(flags as libc::c_short).to_ne_bytes());
+    }
+
+    /// The integer value of e.g. `SIOCGIFMTU` and `SIOCGIFINDEX`
+    fn int_value(&self) -> libc::c_int {
+        libc::c_int::from_ne_bytes(self.data[..4].try_into().unwrap())
+    }
+}
+
+/// Create one TUN device per network, or a single device with one address per network if
+/// `--single-device` is given, which are TAP devices with `--tap`
+pub async fn create_tun_devices(program_args: &Arguments) -> Vec<Vec<Tun>> {
+    let n_hosts = program_args.n_hosts;
+    let (mtu, queues, tap) = (program_args.mtu, program_args.queues, program_args.tap);
+    let mut result = Vec::with_capacity(program_args.networks.len());
+
+    for (i, network) in program_args.networks.iter().enumerate() {
+        let index = if program_args.single_device { 0 } else { i };
+        let device_name = format!("{}{}", program_args.tun_device_name, index);
+        if program_args.single_device && i > 0 {
+            let prefix = Prefix::virtual_network(*network, n_hosts);
+            let own_address = Prefix {
+                address: nth_address(network, prefix.length, 1),
+                length: prefix.length,
+            };
+            netlink::add_address(own_address, &device_name)
+                .expect("Could not add address to TUN device");
+            continue;
+        }
 
-    for (i, network) in networks.iter().enumerate() {
         match network {
             IpAddr::V4(network) => {
                 let netmask_size = ip_addrs::calc_netmask_size_with_n_hosts4(n_hosts);
                 let tun_address = ip_addrs::get_nth_address_in_network4(1, netmask_size, &network);
                 let tun = create_ipv4_tun_device(
-                    &format!("{}{}", base_name, i),
+                    &device_name,
                     tun_address,
                     ip_addrs::calc_netmask_from_size4(netmask_size),
+                    mtu,
+                    queues,
+                    tap,
                 )
                 .await;
-                info!("Created TUN device [own_address={}]", tun_address);
+                info!(
+                    "Created TUN device [own_address={}, queues={}]",
+                    tun_address, queues
+                );
                 result.push(tun);
             }
             IpAddr::V6(network) => {
@@ -29,12 +123,18 @@ pub async fn create_tun_devices(
                 let tun_address =
                     ip_addrs::get_nth_address_in_network6(1, netmask_size as usize, &network);
                 let tun = create_ipv6_tun_device(
-                    &format!("{}{}", base_name, i),
+                    &device_name,
                     tun_address,
                     netmask_size as u32,
+                    mtu,
+                    queues,
+                    tap,
                 )
                 .await;
-                info!("Created TUN device [own_address={}]", tun_address);
+                info!(
+                    "Created TUN device [own_address={}, queues={}]",
+                    tun_address, queues
+                );
                 result.push(tun);
             }
         }
@@ -43,34 +143,356 @@ pub async fn create_tun_devices(
     result
 }
 
+/// The `n`th address in the network of `address` with the given prefix length
+fn nth_address(address: &IpAddr, prefix_length: u8, n: usize) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => IpAddr::V4(ip_addrs::get_nth_address_in_network4(
+            n as u32,
+            prefix_length as u32,
+            address,
+        )),
+        IpAddr::V6(address) => IpAddr::V6(ip_addrs::get_nth_address_in_network6(
+            n,
+            prefix_length as usize,
+            address,
+        )),
+    }
+}
+
+/// Attach to the persistent TUN devices that `create_tun_devices()` would otherwise create.
+///
+/// The devices need to exist already and be owned by the user that runs the program, e.g. by
+/// `ip tuntap add <name> mode tun user <user>`, which allows attaching without any
+/// capabilities.
+/// Their addresses and routes are left as they were configured.
+pub fn attach_tun_devices(
+    base_name: &str,
+    n_devices: usize,
+    queues: usize,
+    tap: bool,
+) -> io::Result<Vec<Vec<TunFd>>> {
+    let mut result = Vec::with_capacity(n_devices);
+    for i in 0..n_devices {
+        let name = format!("{}{}", base_name, i);
+        let mut device = Vec::with_capacity(queues);
+        for _ in 0..queues {
+            device.push(TunFd::new(attach_queue(&name, queues > 1, tap)?)?);
+        }
+        info!(
+            "Attached to persistent TUN device [name={}, queues={}]",
+            name, queues
+        );
+        result.push(device);
+    }
+    Ok(result)
+}
+
+/// Open a new queue of the existing TUN or TAP device `name`
+fn attach_queue(name: &str, multi_queue: bool, tap: bool) -> io::Result<OwnedFd> {
+    let tun = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open("/dev/net/tun")?;
+    let mut request = IfReq::new(name)?;
+    let mut flags = device_mode(tap) | libc::IFF_NO_PI;
+    if multi_queue {
+        flags |= libc::IFF_MULTI_QUEUE;
+    }
+    request.set_flags(flags);
+    // SAFETY: TUNSETIFF reads and writes a struct ifreq
+    if unsafe { libc::ioctl(tun.as_raw_fd(), TUNSETIFF, &mut request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(tun.into())
+}
+
+/// Get the file descriptors that systemd passed to this process (`LISTEN_FDS`).
+///
+/// Like `sd_listen_fds(1)`, the variables that describe them are removed from the environment
+/// so that programs which are started later do not take them for their own. Changing the
+/// environment is only safe while no other threads exist.
+pub fn listen_fds() -> Vec<RawFd> {
+    let for_this_process = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let n_fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    for variable in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(variable);
+    }
+    if !for_this_process {
+        return Vec::new();
+    }
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n_fds).collect()
+}
+
+/// Take over TUN file descriptors that were opened by someone else, e.g. systemd or a parent
+/// process.
+///
+/// File descriptors of the same multi-queue device are grouped together so that they share
+/// their state.
+/// They all need to be TAP devices if `tap` is set and TUN devices otherwise.
+pub fn inherited_tun_devices(fds: &[RawFd], tap: bool) -> io::Result<Vec<Vec<TunFd>>> {
+    let mut devices: Vec<(String, Vec<TunFd>)> = Vec::new();
+    for &fd in fds {
+        // SAFETY: fcntl only reads and writes the flags of a file descriptor and fails if it is
+        // not open
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the file descriptor is open and was handed over to this process to use
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let request = tun_info(fd.as_raw_fd())?;
+        let expected_flags = device_mode(tap) | libc::IFF_NO_PI;
+        let mode_flags = libc::IFF_TUN | libc::IFF_TAP | libc::IFF_NO_PI;
+        if request.flags() & mode_flags != expected_flags {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a {} device without packet information",
+                    request.name(),
+                    if tap { "TAP" } else { "TUN" }
+                ),
+            ));
+        }
+
+        let name = request.name();
+        info!(
+            "Using inherited TUN file descriptor [fd={}, name={}]",
+            fd.as_raw_fd(),
+            name
+        );
+        let tun = TunFd::new(fd)?;
+        match devices.iter_mut().find(|(device, _)| *device == name) {
+            Some((_, queues)) => queues.push(tun),
+            None => devices.push((name, vec![tun])),
+        }
+    }
+    Ok(devices.into_iter().map(|(_, queues)| queues).collect())
+}
+
+/// The `IFF_TUN` or `IFF_TAP` flag of a device
+fn device_mode(tap: bool) -> libc::c_int {
+    if tap {
+        libc::IFF_TAP
+    } else {
+        libc::IFF_TUN
+    }
+}
+
+/// Get the name and flags of the TUN device that `fd` is attached to
+fn tun_info(fd: RawFd) -> io::Result<IfReq> {
+    let mut request = IfReq::new("")?;
+    // SAFETY: TUNGETIFF writes a struct ifreq
+    if unsafe { libc::ioctl(fd, TUNGETIFF, &mut request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(request)
+}
+
+/// Get the name of the TUN device that `fd` is attached to
+pub fn device_name(fd: RawFd) -> io::Result<String> {
+    Ok(tun_info(fd)?.name())
+}
+
+/// Get the index of the interface `name` in the network namespace of `socket`
+pub fn interface_index(socket: RawFd, name: &str) -> io::Result<u32> {
+    let mut request = IfReq::new(name)?;
+    // SAFETY: SIOCGIFINDEX reads and writes a struct ifreq
+    if unsafe { libc::ioctl(socket, libc::SIOCGIFINDEX, &mut request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(request.int_value() as u32)
+}
+
+/// Get the MTU of the TUN device that `fd` is attached to.
+///
+/// The device may live in another network namespace (`--netns` or an inherited file
+/// descriptor), so the MTU is read through a socket in the namespace of the device. If the
+/// namespace cannot be determined the device is assumed to be in the current one.
+pub fn device_mtu(fd: RawFd) -> io::Result<usize> {
+    let mut request = tun_info(fd)?;
+    let read_mtu = |request: &mut IfReq| -> io::Result<()> {
+        // SAFETY: socket has no memory safety requirements
+        let socket =
+            unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the socket was just opened and is not owned by anything else
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+        // SAFETY: SIOCGIFMTU reads and writes a struct ifreq
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU, request) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    match device_netns(fd) {
+        Ok(netns) => std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    enter_netns(&netns)?;
+                    read_mtu(&mut request)
+                })
+                .join()
+                .expect("Thread in network namespace panicked")
+        })?,
+        Err(e)
+            if e.raw_os_error() == Some(libc::EINVAL) || e.raw_os_error() == Some(libc::ENOTTY) =>
+        {
+            read_mtu(&mut request)?
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(request.int_value() as usize)
+}
+
+/// Open the network namespace of the TUN device that `fd` is attached to
+fn device_netns(fd: RawFd) -> io::Result<File> {
+    // SAFETY: TUNGETDEVNETNS takes no argument and returns a new file descriptor
+    let netns = unsafe { libc::ioctl(fd, TUNGETDEVNETNS) };
+    if netns < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just opened and is not owned by anything else
+    Ok(unsafe { File::from_raw_fd(netns) })
+}
+
+/// The path under which `ip netns` keeps the network namespace with the name `netns`.
+///
+/// Names that would lead outside of its directory are rejected.
+pub fn netns_path(netns: &str) -> io::Result<PathBuf> {
+    if netns.is_empty() || netns == "." || netns == ".." || netns.contains('/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid network namespace name", netns),
+        ));
+    }
+    Ok(Path::new(NETNS_DIR).join(netns))
+}
+
+/// Create the TUN devices like `create_tun_devices()` but inside the named network namespace
+/// `netns` which needs to exist already.
+///
+/// Only a short-lived thread enters the namespace so the rest of the program stays in the
+/// namespace that it was started in.
+pub async fn create_tun_devices_in_netns(
+    netns: &str,
+    program_args: &Arguments,
+) -> io::Result<Vec<Vec<Tun>>> {
+    let path = netns_path(netns)?;
+    let program_args = program_args.clone();
+    // the devices are still registered with this runtime so that they can be used from it
+    let runtime = Handle::current();
+    let (result_tx, result_rx) = oneshot::channel();
+
+    std::thread::spawn(move || {
+        let result = File::open(&path)
+            .and_then(|netns| enter_netns(&netns))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("could not enter {}: {}", path.display(), e),
+                )
+            })
+            .map(|()| {
+                let tun_devices = runtime.block_on(create_tun_devices(&program_args));
+                info!(
+                    "Created TUN devices in network namespace [path={}]",
+                    path.display()
+                );
+                tun_devices
+            });
+        let _ = result_tx.send(result);
+    });
+
+    result_rx
+        .await
+        .map_err(|_| io::Error::other("thread in network namespace stopped"))?
+}
+
+/// Run `f` on a short-lived thread inside the named network namespace `netns` so that e.g.
+/// sockets that it opens belong to that namespace
+pub fn in_netns<T: Send>(netns: &str, f: impl FnOnce() -> T + Send) -> io::Result<T> {
+    let path = netns_path(netns)?;
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                enter_netns(&File::open(&path)?)?;
+                Ok(f())
+            })
+            .join()
+            .expect("Thread in network namespace panicked")
+    })
+}
+
+/// Move the calling thread into the network namespace `netns`
+fn enter_netns(netns: &File) -> io::Result<()> {
+    // SAFETY: setns only changes the namespace of the calling thread
+    if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 async fn create_ipv4_tun_device(
     device_name: &str,
     device_address: Ipv4Addr,
     netmask: Ipv4Addr,
-) -> Tun {
-    TunBuilder::new()
+    mtu: Option<i32>,
+    queues: usize,
+    tap: bool,
+) -> Vec<Tun> {
+    let builder = with_mtu(TunBuilder::new(), mtu)
         .name(device_name)
-        .tap(false) // tap would be ethernet bridging but we are only interested in IP packets
+        .tap(tap) // tap is ethernet bridging, otherwise we only see IP packets
         .address(IpAddr::V4(device_address))
         .netmask(netmask)
         .packet_info(false)
-        .up() // automatically bring the device online (instead of having to run `ip link set <name> up`)
-        .try_build()
-        .expect("Could not create TUN device")
+        .up(); // automatically bring the device online (instead of having to run `ip link set <name> up`)
+    build_queues(builder, queues)
 }
 
 async fn create_ipv6_tun_device(
     device_name: &str,
     device_address: Ipv6Addr,
     prefix_length: u32,
-) -> Tun {
-    TunBuilder::new()
+    mtu: Option<i32>,
+    queues: usize,
+    tap: bool,
+) -> Vec<Tun> {
+    let builder = with_mtu(TunBuilder::new(), mtu)
         .name(device_name)
-        .tap(false)
+        .tap(tap)
         .address(IpAddr::V6(device_address))
         .prefix_length(prefix_length)
         .packet_info(false)
-        .up()
-        .try_build()
-        .expect("Could not create TUN device")
+        .up();
+    build_queues(builder, queues)
+}
+
+/// Configure `mtu` on the device that `builder` creates or keep the system's default if none is
+/// given
+fn with_mtu(builder: TunBuilder, mtu: Option<i32>) -> TunBuilder {
+    match mtu {
+        None => builder,
+        Some(mtu) => builder.mtu(mtu),
+    }
+}
+
+/// Create the device that `builder` describes with one file descriptor per queue.
+///
+/// Multi-queue devices (IFF_MULTI_QUEUE) are only requested when more than one queue is wanted.
+fn build_queues(builder: TunBuilder, queues: usize) -> Vec<Tun> {
+    if queues > 1 {
+        builder
+            .try_build_mq(queues)
+            .expect("Could not create multi-queue TUN device")
+    } else {
+        vec![builder.try_build().expect("Could not create TUN device")]
+    }
 }
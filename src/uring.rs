@@ -0,0 +1,231 @@
+//! TUN device I/O based on io_uring
+//!
+//! Instead of one read or write syscall per packet, reads into a fixed set of buffers are kept
+//! in flight permanently and all responses to the packets that arrived together are written
+//! with one submission.
+//! Packet handling itself is the same as with the default tokio based I/O.
+
+use crate::argparse::Arguments;
+use crate::capture;
+use crate::packet_io;
+use crate::packets;
+use crate::pcap::Direction;
+use io_uring::{opcode, types, IoUring};
+use log::{error, trace, warn};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::sync::watch;
+
+/// Number of reads that are kept in flight at the same time
+const READ_BATCH_SIZE: usize = 32;
+
+/// Number of submission queue entries of the ring
+const RING_SIZE: u32 = 2 * READ_BATCH_SIZE as u32;
+
+/// `user_data` of the completion of the first response's write, which the following responses
+/// count up from; reads use the index of their buffer instead
+const WRITE_USER_DATA: u64 = 1 << 32;
+
+/// `user_data` of the completion of the periodic timeout
+const TIMEOUT_USER_DATA: u64 = u64::MAX;
+
+/// `user_data` of the completions of cancellations
+const CANCEL_USER_DATA: u64 = u64::MAX - 2;
+
+/// Read packets from one queue of a TUN device, handle them and write back the responses using
+/// io_uring.
+///
+/// This blocks the calling thread until `stopped` becomes `true` and should therefore run on a
+/// thread of its own, which notices the change within one timeout check interval.
+/// Timeouts are only handled by the worker of the device's first queue (`handles_timeouts`).
+pub fn loop_for_tun_device(
+    program_args: &Arguments,
+    state: &packets::State,
+    tun: impl AsRawFd,
+    handles_timeouts: bool,
+    stopped: &watch::Receiver<bool>,
+) {
+    let fd = tun.as_raw_fd();
+    // io_uring would otherwise complete reads with EAGAIN instead of waiting for a packet
+    packet_io::set_nonblocking(fd, false).expect("Could not switch TUN device to blocking mode");
+    let mut ring = IoUring::new(RING_SIZE).expect("Could not create io_uring");
+
+    // one byte more than the MTU so that packets which are too large can be noticed
+    let mut buffers = vec![vec![0u8; state.max_frame_len() + 1]; READ_BATCH_SIZE];
+    let timeout = types::Timespec::new().sec(crate::TIMEOUT_CHECK_INTERVAL.as_secs());
+    let mut responses = packets::Responses::new();
+    let mut completed_reads: Vec<(usize, i32)> = Vec::with_capacity(READ_BATCH_SIZE);
+    let mut write_results: Vec<i32> = Vec::new();
+    let mut timed_out = false;
+
+    for (i, buffer) in buffers.iter_mut().enumerate() {
+        push_read(&mut ring, fd, i, buffer);
+    }
+    // every worker wakes up regularly to notice when it should stop
+    push_timeout(&mut ring, &timeout);
+
+    loop {
+        // reads may have completed already while waiting for the last writes
+        let want = if completed_reads.is_empty() && !timed_out {
+            1
+        } else {
+            0
+        };
+        ring.submit_and_wait(want)
+            .expect("Could not wait for io_uring completions");
+        collect_completions(
+            &mut ring,
+            &mut completed_reads,
+            &mut write_results,
+            &mut timed_out,
+        );
+
+        if let Some(&(_, result)) = completed_reads.iter().find(|&&(_, result)| result < 0) {
+            error!(
+                "Could not receive packet, stopping [error={}]",
+                io::Error::from_raw_os_error(-result)
+            );
+            // the other reads still use their buffers
+            let reads_in_flight = READ_BATCH_SIZE - completed_reads.len();
+            cancel_in_flight(&mut ring, reads_in_flight, !timed_out);
+            return;
+        }
+
+        responses.clear();
+        for (i, result) in completed_reads.drain(..) {
+            crate::handle_read(
+                program_args,
+                state,
+                &buffers[i][..result as usize],
+                &mut responses,
+            );
+            push_read(&mut ring, fd, i, &mut buffers[i]);
+        }
+        if timed_out {
+            timed_out = false;
+            if handles_timeouts {
+                packets::handle_timeouts(program_args, state, &mut responses);
+            }
+            push_timeout(&mut ring, &timeout);
+        }
+
+        // the responses need to stay untouched until the kernel has written all of them
+        let mut pending_writes = 0;
+        for (i, response) in responses.iter().enumerate() {
+            let write = opcode::Write::new(types::Fd(fd), response.as_ptr(), response.len() as u32)
+                .build()
+                .user_data(WRITE_USER_DATA + i as u64);
+            push(&mut ring, &write);
+            pending_writes += 1;
+        }
+        write_results.clear();
+        write_results.resize(pending_writes, 0);
+        while pending_writes > 0 {
+            ring.submit_and_wait(1)
+                .expect("Could not wait for io_uring completions");
+            pending_writes -= collect_completions(
+                &mut ring,
+                &mut completed_reads,
+                &mut write_results,
+                &mut timed_out,
+            );
+        }
+        // only the responses that were written are captured
+        for (response, &result) in responses.iter().zip(&write_results) {
+            if result < 0 {
+                continue;
+            }
+            if let Some(packet) = packets::ip_packet(state, response) {
+                capture::record(Direction::Outbound, packet);
+            }
+        }
+
+        if *stopped.borrow() {
+            let reads_in_flight = READ_BATCH_SIZE - completed_reads.len();
+            cancel_in_flight(&mut ring, reads_in_flight, !timed_out);
+            return;
+        }
+    }
+}
+
+/// Cancel the reads and the timeout that are still in flight and wait until the kernel is done
+/// with them, after which their buffers may be freed
+fn cancel_in_flight(ring: &mut IoUring, reads_in_flight: usize, timeout_in_flight: bool) {
+    for i in 0..READ_BATCH_SIZE {
+        let cancel = opcode::AsyncCancel::new(i as u64)
+            .build()
+            .user_data(CANCEL_USER_DATA);
+        push(ring, &cancel);
+    }
+    let remove_timeout = opcode::TimeoutRemove::new(TIMEOUT_USER_DATA)
+        .build()
+        .user_data(CANCEL_USER_DATA);
+    push(ring, &remove_timeout);
+
+    let mut pending = reads_in_flight + timeout_in_flight as usize;
+    while pending > 0 {
+        ring.submit_and_wait(1)
+            .expect("Could not wait for io_uring completions");
+        pending -= ring
+            .completion()
+            .filter(|completion| completion.user_data() != CANCEL_USER_DATA)
+            .count();
+    }
+}
+
+/// Move all available completions out of the ring.
+///
+/// Completed reads are added to `completed_reads`, the results of completed writes are stored
+/// in `write_results` by the index of their response, an expired timeout sets `timed_out` and
+/// the number of completed writes is returned.
+fn collect_completions(
+    ring: &mut IoUring,
+    completed_reads: &mut Vec<(usize, i32)>,
+    write_results: &mut [i32],
+    timed_out: &mut bool,
+) -> usize {
+    let mut completed_writes = 0;
+    for completion in ring.completion() {
+        match completion.user_data() {
+            TIMEOUT_USER_DATA => *timed_out = true,
+            write if write >= WRITE_USER_DATA => {
+                write_results[(write - WRITE_USER_DATA) as usize] = completion.result();
+                completed_writes += 1;
+                if completion.result() < 0 {
+                    warn!(
+                        "Could not write response [error={}]",
+                        io::Error::from_raw_os_error(-completion.result())
+                    );
+                } else {
+                    trace!("Wrote response [n_bytes={}]", completion.result());
+                }
+            }
+            i => completed_reads.push((i as usize, completion.result())),
+        }
+    }
+    completed_writes
+}
+
+fn push_read(ring: &mut IoUring, fd: RawFd, i: usize, buffer: &mut [u8]) {
+    let read = opcode::Read::new(types::Fd(fd), buffer.as_mut_ptr(), buffer.len() as u32)
+        .build()
+        .user_data(i as u64);
+    push(ring, &read);
+}
+
+fn push_timeout(ring: &mut IoUring, timeout: &types::Timespec) {
+    let timeout = opcode::Timeout::new(timeout)
+        .build()
+        .user_data(TIMEOUT_USER_DATA);
+    push(ring, &timeout);
+}
+
+/// Add `entry` to the submission queue and submit what is queued already if it is full.
+///
+/// The buffers that `entry` refers to must stay valid until its completion has been collected.
+fn push(ring: &mut IoUring, entry: &io_uring::squeue::Entry) {
+    // SAFETY: all callers keep the referenced buffers alive and untouched until completion
+    while unsafe { ring.submission().push(entry) }.is_err() {
+        ring.submit().expect("Could not submit io_uring entries");
+    }
+}